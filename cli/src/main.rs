@@ -1,103 +1,1090 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use anyhow::{bail, Context, Error, Result};
-use chrono::{DateTime, NaiveDate, Utc};
-use clap::Parser;
+use chrono::{DateTime, NaiveDate, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use clap::{
+    parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser,
+    ValueEnum,
+};
 use polygon_data::{
-    config::{Config, Tickers},
+    client::DEFAULT_AGGREGATES_VERSION,
+    config::{default_horizons, Config, Tickers},
+    http_sink::{HttpSink, DEFAULT_BATCH_SIZE},
+    probe::{Span, Support},
+    retry::RetryPolicy,
+    schema::SchemaCheck,
     service::Service,
-    types::Timespan,
+    types::{
+        Coverage, CsvQuoting, DuplicateCheck, Estimate, HttpVersion, Market,
+        MonotonicCheck, OutputFormat, PartitionBy, SortOrder, Timespan,
+        TimestampUnit,
+    },
 };
-use std::fs;
+use serde::de::DeserializeOwned;
+use std::{fs, io};
 use tracing_subscriber::{fmt, layer::SubscriberExt, prelude::*, EnvFilter};
 
 const DEFAULT_CHUNK_SIZE: u32 = 5_000;
 
-/// CLI tool to download data from Polygon
+/// CLI tool to download data from Polygon.
+///
+/// On Unix, send SIGUSR1 to pause a running fetch and SIGUSR2 to resume it.
+/// Requests in flight when pausing finish and are written, no new ones are
+/// sent until resuming
 #[derive(Parser, Debug)]
 struct Args {
-    /// File path to a config file that lists all the tickers to download data for
-    #[clap(short, long)]
-    config: PathBuf,
+    /// File path to a config file that lists all the tickers to download data for.
+    /// Use `-` to read the config from stdin, together with --config-format.
+    /// A config written by --dump-config is run as it was written, with just
+    /// the flags given along with it changing it, e.g. `-o` to write to
+    /// another folder. --from and --to can be left out then
+    #[clap(short, long, required_unless_present = "grouped_daily")]
+    config: Option<PathBuf>,
+    /// The format of the config. Defaults to going by the extension of the
+    /// config file, and is required when reading the config from stdin
+    #[clap(long, value_enum)]
+    config_format: Option<ConfigFormat>,
+    /// Fail if the config lists a ticker more than once, instead of warning
+    /// and fetching it once. Tickers are compared case-insensitively
+    #[clap(long)]
+    strict_tickers: bool,
+    /// Fetch and save tickers exactly as the config writes them. By default
+    /// they're uppercased, like Polygon's symbols, e.g. `x:btcusd` is
+    /// fetched as `X:BTCUSD`
+    #[clap(long)]
+    keep_ticker_case: bool,
+    /// Download the daily bars of the whole US stock market with Polygon's
+    /// grouped daily endpoint instead of fetching each ticker in the config.
+    /// Saves one file per day, `$output_dir/$date.csv`, with every ticker
+    #[clap(long)]
+    grouped_daily: bool,
+    /// Save the grouped daily data like Polygon's flat files of daily
+    /// aggregates: `$output_dir/us_stocks_sip/day_aggs_v1/$year/$month/$date.csv`
+    /// with the columns `ticker,volume,open,close,high,low,window_start,transactions`,
+    /// where `window_start` is in Unix nanoseconds. Unlike Polygon's files,
+    /// these aren't gzipped
+    #[clap(long, requires = "grouped_daily")]
+    flat_file_layout: bool,
+    /// Save the grouped daily data to one file per ticker instead, the same
+    /// files as fetching each ticker's daily bars would, e.g.
+    /// `$output_dir/$ticker/day.csv`. Only the tickers in the config are
+    /// saved, or every ticker without a config. Each day is merged into the
+    /// files, so runs can be repeated or extended
+    #[clap(
+        long,
+        requires = "grouped_daily",
+        conflicts_with = "flat_file_layout"
+    )]
+    by_ticker: bool,
+    /// Write the configuration this run ends up with, after combining the
+    /// config file and every flag, to this file as YAML, TOML or JSON,
+    /// going by its extension. The API key is left out. Give the file to
+    /// --config to run the same configuration again
+    #[clap(long, value_name = "PATH")]
+    dump_config: Option<PathBuf>,
+    /// Print the work items that would be fetched, one per ticker and date
+    /// range, as a JSON array and exit without fetching anything
+    #[clap(long)]
+    emit_plan: bool,
+    /// Print the estimated number of requests and chunks for each ticker and
+    /// in total, and exit without sending any requests or creating any
+    /// files. Logs go to stderr instead. Chunks are the pages of bars,
+    /// counted as if markets never closed, so sparse tickers take fewer
+    #[clap(long)]
+    dry_run: bool,
+    /// Print what's already downloaded of each ticker in the config, the
+    /// first and last bar and the gaps between them like --validate finds,
+    /// and exit without fetching anything. Logs go to stderr instead
+    #[clap(
+        long,
+        conflicts_with_all = ["grouped_daily", "dry_run", "emit_plan"]
+    )]
+    coverage: bool,
+    /// Download the tickers in a random order, so that interrupted runs don't
+    /// keep re-fetching the same tickers at the start of the config
+    #[clap(long)]
+    shuffle: bool,
+    /// The seed to use with --shuffle, to get the same order across runs
+    #[clap(long, requires = "shuffle")]
+    seed: Option<u64>,
+    /// Only download data for the first N tickers of the config, after
+    /// shuffling. Handy for smoke tests
+    #[clap(long, value_name = "N")]
+    max_tickers: Option<usize>,
     /// The length of time for each candlestick.
     #[clap(short, long, default_value_t, value_parser = Timespan::from_str)]
     span: Timespan,
+    /// How many of --span each candlestick covers, e.g. `5` with
+    /// `--span minute` for 5-minute bars, saved under `$ticker/5minute`
+    #[clap(long, default_value = "1")]
+    multiplier: NonZeroU32,
+    /// Fetch several bar sizes one after another instead of --span and
+    /// --multiplier, e.g. `minute,5minute,day`, each saved under its own
+    /// folder
+    #[clap(
+        long,
+        value_delimiter = ',',
+        value_parser = Span::from_str,
+        conflicts_with_all = ["span", "multiplier", "grouped_daily"]
+    )]
+    spans: Vec<Span>,
+    /// Before fetching, request a single bar with each span to find out
+    /// which ones the Polygon plan covers, and skip the others with a
+    /// warning. Spans that can't be probed for another reason are kept
+    #[clap(long, conflicts_with = "grouped_daily")]
+    probe_spans: bool,
     /// The folder to save the downloaded data. Will be saved
-    /// in this structure: `$output_dir/$ticker/$year/$month/$day.csv`
+    /// in this structure: `$output_dir/$ticker/$span/$year/$month/$day.csv`,
+    /// see --partition-by
     #[clap(short, long)]
     output_dir: PathBuf,
-    /// The starting date to pull data from
-    #[clap(short, long)]
-    from: NaiveDate,
-    /// The ending date to pull data to
-    #[clap(short, long)]
-    to: NaiveDate,
+    /// How to split up each ticker's data into files, by the UTC date of
+    /// each bar: a file per day, `$ticker/$span/$year/$month/$day.csv`, a
+    /// file per month, `$ticker/$span/$year/$month.csv`, or a single file,
+    /// `$ticker/$span.csv`
+    #[clap(long, default_value_t, value_parser = PartitionBy::from_str)]
+    partition_by: PartitionBy,
+    /// The format to save the downloaded data in. Can be given more than
+    /// once to save each ticker in several formats in a single run
+    #[clap(long, default_value = "csv", value_parser = OutputFormat::from_str)]
+    format: Vec<OutputFormat>,
+    /// Also write `dictionary.json` to the output folder, describing the
+    /// name, meaning, type and unit of each column of the files
+    #[clap(long)]
+    data_dictionary: bool,
+    /// How to write the timestamp of each record: Unix milliseconds as
+    /// returned by Polygon, Unix seconds, or RFC 3339 datetimes in UTC
+    #[clap(long, default_value_t, value_parser = TimestampUnit::from_str)]
+    timestamp_unit: TimestampUnit,
+    /// Add a `datetime` column after the `timestamp` column, with the RFC
+    /// 3339 datetime in UTC of each timestamp, e.g. `2024-01-02T14:30:00Z`.
+    /// The timestamp is kept as it is
+    #[clap(long)]
+    with_datetime: bool,
+    /// Add `date` and `time` columns after the `timestamp` column, with the
+    /// UTC date and time of day of each timestamp, e.g. `2024-01-02` and
+    /// `14:30:00`. The timestamp is kept as it is, Polygon's Unix
+    /// milliseconds unless --timestamp-unit says otherwise
+    #[clap(long)]
+    with_date_and_time: bool,
+    /// Leave out the newline after the last row of CSV files, for consumers
+    /// that choke on it. By default every row ends with a newline,
+    /// including the last one
+    #[clap(long)]
+    no_trailing_newline: bool,
+    /// Start CSV files with a UTF-8 byte order mark, so that Excel reads
+    /// them as UTF-8. Off by default since other CSV parsers can take the
+    /// mark as part of the first column name
+    #[clap(long)]
+    csv_bom: bool,
+    /// Which fields of CSV files to quote: only those that need it, every
+    /// field, no field, or every field that isn't a number, for strict CSV
+    /// parsers
+    #[clap(long, default_value_t, value_parser = CsvQuoting::from_str)]
+    csv_quoting: CsvQuoting,
+    /// The delimiter between the fields of CSV files: `,`, `;`, `|` or
+    /// `tab`
+    #[clap(long, default_value = ",", value_parser = parse_delimiter)]
+    csv_delimiter: char,
+    /// Write the decimals of CSV files with a comma instead of a point, as
+    /// spreadsheets in many European locales expect. Needs a
+    /// --csv-delimiter other than a comma, e.g. `;`
+    #[clap(long)]
+    decimal_comma: bool,
+    /// Compress Parquet files with Zstandard at this level, from 1 to 22,
+    /// instead of with Snappy. Higher levels make smaller files but take
+    /// longer to write
+    #[clap(long, value_name = "LEVEL")]
+    compression_level: Option<u32>,
+    /// The starting date to pull data from. Can be given more than once,
+    /// together with --to, to fetch several windows
+    #[clap(
+        short,
+        long,
+        required_unless_present_any = ["full_history", "coverage", "config"]
+    )]
+    from: Vec<NaiveDate>,
+    /// The ending date to pull data to. Can be given more than once,
+    /// together with --from, to fetch several windows
+    #[clap(
+        short,
+        long,
+        required_unless_present_any = ["full_history", "coverage", "config"]
+    )]
+    to: Vec<NaiveDate>,
+    /// The time zone --from and --to are dates in, as an IANA name like
+    /// `UTC` or `Europe/London`. Each date starts at midnight in this time
+    /// zone, which defaults to that of the US markets so that intraday bars
+    /// from the evening before aren't included
+    #[clap(long, default_value = "America/New_York", value_parser = Tz::from_str)]
+    timezone: Tz,
+    /// Move --from up to the earliest date Polygon has data for in each
+    /// ticker's market, instead of requesting dates that come back empty
+    #[clap(long)]
+    clamp_to_horizon: bool,
+    /// Override the earliest date Polygon has data for in a market, e.g.
+    /// `crypto=2015-01-01`. Can be given more than once. Implies
+    /// --clamp-to-horizon
+    #[clap(long, value_parser = parse_horizon)]
+    horizon: Vec<(Market, NaiveDate)>,
+    /// Download all available history, from the day each ticker was listed
+    /// up until now. Falls back to --from, or the Unix epoch, for tickers
+    /// Polygon doesn't have a listing date for
+    #[clap(long)]
+    full_history: bool,
+    /// Keep the details of tickers looked up on Polygon, like their listing
+    /// dates for --full-history, in this JSON file, and reuse them in later
+    /// runs instead of looking them up again
+    #[clap(long, value_name = "PATH")]
+    metadata_cache: Option<PathBuf>,
+    /// How many days the details in --metadata-cache are reused for before
+    /// they're looked up again
+    #[clap(long, value_name = "DAYS", default_value = "7")]
+    metadata_ttl: u64,
+    /// Swap --from and --to, with a warning, when --to is before --from
+    /// instead of failing
+    #[clap(long)]
+    swap_reversed_ranges: bool,
+    /// Warn at startup when any range from --from to --to falls entirely on
+    /// weekends or market holidays, so there's nothing to download
+    #[clap(long)]
+    warn_on_weekend_range: bool,
+    /// The order to ask Polygon for each ticker's records in, by timestamp.
+    /// With `desc`, records are written newest first unless
+    /// --reverse-descending is also given
+    #[clap(long, default_value_t, value_parser = SortOrder::from_str)]
+    sort: SortOrder,
+    /// Get prices adjusted for splits. This is the default
+    #[clap(long, overrides_with = "no_adjusted")]
+    adjusted: bool,
+    /// Get the raw prices as they traded, without adjusting them for splits,
+    /// e.g. for backtesting
+    #[clap(long, overrides_with = "adjusted")]
+    no_adjusted: bool,
+    /// Adjust prices only for the splits up to and including this date, as
+    /// they were adjusted back then, e.g. to backtest without look-ahead.
+    /// Polygon only adjusts for every split up until now, so the raw prices
+    /// are fetched and adjusted with Polygon's list of splits instead.
+    /// Dividends aren't adjusted for, and neither are grouped daily bars
+    #[clap(long, value_name = "DATE", conflicts_with = "no_adjusted")]
+    adjusted_as_of: Option<NaiveDate>,
+    /// Only download the most recent N bars of each ticker between --from
+    /// and --to, stopping as soon as there are N. Fetches newest first, but
+    /// still writes the bars oldest first
+    #[clap(long, value_name = "N")]
+    last: Option<usize>,
+    /// With --sort desc, keep each ticker's records in memory and write them
+    /// oldest first once they're all downloaded
+    #[clap(long)]
+    reverse_descending: bool,
+    /// The Unix mode to give the files and folders created, in octal, e.g.
+    /// `640` to make them readable by the group. Folders also get the
+    /// execute bit wherever they're readable. Ignored on other platforms
+    #[clap(long, value_name = "MODE", value_parser = parse_mode)]
+    output_permissions: Option<u32>,
+    /// If set, the raw JSON body of every response from Polygon is also
+    /// saved in this folder, in this structure:
+    /// `$save_raw/$ticker/$timespan/$from-$to/$page.json`, with the range
+    /// of each request in milliseconds
+    #[clap(long)]
+    save_raw: Option<PathBuf>,
+    /// Write the progress of the run to this file as JSON lines, with a
+    /// `started`, `progress`, `error` or `finished` event on each, for a GUI
+    /// or another wrapper to follow. Pass e.g. `/dev/fd/3` to write them to
+    /// a file descriptor inherited from the parent process
+    #[clap(long, value_name = "PATH")]
+    progress_events: Option<PathBuf>,
+    /// Draw the progress bar even when stderr isn't a terminal, e.g. when
+    /// it's piped to a file. By default it's only drawn to a terminal
+    #[clap(long, conflicts_with = "no_progress")]
+    progress: bool,
+    /// Never draw the progress bar, even when stderr is a terminal
+    #[clap(long)]
+    no_progress: bool,
+    /// POST the fetched records to this URL instead of saving them, as JSON
+    /// arrays like the `.json` files with the ticker in the `ticker` query
+    /// parameter. Failed requests are retried --max-retries times, including
+    /// those that fail with a 429 or 5xx status
+    #[clap(long, value_name = "URL", conflicts_with = "grouped_daily")]
+    post_to: Option<String>,
+    /// A header to send with every request to --post-to, e.g.
+    /// `Authorization: Bearer $TOKEN`. Can be given several times
+    #[clap(long, value_name = "HEADER", requires = "post_to")]
+    post_header: Vec<String>,
+    /// How many records to send in each request to --post-to
+    #[clap(
+        long,
+        value_name = "RECORDS",
+        requires = "post_to",
+        default_value_t = NonZeroUsize::new(DEFAULT_BATCH_SIZE).unwrap()
+    )]
+    post_batch_size: NonZeroUsize,
+    /// The version of Polygon's aggregates API to use, e.g. `v2`
+    #[clap(long, default_value = DEFAULT_AGGREGATES_VERSION)]
+    endpoint_version: String,
+    /// Send requests to this URL instead of Polygon's API, e.g. a caching
+    /// proxy. Defaults to https://api.polygon.io
+    #[clap(long, value_name = "URL")]
+    base_url: Option<String>,
+    /// Only connect to the server if it sends the certificate with this
+    /// SHA-256 fingerprint, either its own or an intermediate one, as shown
+    /// by `openssl x509 -noout -fingerprint -sha256`. Can be given several
+    /// times, e.g. to pin the next certificate ahead of a rotation
+    #[clap(long, value_name = "FINGERPRINT")]
+    pin_certificate: Vec<String>,
+    /// Only talk to the server in this version of HTTP, `1.1` or `2`,
+    /// instead of negotiating it, e.g. for proxies that don't support
+    /// HTTP/2. Over plain HTTP, `2` needs a server that speaks it without an
+    /// upgrade
+    #[clap(long, value_name = "VERSION", value_parser = HttpVersion::from_str)]
+    http_version: Option<HttpVersion>,
+    /// Retry requests that fail with a 5xx status, with exponential backoff,
+    /// instead of giving up on the ticker
+    #[clap(long)]
+    retry_server_errors: bool,
+    /// How many times to retry a request that failed with a network error or
+    /// because of Polygon's rate limit, with exponential backoff, before
+    /// giving up on the ticker. Rate limited requests wait as long as
+    /// Polygon's `Retry-After` header asks, if it sends one
+    #[clap(long, default_value_t = 3)]
+    max_retries: u32,
+    /// Also retry requests that Polygon sends back an error message
+    /// containing this text for, even with a 200 status, e.g. for soft
+    /// failures it reports in the body. Can be given several times
+    #[clap(long, value_name = "TEXT")]
+    retry_on_message: Vec<String>,
+    /// Send at most this many requests a minute, across all tickers, e.g.
+    /// `5` on Polygon's free tier. Requests over the limit wait their turn
+    #[clap(long, value_name = "REQUESTS_PER_MINUTE")]
+    rate_limit: Option<NonZeroU32>,
+    /// Send at most this many requests for the pages of any one ticker. A
+    /// ticker that reaches the limit stops, while the others carry on
+    #[clap(long, value_name = "REQUESTS")]
+    max_requests_per_ticker: Option<NonZeroU32>,
+    /// Push how long each ticker took, its records and its retries to the
+    /// Prometheus Pushgateway at this URL, e.g. `http://localhost:9091`,
+    /// under the `polygon-data` job and a `ticker` label. Only in builds
+    /// with the `metrics` feature
+    #[clap(long, value_name = "URL")]
+    push_metrics: Option<String>,
+    /// Stop the run the first time a request is still rate limited after
+    /// all its retries, instead of moving on to the next ticker
+    #[clap(long)]
+    abort_on_rate_limit: bool,
+    /// Exit with an error if less than this fraction of the tickers were
+    /// fetched successfully, e.g. `0.95`, even though each failed ticker
+    /// only stops itself. With --spans, each span is checked on its own
+    #[clap(long, value_name = "FRACTION")]
+    min_success_rate: Option<f64>,
+    /// Send a single request for identical requests that are in flight at
+    /// the same time, e.g. when ranges in the config overlap, so they only
+    /// count against the quota once
+    #[clap(long)]
+    coalesce_requests: bool,
+    /// How many requests to have in flight at once, and how many tickers to
+    /// fetch at the same time. Lower it on Polygon's free tier, raise it on
+    /// plans with unlimited requests
+    #[clap(long, default_value = "10")]
+    concurrency: NonZeroUsize,
+    /// How many pages of a ticker to fetch ahead of writing them, holding
+    /// them in memory. Raise it if writing keeps up with fetching in bursts,
+    /// lower it to use less memory
+    #[clap(long, value_name = "PAGES", default_value = "4")]
+    write_buffer_size: NonZeroUsize,
+    /// How many of a ticker's files to write at the same time, when saving
+    /// it in several formats or with --derive-daily. Raise it if one of the
+    /// formats is much slower to write than the others
+    #[clap(long, value_name = "FILES", default_value = "1")]
+    write_concurrency: NonZeroUsize,
+    /// How many months of a single ticker to fetch concurrently. Speeds up
+    /// downloading long histories, at the cost of buffering a month of
+    /// data per ticker in memory
+    #[clap(long, default_value = "1")]
+    ticker_parallelism: NonZeroUsize,
+    /// Start with a single request in flight and allow more gradually over
+    /// this many seconds, to avoid tripping Polygon's burst rate limit
+    #[clap(long, value_name = "SECONDS")]
+    ramp_up: Option<u64>,
+    /// When fetching second, minute or hour bars, also combine them into
+    /// daily bars as they're fetched and save those next to them, under
+    /// `$output_dir/$ticker/day-from-$span`. Days are New York days, like
+    /// the trading days of US markets
+    #[clap(long)]
+    derive_daily: bool,
+    /// After fetching a ticker, look for gaps in its data and re-request
+    /// just the missing periods
+    #[clap(long)]
+    fill_gaps: bool,
+    /// The maximum number of passes to make over a ticker with --fill-gaps
+    #[clap(long, default_value_t = 3)]
+    max_fill_attempts: usize,
+    /// Log summary statistics of each ticker's data once it's downloaded:
+    /// the min/max/average close and the total volume
+    #[clap(long)]
+    summary_stats: bool,
+    /// Once a ticker is downloaded, scan the records in its files and log
+    /// the gaps between consecutive bars that are further apart than the
+    /// bar size. Overnight gaps and weekends are ignored, and so are market
+    /// holidays for daily bars. Only reports the gaps, see --fill-gaps to
+    /// fill them
+    #[clap(long)]
+    validate: bool,
+    /// Skip whatever a previous run already downloaded. Progress is
+    /// recorded per ticker, per month with --ticker-parallelism, or per day
+    /// with --partition-by day, so only the parts that failed or never ran
+    /// are fetched again. Tickers that
+    /// the span's manifest in the output folder, e.g. `manifest-minute.json`,
+    /// has as successfully downloaded aren't looked at at all
+    #[clap(long)]
+    resume: bool,
+    /// Keep the checkpoint at this path instead of in the output folder,
+    /// e.g. on storage shared by several machines. Implies --resume
+    #[clap(long, value_name = "PATH")]
+    resume_from_checkpoint: Option<PathBuf>,
+    /// Start each ticker's files over. By default a ticker's files are
+    /// appended to, starting just after the last record already in them
+    #[clap(long, conflicts_with_all = ["resume", "resume_from_checkpoint"])]
+    overwrite: bool,
+    /// When picking up after the records already in a ticker's files, fetch
+    /// this many days before the last one again, and replace the bars that
+    /// Polygon has revised since instead of leaving them stale
+    #[clap(long, value_name = "DAYS", conflicts_with = "overwrite")]
+    revision_window: Option<u64>,
+    /// Check that the timestamps written for each ticker strictly increase.
+    /// With `warn` out of order records are logged, with `error` the ticker
+    /// is stopped at the first one
+    #[clap(long, value_parser = MonotonicCheck::from_str)]
+    enforce_monotonic: Option<MonotonicCheck>,
+    /// Check each page from Polygon for records with the same timestamp.
+    /// With `warn` they're logged, with `drop` only the first record with
+    /// each timestamp is kept, and with `error` the ticker is stopped
+    #[clap(long, value_parser = DuplicateCheck::from_str)]
+    page_duplicates: Option<DuplicateCheck>,
+    /// Write records in the order they're fetched, instead of making sure
+    /// each file is strictly ordered by timestamp. Out of order records can
+    /// be found with --enforce-monotonic
+    #[clap(long)]
+    unordered: bool,
+    /// Check Polygon's responses for fields this tool doesn't know about,
+    /// which usually means the API changed. With `warn` they're logged, with
+    /// `error` the request fails
+    #[clap(long, value_parser = SchemaCheck::from_str)]
+    strict_schema: Option<SchemaCheck>,
+    /// Name each ticker's folder after the ticker exactly, e.g. `X:BTCUSD`.
+    /// By default characters that aren't valid in paths on Windows are
+    /// replaced with `_`, Windows' reserved names are prefixed with `_`, and
+    /// very long tickers are cut short. A folder named after the ticker as
+    /// is, from before the default changed, is still used, with a warning
+    #[clap(long)]
+    raw_ticker_paths: bool,
     #[clap(env = "POLYGON_API_KEY")]
     polygon_api_key: String,
+    /// The ids of the flags given on the command line, to set them on top of
+    /// a config written by --dump-config
+    #[clap(skip)]
+    given: Vec<String>,
+}
+
+impl Args {
+    /// The arguments in `matches`, noting which flags were given on the
+    /// command line. Exits on invalid arguments like [`Parser::parse`]
+    fn from_matches(matches: &ArgMatches) -> Self {
+        let mut args =
+            Self::from_arg_matches(matches).unwrap_or_else(|e| e.exit());
+        args.given = matches
+            .ids()
+            .filter(|id| {
+                matches.value_source(id.as_str())
+                    == Some(ValueSource::CommandLine)
+            })
+            .map(ToString::to_string)
+            .collect();
+        args
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
-    let file_appender = tracing_appender::rolling::daily(
-        args.output_dir.clone(),
-        "polygon-data.log",
-    );
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    let args = Args::from_matches(&Args::command().get_matches());
+    let (non_blocking, _guard) = if args.dry_run || args.coverage {
+        tracing_appender::non_blocking(std::io::stderr())
+    } else {
+        tracing_appender::non_blocking(tracing_appender::rolling::daily(
+            args.output_dir.clone(),
+            "polygon-data.log",
+        ))
+    };
 
     tracing_subscriber::registry()
         .with(fmt::layer().with_ansi(false).with_writer(non_blocking))
         .with(EnvFilter::from_default_env())
         .init();
     let api_key = args.polygon_api_key.clone();
-    let config = args.try_into()?;
-    let service = Service::new(config, &api_key)?;
-    service.fetch_data().await;
+    let grouped_daily = args.grouped_daily;
+    let emit_plan = args.emit_plan;
+    let dry_run = args.dry_run;
+    let coverage = args.coverage;
+    let dump_config = args.dump_config.clone();
+    let probe = args.probe_spans;
+    let mut spans = args.spans.clone();
+    let sink = http_sink(&args)?;
+    let config: Config = args.try_into()?;
+    if spans.is_empty() {
+        spans.push(Span {
+            timespan: config.timespan,
+            multiplier: config.multiplier,
+        });
+    }
+    if let Some(path) = dump_config {
+        write_config(&path, &config)?;
+    }
+    if probe {
+        let service = Service::new(config.clone(), &api_key)?;
+        spans = supported_spans(&service, &spans).await?;
+    }
+    for span in spans {
+        let config = Config {
+            timespan: span.timespan,
+            multiplier: span.multiplier,
+            ..config.clone()
+        };
+        let service = Service::new(config, &api_key)?;
+        run(
+            &service,
+            sink.as_ref(),
+            grouped_daily,
+            dry_run,
+            coverage,
+            emit_plan,
+        )
+        .await?;
+    }
     Ok(())
 }
 
+/// The spans of `spans` the plan covers, or that couldn't be probed. The
+/// probe logs what it found for each
+async fn supported_spans(
+    service: &Service,
+    spans: &[Span],
+) -> Result<Vec<Span>> {
+    let supported: Vec<_> = service
+        .probe_spans(spans)
+        .await
+        .into_iter()
+        .filter(|probed| !matches!(probed.support, Support::Unsupported(_)))
+        .map(|probed| probed.span)
+        .collect();
+    if supported.is_empty() {
+        bail!("The plan doesn't cover any of the spans");
+    }
+    Ok(supported)
+}
+
+async fn run(
+    service: &Service,
+    sink: Option<&HttpSink>,
+    grouped_daily: bool,
+    dry_run: bool,
+    coverage: bool,
+    emit_plan: bool,
+) -> Result<()> {
+    if dry_run {
+        let estimates = service.estimate();
+        for estimate in &estimates {
+            println!(
+                "{}: {} requests, {} chunks",
+                estimate.ticker, estimate.requests, estimate.chunks
+            );
+        }
+        let total = |count: fn(&Estimate) -> i64| {
+            estimates.iter().map(count).sum::<i64>()
+        };
+        println!(
+            "Total: {} requests, {} chunks",
+            total(|estimate| estimate.requests),
+            total(|estimate| estimate.chunks)
+        );
+    } else if coverage {
+        for coverage in service.coverage()? {
+            print_coverage(&coverage);
+        }
+    } else if emit_plan {
+        let plan = service.plan().await;
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+    } else {
+        let fetch = async {
+            if let Some(sink) = sink {
+                service.fetch_to_http(sink).await;
+            } else if grouped_daily {
+                service.fetch_grouped_daily().await;
+            } else {
+                service.fetch_data().await?;
+            }
+            Ok::<_, Error>(())
+        };
+        tokio::select! {
+            result = fetch => result?,
+            result = pause_on_signals(service) => result?,
+        }
+    }
+    Ok(())
+}
+
+fn print_coverage(coverage: &Coverage) {
+    let Some((first, last)) = coverage.range else {
+        println!("{}: nothing downloaded", coverage.ticker);
+        return;
+    };
+    println!(
+        "{}: {} records from {first} to {last}, {} gaps",
+        coverage.ticker,
+        coverage.records,
+        coverage.gaps.len()
+    );
+    for gap in &coverage.gaps {
+        println!(
+            "  {} missing between {} and {}",
+            gap.missing, gap.start, gap.end
+        );
+    }
+}
+
+/// Pause `service` on SIGUSR1 and resume it on SIGUSR2, for as long as it
+/// runs
+#[cfg(unix)]
+async fn pause_on_signals(service: &Service) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut pause = signal(SignalKind::user_defined1())?;
+    let mut resume = signal(SignalKind::user_defined2())?;
+    loop {
+        tokio::select! {
+            _ = pause.recv() => service.pause(),
+            _ = resume.recv() => service.resume(),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn pause_on_signals(_service: &Service) -> Result<()> {
+    std::future::pending().await
+}
+
 impl TryFrom<Args> for Config {
     type Error = Error;
     fn try_from(args: Args) -> Result<Self, Self::Error> {
-        let tickers = parse_config(args.config)?.tickers;
-        let from = args.from.and_hms_opt(0, 0, 0).ok_or_else(|| {
-            Error::msg("couldn't construct date with --from argument")
-        })?;
-        let from = DateTime::<Utc>::from_naive_utc_and_offset(from, Utc);
-        let to = args.to.and_hms_opt(0, 0, 0).ok_or_else(|| {
-            Error::msg("couldn't construct date with --to argument")
-        })?;
-        let to = DateTime::<Utc>::from_naive_utc_and_offset(to, Utc);
-        Ok(Self {
+        let mut dumped = None;
+        let tickers = match args.config {
+            Some(path) => match parse_config(path, args.config_format)? {
+                ConfigFile::Tickers(_)
+                    if (args.from.is_empty() || args.to.is_empty())
+                        && !args.full_history
+                        && !args.coverage =>
+                {
+                    bail!(
+                        "--from and --to are required unless the config was \
+                         written by --dump-config"
+                    );
+                }
+                ConfigFile::Tickers(tickers) => {
+                    tickers
+                        .normalize(args.strict_tickers, args.keep_ticker_case)?
+                        .tickers
+                }
+                ConfigFile::Dumped(config) => {
+                    dumped = Some(config);
+                    Vec::new()
+                }
+            },
+            None => Vec::new(),
+        };
+        if args.from.len() != args.to.len()
+            && (args.from.len() > 1 || args.to.len() > 1)
+        {
+            bail!("--from and --to must be given the same number of times");
+        }
+        let tz = args.timezone;
+        let from = match args.from.first() {
+            Some(from) => midnight(*from, tz, "--from")?,
+            None => DateTime::UNIX_EPOCH,
+        };
+        let to = match args.to.first() {
+            Some(to) => midnight(*to, tz, "--to")?,
+            None => Utc::now(),
+        };
+        let extra_ranges = args
+            .from
+            .iter()
+            .zip(&args.to)
+            .skip(1)
+            .map(|(from, to)| {
+                Ok((midnight(*from, tz, "--from")?, midnight(*to, tz, "--to")?))
+            })
+            .collect::<Result<_>>()?;
+        let flags = Self {
             tickers,
+            shuffle: args.shuffle,
+            seed: args.seed,
+            max_tickers: args.max_tickers,
             timespan: args.span,
+            multiplier: args.multiplier.get(),
             output_dir: args.output_dir,
+            partition_by: args.partition_by,
+            formats: args.format,
+            data_dictionary: args.data_dictionary,
+            timestamp_unit: args.timestamp_unit,
+            with_datetime: args.with_datetime,
+            with_date_and_time: args.with_date_and_time,
+            csv_trailing_newline: !args.no_trailing_newline,
+            csv_bom: args.csv_bom,
+            csv_quoting: args.csv_quoting,
+            csv_delimiter: args.csv_delimiter,
+            csv_decimal_comma: args.decimal_comma,
+            compression_level: args.compression_level,
             from,
             to,
+            horizons: (args.clamp_to_horizon || !args.horizon.is_empty()).then(
+                || {
+                    let mut horizons = default_horizons();
+                    horizons.extend(args.horizon);
+                    horizons
+                },
+            ),
+            extra_ranges,
+            swap_reversed_ranges: args.swap_reversed_ranges,
+            full_history: args.full_history,
+            metadata_cache: args.metadata_cache,
+            metadata_ttl: Duration::from_secs(args.metadata_ttl * 24 * 60 * 60),
+            warn_on_weekend_range: args.warn_on_weekend_range,
+            aggregates_version: args.endpoint_version,
+            base_url: args.base_url,
+            pinned_certificates: args.pin_certificate,
+            http_version: args.http_version,
             limit: DEFAULT_CHUNK_SIZE,
+            sort: args.sort,
+            adjusted: !args.no_adjusted,
+            adjusted_as_of: args.adjusted_as_of,
+            reverse_descending: args.reverse_descending,
+            last: args.last,
+            save_raw: args.save_raw,
+            progress_events: args.progress_events,
+            progress: match (args.progress, args.no_progress) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+            retry_server_errors: args.retry_server_errors,
+            max_retries: args.max_retries,
+            retry_messages: args.retry_on_message,
+            requests_per_minute: args.rate_limit,
+            max_requests_per_ticker: args.max_requests_per_ticker,
+            push_metrics: args.push_metrics,
+            abort_on_rate_limit: args.abort_on_rate_limit,
+            min_success_rate: args.min_success_rate,
+            coalesce_requests: args.coalesce_requests,
+            concurrency: args.concurrency.get(),
+            write_buffer_size: args.write_buffer_size.get(),
+            write_concurrency: args.write_concurrency.get(),
+            ticker_parallelism: args.ticker_parallelism.get(),
+            ramp_up: args.ramp_up.map(Duration::from_secs),
+            derive_daily: args.derive_daily,
+            fill_gaps: args.fill_gaps,
+            max_fill_attempts: args.max_fill_attempts,
+            summary_stats: args.summary_stats,
+            validate: args.validate,
+            resume: args.resume || args.resume_from_checkpoint.is_some(),
+            checkpoint: args.resume_from_checkpoint,
+            overwrite: args.overwrite,
+            revision_window: args
+                .revision_window
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            permissions: args.output_permissions,
+            monotonic: args.enforce_monotonic,
+            page_duplicates: args.page_duplicates,
+            strict_order: !args.unordered,
+            schema_check: args.strict_schema,
+            flat_file_layout: args.flat_file_layout,
+            grouped_by_ticker: args.by_ticker,
+            raw_ticker_paths: args.raw_ticker_paths,
+        };
+        match dumped {
+            Some(config) => with_flags(*config, &flags, &args.given),
+            None => Ok(flags),
+        }
+    }
+}
+
+/// The keys of [`Config`] set by the flags that aren't named after them.
+/// The other flags set the key of the same name, if there is one
+const RENAMED_FLAGS: &[(&str, &[&str])] = &[
+    ("span", &["timespan"]),
+    ("format", &["formats"]),
+    ("no_trailing_newline", &["csv_trailing_newline"]),
+    ("decimal_comma", &["csv_decimal_comma"]),
+    ("from", &["from", "extra_ranges"]),
+    ("to", &["to", "extra_ranges"]),
+    ("clamp_to_horizon", &["horizons"]),
+    ("horizon", &["horizons"]),
+    ("endpoint_version", &["aggregates_version"]),
+    ("pin_certificate", &["pinned_certificates"]),
+    ("no_adjusted", &["adjusted"]),
+    ("no_progress", &["progress"]),
+    ("retry_on_message", &["retry_messages"]),
+    ("rate_limit", &["requests_per_minute"]),
+    ("resume_from_checkpoint", &["resume", "checkpoint"]),
+    ("output_permissions", &["permissions"]),
+    ("enforce_monotonic", &["monotonic"]),
+    ("unordered", &["strict_order"]),
+    ("strict_schema", &["schema_check"]),
+    ("by_ticker", &["grouped_by_ticker"]),
+];
+
+/// `config` with what each of the `given` flags sets taken from `flags`, the
+/// config all the flags make up
+fn with_flags(
+    config: Config,
+    flags: &Config,
+    given: &[String],
+) -> Result<Config> {
+    let mut config = serde_json::to_value(config)?;
+    let flags = serde_json::to_value(flags)?;
+    for flag in given {
+        let keys = RENAMED_FLAGS
+            .iter()
+            .find(|(renamed, _)| renamed == flag)
+            .map_or_else(|| vec![flag.as_str()], |(_, keys)| keys.to_vec());
+        for key in keys {
+            if let Some(value) = flags.get(key) {
+                config[key] = value.clone();
+            }
+        }
+    }
+    Ok(serde_json::from_value(config)?)
+}
+
+/// The sink to POST records to instead of saving them, with --post-to
+fn http_sink(args: &Args) -> Result<Option<HttpSink>> {
+    let Some(url) = &args.post_to else {
+        return Ok(None);
+    };
+    let headers = args
+        .post_header
+        .iter()
+        .map(|header| {
+            let (name, value) = header.split_once(':').ok_or_else(|| {
+                Error::msg(format!("expected `name: value`, got `{header}`"))
+            })?;
+            Ok((name.trim(), value.trim()))
         })
+        .collect::<Result<Vec<_>>>()?;
+    let sink = HttpSink::with_headers(url, headers)?
+        .with_batch_size(args.post_batch_size)
+        .with_retry_policy(RetryPolicy {
+            max_retries: args.max_retries,
+            retry_server_errors: true,
+            ..Default::default()
+        });
+    Ok(Some(sink))
+}
+
+fn parse_horizon(horizon: &str) -> Result<(Market, NaiveDate)> {
+    let (market, date) = horizon
+        .split_once('=')
+        .ok_or_else(|| Error::msg("expected `market=date`"))?;
+    Ok((Market::from_str(market)?, NaiveDate::from_str(date)?))
+}
+
+fn parse_delimiter(delimiter: &str) -> Result<char> {
+    if delimiter == "tab" {
+        return Ok('\t');
+    }
+    let mut chars = delimiter.chars();
+    match (chars.next(), chars.next()) {
+        (Some(delimiter), None) => Ok(delimiter),
+        _ => bail!("expected a single character or `tab`"),
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(mode.trim_start_matches("0o"), 8)?;
+    if mode > 0o7777 {
+        bail!("expected a mode up to 7777");
+    }
+    Ok(mode)
+}
+
+/// The start of `date` in `tz`, which is 1 AM in time zones where the clocks
+/// spring forward at midnight on that day
+fn midnight(date: NaiveDate, tz: Tz, arg: &str) -> Result<DateTime<Utc>> {
+    let invalid =
+        || Error::msg(format!("couldn't construct date with {arg} argument"));
+    let start = date.and_hms_opt(0, 0, 0).ok_or_else(invalid)?;
+    let datetime = [0, 1]
+        .into_iter()
+        .find_map(|hour| {
+            let datetime = start + TimeDelta::hours(hour);
+            tz.from_local_datetime(&datetime).earliest()
+        })
+        .ok_or_else(invalid)?;
+    Ok(datetime.with_timezone(&Utc))
+}
+
+/// The formats a config can be written in
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+fn write_config(path: &Path, config: &Config) -> Result<()> {
+    let contents = match config_format(path)? {
+        ConfigFormat::Yaml => serde_yaml::to_string(config)?,
+        ConfigFormat::Toml => toml::to_string_pretty(config)?,
+        ConfigFormat::Json => serde_json::to_string_pretty(config)?,
+    };
+    fs::write(path, contents)
+        .with_context(|| format!("Failed to write file: {:?}", path))
+}
+
+/// The format of the config file at `path`, going by its extension
+fn config_format(path: &Path) -> Result<ConfigFormat> {
+    match path.extension().and_then(std::ffi::OsStr::to_str) {
+        Some("yaml" | "yml") => Ok(ConfigFormat::Yaml),
+        Some("toml") => Ok(ConfigFormat::Toml),
+        Some("json") => Ok(ConfigFormat::Json),
+        _ => bail!("Unknown extension"),
     }
 }
 
-fn parse_config(path: PathBuf) -> Result<Tickers, Error> {
-    let contents = fs::read_to_string(&path)
-        .with_context(|| format!("Failed to read file: {:?}", path))?;
+/// What a config file holds: the tickers to download, or the whole
+/// configuration of a run, as written by --dump-config
+enum ConfigFile {
+    Tickers(Tickers),
+    Dumped(Box<Config>),
+}
+
+fn parse_config(
+    path: PathBuf,
+    format: Option<ConfigFormat>,
+) -> Result<ConfigFile, Error> {
+    let from_stdin = path.as_os_str() == "-";
+    let contents = if from_stdin {
+        io::read_to_string(io::stdin())
+            .with_context(|| "Failed to read config from stdin")?
+    } else {
+        fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read file: {:?}", path))?
+    };
+
+    let format = match format {
+        Some(format) => format,
+        None if from_stdin => {
+            bail!("--config-format is required when reading the config from stdin")
+        }
+        None => config_format(&path)?,
+    };
 
-    let extension = path
-        .extension()
-        .and_then(std::ffi::OsStr::to_str)
-        .unwrap_or("");
+    // A list of tickers has nothing but `tickers`, anything more has to be
+    // a whole config as written by --dump-config
+    if let Ok(tickers) = deserialize(format, &contents) {
+        return Ok(ConfigFile::Tickers(tickers));
+    }
+    deserialize(format, &contents)
+        .map(|config| ConfigFile::Dumped(Box::new(config)))
+        .context(
+            "The config is neither a list of tickers nor a config written by \
+             --dump-config",
+        )
+}
 
-    match extension {
-        "yaml" | "yml" => serde_yaml::from_str(&contents)
+fn deserialize<T: DeserializeOwned>(
+    format: ConfigFormat,
+    contents: &str,
+) -> Result<T> {
+    match format {
+        ConfigFormat::Yaml => serde_yaml::from_str(contents)
             .with_context(|| "Failed to parse YAML"),
-        "toml" => {
-            toml::from_str(&contents).with_context(|| "Failed to parse TOML")
+        ConfigFormat::Toml => {
+            toml::from_str(contents).with_context(|| "Failed to parse TOML")
         }
-        "json" => serde_json::from_str(&contents)
+        ConfigFormat::Json => serde_json::from_str(contents)
             .with_context(|| "Failed to parse JSON"),
-        _ => {
-            bail!("Unknown extension")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use clap::CommandFactory;
+    use polygon_data::{config::Config, types::Timespan};
+
+    use super::{parse_config, write_config, Args};
+
+    #[test]
+    fn dumped_configs_can_be_run_again() {
+        let dir = std::env::temp_dir()
+            .join(format!("polygon-cli-dump-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tickers = dir.join("tickers.yaml");
+        std::fs::write(&tickers, "tickers: [spy, qqq]\n").unwrap();
+        let config = |args: &[&str]| {
+            let args = ["polygon-cli", "key"].iter().chain(args);
+            let matches = Args::command().get_matches_from(args);
+            Config::try_from(Args::from_matches(&matches)).unwrap()
+        };
+        let dumped = config(&[
+            "--output-dir",
+            "data",
+            "--config",
+            tickers.to_str().unwrap(),
+            "--span",
+            "minute",
+            "--from",
+            "2024-01-02",
+            "--to",
+            "2024-02-01",
+            "--resume",
+        ]);
+
+        for extension in ["yaml", "toml", "json"] {
+            let path = dir.join(format!("dumped.{extension}"));
+            write_config(&path, &dumped).unwrap();
+            let path = path.to_str().unwrap();
+            let again = config(&["--output-dir", "data", "--config", path]);
+            assert_eq!(
+                serde_json::to_value(&again).unwrap(),
+                serde_json::to_value(&dumped).unwrap()
+            );
+            // Only the flags given along with a dumped config change it
+            let moved =
+                config(&["--config", path, "-o", "other", "--span", "hour"]);
+            assert_eq!(moved.output_dir, Path::new("other"));
+            assert_eq!(moved.timespan, Timespan::Hour);
+            assert_eq!(moved.tickers, dumped.tickers);
+            assert_eq!((moved.from, moved.to), (dumped.from, dumped.to));
+            assert!(moved.resume);
         }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dumped_configs_missing_fields_are_an_error() {
+        let dir = std::env::temp_dir()
+            .join(format!("polygon-cli-stale-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stale.yaml");
+        std::fs::write(&path, "tickers: [SPY]\nshuffle: true\n").unwrap();
+        let parsed = parse_config(path, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(parsed.is_err());
     }
 }
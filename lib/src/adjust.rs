@@ -0,0 +1,123 @@
+use chrono::{DateTime, NaiveDate};
+use rust_decimal::Decimal;
+
+use crate::{
+    error::Error,
+    types::{AggregateRecord, Split},
+};
+
+/// Adjust the unadjusted prices and volumes of `records` the way they would
+/// have been adjusted on `as_of`: for every split executed on or before
+/// `as_of`, the records from before it are adjusted for it. Splits after
+/// `as_of` are ignored, so there's no look-ahead.
+///
+/// Polygon only adjusts prices for every split up until now, so this has to
+/// be done locally, starting from unadjusted prices. Dividends aren't
+/// adjusted for.
+pub fn adjust_as_of(
+    records: &mut [AggregateRecord],
+    splits: &[Split],
+    as_of: NaiveDate,
+) -> Result<(), Error> {
+    let splits: Vec<_> = splits
+        .iter()
+        .filter(|split| {
+            split.execution_date <= as_of
+                && !split.split_from.is_zero()
+                && !split.split_to.is_zero()
+        })
+        .collect();
+    for record in records {
+        let Some(date) = DateTime::from_timestamp_millis(record.timestamp)
+            .map(|timestamp| timestamp.date_naive())
+        else {
+            continue;
+        };
+        for split in splits.iter().filter(|split| date < split.execution_date) {
+            adjust(record, split)
+                .ok_or(Error::SplitOverflow(split.execution_date))?;
+        }
+    }
+    Ok(())
+}
+
+/// Adjust `record` for `split`, `None` if that overflows
+fn adjust(record: &mut AggregateRecord, split: &Split) -> Option<()> {
+    let price = |price: Decimal| {
+        let adjusted = price
+            .checked_mul(split.split_from)?
+            .checked_div(split.split_to)?;
+        Some(adjusted.normalize())
+    };
+    record.open = price(record.open)?;
+    record.high = price(record.high)?;
+    record.low = price(record.low)?;
+    record.close = price(record.close)?;
+    record.vwap = match record.vwap {
+        Some(vwap) => Some(price(vwap)?),
+        None => None,
+    };
+    record.volume = record
+        .volume
+        .checked_mul(split.split_to)?
+        .checked_div(split.split_from)?
+        .normalize();
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use rust_decimal::Decimal;
+
+    use super::adjust_as_of;
+    use crate::types::{AggregateRecord, Split};
+
+    fn bar(day: u32, price: i64, volume: i64) -> AggregateRecord {
+        AggregateRecord {
+            timestamp: Utc
+                .with_ymd_and_hms(2024, 6, day, 14, 30, 0)
+                .unwrap()
+                .timestamp_millis(),
+            open: Decimal::from(price),
+            high: Decimal::from(price),
+            low: Decimal::from(price),
+            close: Decimal::from(price),
+            volume: Decimal::from(volume),
+            transactions: None,
+            otc: None,
+            vwap: Some(Decimal::from(price)),
+        }
+    }
+
+    #[test]
+    fn only_splits_up_to_the_as_of_date_are_adjusted_for() {
+        // 4-for-1 on the 10th, then 2-for-1 on the 20th
+        let date = |day| NaiveDate::from_ymd_opt(2024, 6, day).unwrap();
+        let split = |day, split_to| Split {
+            ticker: "NVDA".to_string(),
+            execution_date: date(day),
+            split_from: Decimal::ONE,
+            split_to: Decimal::from(split_to),
+        };
+        let splits = [split(10, 4), split(20, 2)];
+        let bars =
+            || vec![bar(7, 800, 100), bar(10, 200, 400), bar(21, 100, 1)];
+
+        let mut records = bars();
+        adjust_as_of(&mut records, &splits, date(15)).unwrap();
+        let closes: Vec<_> = records.iter().map(|r| r.close).collect();
+        assert_eq!(closes, [200, 200, 100].map(Decimal::from));
+        assert_eq!(records[0].volume, Decimal::from(400));
+        assert_eq!(records[0].vwap, Some(Decimal::from(200)));
+
+        let mut records = bars();
+        adjust_as_of(&mut records, &splits, date(30)).unwrap();
+        let closes: Vec<_> = records.iter().map(|r| r.close).collect();
+        assert_eq!(closes, [100, 100, 100].map(Decimal::from));
+
+        let mut records = bars();
+        adjust_as_of(&mut records, &splits, date(1)).unwrap();
+        assert_eq!(records[0].close, Decimal::from(800));
+    }
+}
@@ -0,0 +1,160 @@
+use chrono::{Datelike, Days, NaiveDate, Weekday};
+
+use crate::types::Market;
+
+/// Whether `market` is open at some point on `date`.
+///
+/// Stocks, options and indices follow the regular NYSE holidays, observed on
+/// the nearest weekday. One-off closings and early closes aren't known.
+/// Forex trades on weekdays and crypto trades every day
+pub fn is_trading_day(market: Market, date: NaiveDate) -> bool {
+    match market {
+        Market::Stocks | Market::Options | Market::Indices => {
+            !is_weekend(date) && !is_nyse_holiday(date)
+        }
+        Market::Forex => !is_weekend(date),
+        Market::Crypto => true,
+    }
+}
+
+/// The days from `from` to `to`, inclusive, that `market` is open on
+pub fn trading_days(
+    market: Market,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> impl Iterator<Item = NaiveDate> {
+    from.iter_days()
+        .take_while(move |day| *day <= to)
+        .filter(move |day| is_trading_day(market, *day))
+}
+
+/// The days from `from` to `to`, inclusive, that `market` is closed on, the
+/// ones [`trading_days`] leaves out
+pub fn non_trading_days(
+    market: Market,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> impl Iterator<Item = NaiveDate> {
+    let mut trading = trading_days(market, from, to).peekable();
+    from.iter_days()
+        .take_while(move |day| *day <= to)
+        .filter(move |day| trading.next_if_eq(day).is_none())
+}
+
+fn is_weekend(date: NaiveDate) -> bool {
+    matches!(date.weekday(), Weekday::Sat | Weekday::Sun)
+}
+
+fn is_nyse_holiday(date: NaiveDate) -> bool {
+    let year = date.year();
+    let fixed =
+        |month, day| NaiveDate::from_ymd_opt(year, month, day).map(observed);
+    let nth = |month, weekday, n| {
+        NaiveDate::from_weekday_of_month_opt(year, month, weekday, n)
+    };
+    let last_monday_of_may = NaiveDate::from_ymd_opt(year, 5, 31).map(|day| {
+        day - Days::new(day.weekday().num_days_from_monday().into())
+    });
+    // New Year's Day falling on a Saturday isn't made up for on the Friday
+    // before, since that's the last trading day of the year
+    let new_years_day = NaiveDate::from_ymd_opt(year, 1, 1)
+        .filter(|day| day.weekday() != Weekday::Sat)
+        .map(observed);
+    [
+        new_years_day,
+        (year >= 1998).then(|| nth(1, Weekday::Mon, 3)).flatten(),
+        nth(2, Weekday::Mon, 3),
+        easter(year).map(|easter| easter - Days::new(2)),
+        last_monday_of_may,
+        (year >= 2022).then(|| fixed(6, 19)).flatten(),
+        fixed(7, 4),
+        nth(9, Weekday::Mon, 1),
+        nth(11, Weekday::Thu, 4),
+        fixed(12, 25),
+    ]
+    .contains(&Some(date))
+}
+
+/// The weekday a holiday on `date` is observed on: the Friday before a
+/// Saturday or the Monday after a Sunday
+fn observed(date: NaiveDate) -> NaiveDate {
+    match date.weekday() {
+        Weekday::Sat => date - Days::new(1),
+        Weekday::Sun => date + Days::new(1),
+        _ => date,
+    }
+}
+
+/// Easter Sunday in the Gregorian calendar, with the anonymous Gregorian
+/// algorithm
+fn easter(year: i32) -> Option<NaiveDate> {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd_opt(year, month as u32, day as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::{is_trading_day, trading_days};
+    use crate::types::Market;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn stocks_are_closed_on_nyse_holidays() {
+        for holiday in [
+            date(2024, 1, 15),
+            date(2024, 3, 29),
+            date(2024, 5, 27),
+            date(2024, 6, 19),
+            date(2024, 11, 28),
+            date(2024, 12, 25),
+            // Independence Day on a Saturday is observed on the Friday
+            date(2026, 7, 3),
+        ] {
+            assert!(!is_trading_day(Market::Stocks, holiday), "{holiday}");
+            assert!(is_trading_day(Market::Crypto, holiday));
+        }
+        assert!(is_trading_day(Market::Stocks, date(2024, 7, 5)));
+        // Juneteenth was only added in 2022
+        assert!(is_trading_day(Market::Stocks, date(2020, 6, 19)));
+    }
+
+    #[test]
+    fn weekends_have_no_trading_days_except_for_crypto() {
+        let (saturday, sunday) = (date(2024, 1, 6), date(2024, 1, 7));
+        assert_eq!(trading_days(Market::Stocks, saturday, sunday).count(), 0);
+        assert_eq!(trading_days(Market::Forex, saturday, sunday).count(), 0);
+        assert_eq!(trading_days(Market::Crypto, saturday, sunday).count(), 2);
+        assert_eq!(
+            trading_days(
+                Market::Stocks,
+                date(2024, 12, 23),
+                date(2024, 12, 27)
+            )
+            .collect::<Vec<_>>(),
+            [
+                date(2024, 12, 23),
+                date(2024, 12, 24),
+                date(2024, 12, 26),
+                date(2024, 12, 27)
+            ]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{error, types::DateRange};
+
+/// A record of which date ranges have been downloaded for each file, so an
+/// interrupted run can pick up where it left off.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Checkpoint {
+    /// The ranges that have been fully downloaded, keyed by the path of the
+    /// file they were written to, relative to the output directory.
+    #[serde(default)]
+    pub completed: BTreeMap<String, Vec<DateRange>>,
+}
+
+impl Checkpoint {
+    /// Load the checkpoint at `path`, or an empty one if there isn't one yet
+    pub fn load(path: &Path) -> Result<Self, error::FileIo> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            Err(e) => Err(error::FileIo::ReadFile(e)),
+        }
+    }
+
+    /// Save the checkpoint to `path`. The checkpoint is written to a
+    /// temporary file first so a crash can't leave a half-written checkpoint
+    pub async fn save(&self, path: &Path) -> Result<(), error::FileIo> {
+        let tmp_path = path.with_extension("json.tmp");
+        let contents = serde_json::to_string_pretty(self)?;
+        tokio::fs::write(&tmp_path, contents)
+            .await
+            .map_err(error::FileIo::FileWrite)?;
+        tokio::fs::rename(&tmp_path, path)
+            .await
+            .map_err(error::FileIo::FileWrite)
+    }
+
+    /// Whether `from..=to` is covered by a range already downloaded for `key`
+    pub fn is_complete(
+        &self,
+        key: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> bool {
+        self.completed.get(key).is_some_and(|ranges| {
+            ranges
+                .iter()
+                .any(|(start, end)| *start <= from && to <= *end)
+        })
+    }
+
+    /// Record that `from..=to` has been downloaded for `key`
+    pub fn complete(
+        &mut self,
+        key: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) {
+        let ranges = self.completed.entry(key.to_string()).or_default();
+        ranges.push((from, to));
+        ranges.sort();
+    }
+
+    /// Forget everything downloaded for `key`
+    pub fn clear(&mut self, key: &str) {
+        self.completed.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::Checkpoint;
+
+    #[test]
+    fn sub_ranges_of_completed_ranges_are_complete() {
+        let jan = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let feb = Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap();
+        let mar = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.complete("SPY/minute", jan, mar);
+
+        assert!(checkpoint.is_complete("SPY/minute", jan, feb));
+        assert!(checkpoint.is_complete("SPY/minute", feb, mar));
+        assert!(!checkpoint.is_complete("SPY/day", jan, feb));
+        assert!(!checkpoint.is_complete(
+            "SPY/minute",
+            feb,
+            Utc.with_ymd_and_hms(2024, 4, 1, 0, 0, 0).unwrap()
+        ));
+    }
+}
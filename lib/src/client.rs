@@ -1,20 +1,75 @@
-use std::str::FromStr;
+use std::{
+    collections::BTreeMap,
+    num::NonZeroU32,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
-use tracing::{debug, instrument};
+use chrono::{DateTime, NaiveDate, Utc};
+use reqwest::{
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    StatusCode,
+};
+use serde::de::DeserializeOwned;
+use tokio::time::sleep;
+use tracing::{debug, info, instrument, warn};
 use url::Url;
 
 use crate::{
+    coalesce::Coalescer,
     error::{self, Error},
-    types::{AggregateRequest, AggregateResponse},
+    pinning,
+    rate_limit::RateLimiter,
+    retry::{RetryPolicy, RetryState},
+    schema::{self, SchemaCheck},
+    types::{
+        AggregateRecord, AggregateRequest, AggregateResponse, ErrorResponse,
+        GroupedDailyRecord, GroupedDailyResponse, HttpVersion, Split,
+        SplitsResponse, TickerDetails, TickerDetailsResponse,
+    },
 };
 
-const MULIPLIER: usize = 1;
-const BASE_URL: &str = "https://api.polygon.io";
+/// Where requests are sent unless configured otherwise
+pub const DEFAULT_BASE_URL: &str = "https://api.polygon.io";
+/// The version of Polygon's aggregates API used unless configured otherwise
+pub const DEFAULT_AGGREGATES_VERSION: &str = "v2";
+/// The longest URL requests are sent to. Servers and proxies commonly refuse
+/// longer ones with a 414, so these fail before being sent instead
+pub const MAX_URL_LENGTH: usize = 8_000;
 
 #[derive(Clone)]
 pub struct Client {
     inner: reqwest::Client,
+    /// Sent with every request, kept to build `inner` again with
+    headers: HeaderMap,
+    /// The TLS config `inner` was built with, if not the default one
+    tls: Option<rustls::ClientConfig>,
+    /// The only version of HTTP `inner` talks, if it's forced
+    http_version: Option<HttpVersion>,
+    /// The URL the paths of the endpoints are appended to, without a
+    /// trailing slash
+    base_url: String,
+    retry_policy: RetryPolicy,
+    /// The version segment in the path of the aggregates endpoints, e.g. `v2`
+    aggregates_version: String,
+    /// Called whenever a request starts or stops waiting to be retried
+    on_retry: Option<Arc<dyn Fn(RetryState) + Send + Sync>>,
+    /// How long to wait for a response, unless a request overrides it
+    timeout: Option<Duration>,
+    /// Whether to check aggregates responses for fields we don't know about
+    schema_check: Option<SchemaCheck>,
+    /// Shares the body of a response between identical requests in flight
+    coalescer: Option<Coalescer<String, Result<String, Arc<Error>>>>,
+    /// Shared by every clone of the client, so that all requests draw from
+    /// the same budget
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Set once Polygon marks a response as `DELAYED`, shared by every clone
+    /// of the client
+    delayed: Arc<AtomicBool>,
 }
 
 impl Client {
@@ -30,49 +85,889 @@ impl Client {
             (header::ACCEPT, HeaderValue::from_static("application/json")),
         ]);
         let inner = reqwest::Client::builder()
-            .default_headers(headers)
+            .default_headers(headers.clone())
             .build()
             .map_err(error::Init::ClientInitialization)?;
-        Ok(Self { inner })
+        Ok(Self {
+            inner,
+            headers,
+            tls: None,
+            http_version: None,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            retry_policy: RetryPolicy::default(),
+            aggregates_version: DEFAULT_AGGREGATES_VERSION.to_string(),
+            on_retry: None,
+            timeout: None,
+            schema_check: None,
+            coalescer: None,
+            rate_limiter: None,
+            delayed: Arc::default(),
+        })
+    }
+
+    /// Use `version` of Polygon's aggregates API, e.g. `v3`, instead of
+    /// [`DEFAULT_AGGREGATES_VERSION`]
+    pub fn with_aggregates_version(
+        mut self,
+        version: &str,
+    ) -> Result<Self, error::Init> {
+        let is_valid = version.strip_prefix('v').is_some_and(|n| {
+            !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())
+        });
+        if !is_valid {
+            return Err(error::Init::InvalidApiVersion(version.to_string()));
+        }
+        self.aggregates_version = version.to_string();
+        Ok(self)
+    }
+
+    /// Send requests to `base_url` instead of [`DEFAULT_BASE_URL`], e.g. a
+    /// caching proxy or a mock server. It can have a path, which the paths
+    /// of the endpoints are appended to. The `next_url`s of paginated
+    /// responses that point at Polygon are sent there too
+    pub fn with_base_url(
+        mut self,
+        base_url: &str,
+    ) -> Result<Self, error::Init> {
+        let invalid = || error::Init::InvalidBaseUrl(base_url.to_string());
+        let url = Url::parse(base_url).map_err(|_| invalid())?;
+        let is_valid = matches!(url.scheme(), "http" | "https")
+            && url.has_host()
+            && url.query().is_none()
+            && url.fragment().is_none();
+        if !is_valid {
+            return Err(invalid());
+        }
+        self.base_url = url.as_str().trim_end_matches('/').to_string();
+        Ok(self)
+    }
+
+    /// Only connect to servers that send one of the certificates with these
+    /// SHA-256 `fingerprints`, either their own or an intermediate one, see
+    /// [`Fingerprint`](pinning::Fingerprint). It's checked during the TLS
+    /// handshake, so nothing is sent to other servers. The certificates also
+    /// have to be trusted by Mozilla's root certificates rather than the
+    /// system's
+    pub fn with_pinned_certificates(
+        mut self,
+        fingerprints: &[impl AsRef<str>],
+    ) -> Result<Self, error::Init> {
+        if fingerprints.is_empty() {
+            return Ok(self);
+        }
+        let pins = fingerprints
+            .iter()
+            .map(|fingerprint| fingerprint.as_ref().parse())
+            .collect::<Result<_, _>>()?;
+        self.tls = Some(pinning::tls_config(pins)?);
+        self.rebuild()
+    }
+
+    /// Only talk to the server in `version` of HTTP, instead of negotiating
+    /// it. `None` keeps negotiating
+    pub fn with_http_version(
+        mut self,
+        version: Option<HttpVersion>,
+    ) -> Result<Self, error::Init> {
+        if version.is_none() {
+            return Ok(self);
+        }
+        self.http_version = version;
+        self.rebuild()
+    }
+
+    /// Build `inner` again after changing how it connects
+    fn rebuild(mut self) -> Result<Self, error::Init> {
+        let mut builder =
+            reqwest::Client::builder().default_headers(self.headers.clone());
+        builder = match self.http_version {
+            Some(HttpVersion::Http1) => builder.http1_only(),
+            Some(HttpVersion::Http2) => builder.http2_prior_knowledge(),
+            None => builder,
+        };
+        if let Some(tls) = &self.tls {
+            let mut tls = tls.clone();
+            // Only offer the version that's forced in the handshake
+            tls.alpn_protocols
+                .retain(|protocol| match self.http_version {
+                    Some(HttpVersion::Http1) => protocol == b"http/1.1",
+                    Some(HttpVersion::Http2) => protocol == b"h2",
+                    None => true,
+                });
+            builder = builder.use_preconfigured_tls(tls);
+        }
+        self.inner =
+            builder.build().map_err(error::Init::ClientInitialization)?;
+        Ok(self)
+    }
+
+    /// Retry failed requests according to `retry_policy`
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Give up on requests that take longer than `timeout`, unless the
+    /// request overrides it, e.g. with [`AggregateRequestBuilder::timeout`]
+    ///
+    /// [`AggregateRequestBuilder::timeout`]: crate::types::AggregateRequestBuilder::timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Check aggregates responses for fields that aren't in the schema this
+    /// crate expects, which usually means Polygon changed its API
+    pub fn with_schema_check(mut self, check: Option<SchemaCheck>) -> Self {
+        self.schema_check = check;
+        self
+    }
+
+    /// Send a single request for identical requests that are in flight at
+    /// the same time, e.g. from overlapping ranges in the config, and give
+    /// each of them the same response
+    pub fn with_coalescing(mut self, coalesce: bool) -> Self {
+        self.coalescer = coalesce.then(Coalescer::default);
+        self
+    }
+
+    /// Send at most `requests_per_minute` requests a minute, across every
+    /// clone of the client. Requests over the limit wait for their turn
+    pub fn with_rate_limit(mut self, requests_per_minute: NonZeroU32) -> Self {
+        self.rate_limiter =
+            Some(Arc::new(RateLimiter::per_minute(requests_per_minute)));
+        self
+    }
+
+    /// Whether requests are rate limited
+    pub fn is_rate_limited(&self) -> bool {
+        self.rate_limiter.is_some()
+    }
+
+    /// Whether Polygon has sent any response with the `DELAYED` status,
+    /// which it does on plans without real-time data
+    pub fn is_delayed(&self) -> bool {
+        self.delayed.load(Ordering::Relaxed)
+    }
+
+    /// Keep track of the plan tier the `status` of a response hints at,
+    /// logging it the first time the data is delayed
+    fn note_status(&self, status: &str) {
+        if status == "DELAYED" && !self.delayed.swap(true, Ordering::Relaxed) {
+            info!(
+                "Detected delayed data, likely a free or basic Polygon plan. \
+                 The most recent bars may be missing or behind the market"
+            );
+        }
+    }
+
+    /// Call `on_retry` whenever a request starts or stops waiting to be
+    /// retried, e.g. to show the backoff in a progress bar
+    pub fn with_on_retry(
+        mut self,
+        on_retry: impl Fn(RetryState) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_retry = Some(Arc::new(on_retry));
+        self
     }
 
-    #[instrument(skip_all, err, fields(ticker = %request.ticker))]
     pub async fn get_aggregate(
         &self,
         request: &AggregateRequest<'_>,
     ) -> Result<AggregateResponse, Error> {
+        let (response, _body) = self.get_aggregate_raw(request).await?;
+        Ok(response)
+    }
+
+    /// Same as [`Client::get_aggregate`], but also returns the raw, unparsed
+    /// body of the response
+    #[instrument(skip_all, err, fields(ticker = %request.ticker))]
+    pub async fn get_aggregate_raw(
+        &self,
+        request: &AggregateRequest<'_>,
+    ) -> Result<(AggregateResponse, String), Error> {
+        let url = self.aggregate_url(request)?;
+        let (response, body): (AggregateResponse, _) =
+            self.get(url, request.timeout).await?;
+        debug!(num_results = %response.results.len(), "Got response");
+        self.note_status(&response.status);
+        if let Some(check) = self.schema_check {
+            if let Err(e) = schema::check_aggregate_response(&body) {
+                match check {
+                    SchemaCheck::Warn => {
+                        warn!(error = %e, "Response doesn't match the expected schema");
+                    }
+                    SchemaCheck::Error => {
+                        return Err(Error::UnexpectedSchema(e))
+                    }
+                }
+            }
+        }
+        Ok((response, body))
+    }
+
+    fn aggregate_url(
+        &self,
+        request: &AggregateRequest<'_>,
+    ) -> Result<Url, Error> {
         let AggregateRequest {
             ticker,
             timespan,
+            multiplier,
             from,
             to,
             next_url,
             limit,
+            sort,
+            adjusted,
+            ..
         } = request;
         let from = from.timestamp_millis();
         let to = to.timestamp_millis();
+        let version = &self.aggregates_version;
+        let base_url = &self.base_url;
         let url = if let Some(url) = next_url {
-            Url::from_str(url)?
+            self.rebase(Url::from_str(url)?)?
         } else {
             Url::from_str(&format!(
-                "{BASE_URL}/v2/aggs/ticker/{ticker}/range/{MULIPLIER}/{timespan}/{from}/{to}?limit={limit}"
+                "{base_url}/{version}/aggs/ticker/{ticker}/range/{multiplier}/{timespan}/{from}/{to}?limit={limit}&sort={sort}&adjusted={adjusted}"
             ))?
         };
+        Ok(url)
+    }
 
-        let response = self
-            .inner
-            .get(url)
-            .send()
-            .await
-            .map_err(Error::SendRequest)?;
-        let status = response.status();
-        let response: AggregateResponse = response
-            .error_for_status()
-            .map_err(Error::UnexpectedStatus)?
-            .json()
-            .await
-            .map_err(Error::Deserialization)?;
-        debug!(status = %status, num_results = %response.results.len(), "Got response");
+    /// Get the daily bar of every ticker in the US stock market for `date`,
+    /// with prices `adjusted` for splits or not
+    #[instrument(skip(self), err)]
+    pub async fn get_grouped_daily(
+        &self,
+        date: NaiveDate,
+        adjusted: bool,
+    ) -> Result<GroupedDailyResponse, Error> {
+        let (base_url, version) = (&self.base_url, &self.aggregates_version);
+        let url = Url::from_str(&format!(
+            "{base_url}/{version}/aggs/grouped/locale/us/market/stocks/{date}?adjusted={adjusted}"
+        ))?;
+        let (response, _body): (GroupedDailyResponse, _) =
+            self.get(url, None).await?;
+        debug!(num_results = %response.results.len(), "Got response");
+        self.note_status(&response.status);
         Ok(response)
     }
+
+    /// Same as [`get_grouped_daily`](Self::get_grouped_daily), with the bar
+    /// of each ticker in the same shape as the bars of a single ticker
+    pub async fn get_grouped_daily_by_ticker(
+        &self,
+        date: NaiveDate,
+        adjusted: bool,
+    ) -> Result<BTreeMap<String, AggregateRecord>, Error> {
+        let response = self.get_grouped_daily(date, adjusted).await?;
+        Ok(response
+            .results
+            .into_iter()
+            .map(GroupedDailyRecord::into_aggregate)
+            .collect())
+    }
+
+    /// Get the reference data Polygon has on `ticker`, like its name and
+    /// when it was listed
+    #[instrument(skip(self), err)]
+    pub async fn get_ticker_details(
+        &self,
+        ticker: &str,
+    ) -> Result<TickerDetails, Error> {
+        let base_url = &self.base_url;
+        let url = Url::from_str(&format!(
+            "{base_url}/v3/reference/tickers/{ticker}"
+        ))?;
+        let (response, _body): (TickerDetailsResponse, _) =
+            self.get(url, None).await?;
+        Ok(response.results)
+    }
+
+    /// Get every split of `ticker` Polygon knows about, following the pages
+    /// of the response
+    #[instrument(skip(self), err)]
+    pub async fn get_splits(&self, ticker: &str) -> Result<Vec<Split>, Error> {
+        let base_url = &self.base_url;
+        let mut url = Some(Url::from_str(&format!(
+            "{base_url}/v3/reference/splits?ticker={ticker}&limit=1000"
+        ))?);
+        let mut splits = Vec::new();
+        while let Some(next) = url {
+            let (response, _body): (SplitsResponse, _) =
+                self.get(next, None).await?;
+            splits.extend(response.results);
+            url = match response.next_url {
+                Some(next_url) => Some(self.rebase(Url::from_str(&next_url)?)?),
+                None => None,
+            };
+        }
+        debug!(num_splits = splits.len(), "Got splits");
+        Ok(splits)
+    }
+
+    /// `url` on the client's base URL instead, if it's one of Polygon's
+    fn rebase(&self, url: Url) -> Result<Url, Error> {
+        let polygon = Url::from_str(DEFAULT_BASE_URL)?;
+        if self.base_url == DEFAULT_BASE_URL || url.origin() != polygon.origin()
+        {
+            return Ok(url);
+        }
+        let mut rebased =
+            Url::from_str(&format!("{}{}", self.base_url, url.path()))?;
+        rebased.set_query(url.query());
+        Ok(rebased)
+    }
+
+    /// Send a GET request to `url` and deserialize the body of the response,
+    /// returning the raw body alongside it. `timeout` overrides the client's
+    /// timeout.
+    async fn get<T: DeserializeOwned>(
+        &self,
+        url: Url,
+        timeout: Option<Duration>,
+    ) -> Result<(T, String), Error> {
+        let body = match &self.coalescer {
+            Some(coalescer) => {
+                let client = self.clone();
+                coalescer
+                    .run(url.to_string(), move || async move {
+                        client.get_body(url, timeout).await.map_err(Arc::new)
+                    })
+                    .await
+                    .map_err(|e| {
+                        Arc::try_unwrap(e).unwrap_or_else(Error::Coalesced)
+                    })?
+            }
+            None => self.get_body(url, timeout).await?,
+        };
+        let response = serde_json::from_str(&body)?;
+        Ok((response, body))
+    }
+
+    /// The body of a successful response to a GET request to `url`. Failed
+    /// requests are retried according to the client's [`RetryPolicy`]
+    async fn get_body(
+        &self,
+        url: Url,
+        timeout: Option<Duration>,
+    ) -> Result<String, Error> {
+        let length = url.as_str().len();
+        if length > MAX_URL_LENGTH {
+            return Err(Error::UrlTooLong {
+                length,
+                max: MAX_URL_LENGTH,
+            });
+        }
+        let mut attempt = 0;
+        loop {
+            match self.try_get(url.clone(), timeout).await {
+                Err(e)
+                    if attempt < self.retry_policy.max_retries
+                        && self.retry_policy.is_retryable(&e) =>
+                {
+                    let delay = e
+                        .retry_after()
+                        .unwrap_or_else(|| self.retry_policy.backoff(attempt));
+                    warn!(error = %e, attempt, ?delay, "Retrying request");
+                    self.notify(RetryState::Backoff { attempt, delay });
+                    sleep(delay).await;
+                    self.notify(RetryState::Retrying);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn notify(&self, state: RetryState) {
+        if let Some(on_retry) = &self.on_retry {
+            on_retry(state);
+        }
+    }
+
+    fn request(
+        &self,
+        url: Url,
+        timeout: Option<Duration>,
+    ) -> reqwest::RequestBuilder {
+        let request = self.inner.get(url);
+        match timeout.or(self.timeout) {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+
+    async fn try_get(
+        &self,
+        url: Url,
+        timeout: Option<Duration>,
+    ) -> Result<String, Error> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+        let response =
+            self.request(url, timeout).send().await.map_err(|e| {
+                match pinning::unpinned(&e) {
+                    Some(fingerprint) => {
+                        Error::UnpinnedCertificate(fingerprint)
+                    }
+                    None => Error::SendRequest(e),
+                }
+            })?;
+        let status = response.status();
+        if let Err(e) = response.error_for_status_ref() {
+            let retry_after =
+                retry_after(response.headers(), self.retry_policy.max_delay);
+            let body = response.text().await.unwrap_or_default();
+            let error =
+                api_error(status, &body).unwrap_or(Error::UnexpectedStatus(e));
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                return Err(Error::RateLimited {
+                    retry_after,
+                    source: Box::new(error),
+                });
+            }
+            return Err(error);
+        }
+        debug!(status = %status, "Got response");
+        let body = response.text().await.map_err(Error::Deserialization)?;
+        match self.soft_error(&body) {
+            Some(message) => Err(Error::ApiError {
+                status_code: status,
+                message,
+            }),
+            None => Ok(body),
+        }
+    }
+
+    /// The error message in the `body` of a successful response, if it has
+    /// one the retry policy retries. Polygon sends some transient failures
+    /// like this rather than with an error status
+    fn soft_error(&self, body: &str) -> Option<String> {
+        if self.retry_policy.retry_messages.is_empty() {
+            return None;
+        }
+        let ErrorResponse { message, .. } = serde_json::from_str(body).ok()?;
+        self.retry_policy
+            .retries_message(&message)
+            .then_some(message)
+    }
+}
+
+/// How long the `Retry-After` header asks to wait, given either in seconds
+/// or as the date to wait until, but no longer than `max`
+fn retry_after(headers: &HeaderMap, max: Duration) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let until = DateTime::parse_from_rfc2822(value).ok()?;
+            (until.with_timezone(&Utc) - Utc::now()).to_std().ok()?
+        }
+    };
+    Some(delay.min(max))
+}
+
+/// Polygon usually explains what went wrong in the body of a non-2xx
+/// response, so surface that message if there is one
+fn api_error(status_code: StatusCode, body: &str) -> Option<Error> {
+    let ErrorResponse { message, .. } = serde_json::from_str(body).ok()?;
+    Some(Error::ApiError {
+        status_code,
+        message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use reqwest::{
+        header::{self, HeaderMap, HeaderValue},
+        StatusCode,
+    };
+
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{api_error, retry_after, Client, MAX_URL_LENGTH};
+    use crate::{
+        error::{self, Error},
+        retry::RetryPolicy,
+        types::{
+            AggregateRequest, AggregateRequestBuilder, HttpVersion, SortOrder,
+        },
+    };
+
+    /// A response with a single bar and no next page
+    fn aggregate_response(ticker: &str) -> serde_json::Value {
+        serde_json::json!({
+            "ticker": ticker,
+            "adjusted": true,
+            "queryCount": 1,
+            "request_id": "1",
+            "resultsCount": 1,
+            "status": "OK",
+            "results": [{
+                "t": 1_704_205_800_000_i64,
+                "o": 1, "h": 2, "l": 0.5, "c": 1.5, "v": 100
+            }],
+        })
+    }
+
+    #[tokio::test]
+    async fn requests_go_to_the_base_url() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v2/aggs/ticker/SPY/range/1/minute/1704067200000/1704153600000"))
+            .and(query_param("limit", "5000"))
+            .and(query_param("sort", "asc"))
+            .and(query_param("adjusted", "false"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(aggregate_response("SPY")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+            .limit(5000)
+            .adjusted(false)
+            .build()
+            .unwrap();
+        let client = Client::new("key")
+            .unwrap()
+            .with_base_url(&server.uri())
+            .unwrap();
+        let response = client.get_aggregate(&request).await.unwrap();
+        assert_eq!(response.results.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn delayed_responses_are_noted() {
+        let server = MockServer::start().await;
+        let mut delayed = aggregate_response("SPY");
+        delayed["status"] = "DELAYED".into();
+        Mock::given(path("/v2/aggs/ticker/SPY/range/1/minute/1/2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(aggregate_response("SPY")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/v2/aggs/ticker/SPY/range/1/minute/2/3"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(delayed))
+            .mount(&server)
+            .await;
+        let client = Client::new("key")
+            .unwrap()
+            .with_base_url(&server.uri())
+            .unwrap();
+        let request = |from, to| {
+            AggregateRequestBuilder::default()
+                .ticker("SPY")
+                .from(Utc.timestamp_millis_opt(from).unwrap())
+                .to(Utc.timestamp_millis_opt(to).unwrap())
+                .limit(5000)
+                .build()
+                .unwrap()
+        };
+        client.get_aggregate(&request(1, 2)).await.unwrap();
+        assert!(!client.is_delayed());
+        // Clones share what's been seen, like the service's workers do
+        client.clone().get_aggregate(&request(2, 3)).await.unwrap();
+        assert!(client.is_delayed());
+    }
+
+    #[test]
+    fn next_pages_go_to_the_base_url() {
+        let client = Client::new("key")
+            .unwrap()
+            .with_base_url("http://localhost:8080/polygon/")
+            .unwrap();
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+            .limit(5000)
+            .next_url(
+                "https://api.polygon.io/v2/aggs/ticker/SPY?cursor=abc"
+                    .to_string(),
+            )
+            .build()
+            .unwrap();
+        let url = client.aggregate_url(&request).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://localhost:8080/polygon/v2/aggs/ticker/SPY?cursor=abc"
+        );
+    }
+
+    #[tokio::test]
+    async fn http_version_can_be_forced() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(aggregate_response("SPY")),
+            )
+            .mount(&server)
+            .await;
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+            .limit(5000)
+            .build()
+            .unwrap();
+        for version in [HttpVersion::Http1, HttpVersion::Http2] {
+            let client = Client::new("key")
+                .unwrap()
+                .with_base_url(&server.uri())
+                .unwrap()
+                .with_http_version(Some(version))
+                .unwrap();
+            client.get_aggregate(&request).await.unwrap();
+        }
+        // HTTP/2 sends the host as the `:authority` of the request instead
+        let requests = server.received_requests().await.unwrap();
+        assert!(requests[0].headers.contains_key(header::HOST));
+        assert!(!requests[1].headers.contains_key(header::HOST));
+    }
+
+    #[tokio::test]
+    async fn long_urls_are_rejected_before_sending() {
+        let server = MockServer::start().await;
+        let client = Client::new("key")
+            .unwrap()
+            .with_base_url(&server.uri())
+            .unwrap();
+        let cursor = "a".repeat(MAX_URL_LENGTH);
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+            .limit(5000)
+            .next_url(format!("https://api.polygon.io/v2/next?cursor={cursor}"))
+            .build()
+            .unwrap();
+        let result = client.get_aggregate(&request).await;
+        assert!(matches!(
+            result,
+            Err(Error::UrlTooLong {
+                max: MAX_URL_LENGTH,
+                ..
+            })
+        ));
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn soft_errors_with_a_retryable_message_are_retried() {
+        let server = MockServer::start().await;
+        let soft_error = serde_json::json!({
+            "status": "ERROR",
+            "request_id": "1",
+            "error": "Temporary backend issue, please try again",
+        });
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(soft_error))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(aggregate_response("SPY")),
+            )
+            .mount(&server)
+            .await;
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+            .limit(5000)
+            .build()
+            .unwrap();
+        let client = Client::new("key")
+            .unwrap()
+            .with_base_url(&server.uri())
+            .unwrap()
+            .with_retry_policy(RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                retry_messages: vec!["try again".into()],
+                ..Default::default()
+            });
+        let response = client.get_aggregate(&request).await.unwrap();
+        assert_eq!(response.results.len(), 1);
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn base_url_must_be_http() {
+        for base_url in ["not a url", "ftp://example.com", "http://a/?q=1"] {
+            assert!(matches!(
+                Client::new("key").unwrap().with_base_url(base_url),
+                Err(error::Init::InvalidBaseUrl(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn aggregates_version_is_in_the_path() {
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+            .limit(5000)
+            .build()
+            .unwrap();
+        let client = Client::new("key").unwrap();
+        let url = client.aggregate_url(&request).unwrap();
+        assert!(url.path().starts_with("/v2/aggs/ticker/SPY/"));
+
+        let client = client.with_aggregates_version("v3").unwrap();
+        let url = client.aggregate_url(&request).unwrap();
+        assert!(url.path().starts_with("/v3/aggs/ticker/SPY/"));
+    }
+
+    #[test]
+    fn sort_order_is_in_the_query() {
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+            .limit(5000)
+            .sort(SortOrder::Desc)
+            .build()
+            .unwrap();
+        let url = Client::new("key").unwrap().aggregate_url(&request).unwrap();
+        assert_eq!(url.query(), Some("limit=5000&sort=desc&adjusted=true"));
+    }
+
+    #[test]
+    fn multiplier_is_in_the_path() {
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .multiplier(15)
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+            .limit(5000)
+            .build()
+            .unwrap();
+        let url = Client::new("key").unwrap().aggregate_url(&request).unwrap();
+        assert!(url.path().contains("/range/15/minute/"));
+    }
+
+    #[test]
+    fn unadjusted_prices_can_be_requested() {
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+            .limit(5000)
+            .adjusted(false)
+            .build()
+            .unwrap();
+        let url = Client::new("key").unwrap().aggregate_url(&request).unwrap();
+        assert!(url
+            .query_pairs()
+            .any(|(k, v)| k == "adjusted" && v == "false"));
+    }
+
+    #[test]
+    fn request_timeout_overrides_the_client_timeout() {
+        let builder = || {
+            AggregateRequestBuilder::default()
+                .ticker("SPY")
+                .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+                .to(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+                .limit(5000)
+                .clone()
+        };
+        let client = Client::new("key")
+            .unwrap()
+            .with_timeout(Duration::from_secs(30));
+        let timeout = |request: AggregateRequest| {
+            let url = client.aggregate_url(&request).unwrap();
+            client
+                .request(url, request.timeout)
+                .build()
+                .unwrap()
+                .timeout()
+                .copied()
+        };
+        assert_eq!(
+            timeout(builder().build().unwrap()),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            timeout(
+                builder().timeout(Duration::from_secs(300)).build().unwrap()
+            ),
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn aggregates_version_must_look_like_a_version() {
+        for version in ["", "v", "2", "v2/../", "V2"] {
+            assert!(Client::new("key")
+                .unwrap()
+                .with_aggregates_version(version)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn api_error_includes_polygon_message() {
+        let body = r#"{"status":"ERROR","request_id":"abc","error":"Could not parse the time parameter: 'from'."}"#;
+        let error = api_error(StatusCode::BAD_REQUEST, body);
+        assert!(matches!(
+            error,
+            Some(Error::ApiError { status_code: StatusCode::BAD_REQUEST, ref message })
+                if message == "Could not parse the time parameter: 'from'."
+        ));
+    }
+
+    #[test]
+    fn retry_after_is_read_in_seconds_or_as_a_date() {
+        let headers = |value: &str| {
+            HeaderMap::from_iter([(
+                header::RETRY_AFTER,
+                HeaderValue::from_str(value).unwrap(),
+            )])
+        };
+        let max = Duration::from_secs(300);
+        assert_eq!(
+            retry_after(&headers("120"), max),
+            Some(Duration::from_secs(120))
+        );
+        let soon = (Utc::now() + chrono::TimeDelta::seconds(30)).to_rfc2822();
+        let delay = retry_after(&headers(&soon), max).unwrap();
+        assert!(delay > Duration::from_secs(25));
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT"), max),
+            None
+        );
+        assert_eq!(retry_after(&HeaderMap::new(), max), None);
+        // Waiting is capped at the retry policy's longest delay
+        assert_eq!(retry_after(&headers("86400"), max), Some(max));
+        let later = (Utc::now() + chrono::TimeDelta::days(1)).to_rfc2822();
+        assert_eq!(retry_after(&headers(&later), max), Some(max));
+    }
+
+    #[test]
+    fn api_error_ignores_non_json_bodies() {
+        assert!(api_error(StatusCode::BAD_GATEWAY, "<html></html>").is_none());
+    }
 }
@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+
+/// Runs a single future for each key at a time, so that callers asking for
+/// the same key while it's in flight share its output instead of running it
+/// again
+#[derive(Clone)]
+pub struct Coalescer<K, V: Clone> {
+    in_flight: Arc<Mutex<HashMap<K, Shared<BoxFuture<'static, V>>>>>,
+}
+
+impl<K, V: Clone> Default for Coalescer<K, V> {
+    fn default() -> Self {
+        Self {
+            in_flight: Arc::default(),
+        }
+    }
+}
+
+impl<K, V> Coalescer<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone + Send + Sync + 'static,
+{
+    /// The output of `run` for `key`, or of the future already running for
+    /// `key` if there is one
+    pub async fn run<F>(&self, key: K, run: impl FnOnce() -> F) -> V
+    where
+        F: Future<Output = V> + Send + 'static,
+    {
+        let future = self
+            .in_flight
+            .lock()
+            .expect("not poisoned")
+            .entry(key.clone())
+            .or_insert_with(|| run().boxed().shared())
+            .clone();
+        let output = future.clone().await;
+        // Whoever gets here first removes the future, unless a new one has
+        // taken its place already
+        let mut in_flight = self.in_flight.lock().expect("not poisoned");
+        if in_flight.get(&key).is_some_and(|f| f.ptr_eq(&future)) {
+            in_flight.remove(&key);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::Coalescer;
+
+    #[tokio::test]
+    async fn identical_requests_in_flight_are_sent_once() {
+        let coalescer = Coalescer::default();
+        let calls = std::sync::Arc::new(AtomicUsize::new(0));
+        let request = |key: &'static str| {
+            let calls = calls.clone();
+            coalescer.run(key, move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                key.len()
+            })
+        };
+        let (a, b, c) =
+            tokio::join!(request("SPY"), request("SPY"), request("QQQQ"));
+        assert_eq!((a, b, c), (3, 3, 4));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Once a request is done, the next one is sent again
+        request("SPY").await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
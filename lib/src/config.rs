@@ -1,29 +1,399 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap, num::NonZeroU32, path::PathBuf, time::Duration,
+};
 
-use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-use crate::types::Timespan;
+use crate::{
+    error,
+    schema::SchemaCheck,
+    types::{
+        CsvQuoting, DateRange, DuplicateCheck, HttpVersion, Market,
+        MonotonicCheck, OutputFormat, PartitionBy, SortOrder, Timespan,
+        TimestampUnit,
+    },
+};
 
+/// A config that only lists tickers. Any other field is an error, so that a
+/// whole [`Config`] that can't be read isn't taken for a list of tickers
 #[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Tickers {
     /// A list of tickers to download data for.
     pub tickers: Vec<String>,
 }
 
-#[derive(Clone)]
+impl Tickers {
+    /// Trim and uppercase every ticker, and drop the blank ones, the ones
+    /// that can't be valid symbols and the ones that are listed more than
+    /// once, keeping the first. Dropped tickers are logged. With `strict`,
+    /// duplicates are an error instead of a warning. With `keep_case`,
+    /// tickers are kept as they're written, but still compared
+    /// case-insensitively.
+    pub fn normalize(
+        self,
+        strict: bool,
+        keep_case: bool,
+    ) -> Result<Self, error::Init> {
+        let mut tickers: Vec<String> = Vec::with_capacity(self.tickers.len());
+        let mut duplicates = Vec::new();
+        let mut invalid = Vec::new();
+        let mut num_blank = 0;
+        for ticker in self.tickers {
+            let uppercase = ticker.trim().to_uppercase();
+            let ticker = if keep_case {
+                ticker.trim().to_string()
+            } else {
+                uppercase.clone()
+            };
+            if ticker.is_empty() {
+                num_blank += 1;
+            } else if !is_valid_ticker(&uppercase) {
+                invalid.push(ticker);
+            } else if tickers.iter().any(|t| t.to_uppercase() == uppercase) {
+                duplicates.push(ticker);
+            } else {
+                tickers.push(ticker);
+            }
+        }
+        if num_blank > 0 {
+            warn!(num_blank, "Skipping blank tickers in the config");
+        }
+        if !invalid.is_empty() {
+            let invalid = invalid.join(", ");
+            warn!(%invalid, "Skipping invalid tickers in the config");
+        }
+        if !duplicates.is_empty() {
+            let duplicates = duplicates.join(", ");
+            if strict {
+                return Err(error::Init::DuplicateTickers(duplicates));
+            }
+            warn!(%duplicates, "Skipping duplicate tickers in the config");
+        }
+        Ok(Self { tickers })
+    }
+}
+
+/// Whether `ticker` could be one of Polygon's symbols: letters, digits, dots
+/// and dashes, after the prefix of its market outside of stocks, e.g. `X:`
+/// for crypto
+fn is_valid_ticker(ticker: &str) -> bool {
+    let symbol = match ticker.split_once(':') {
+        Some(("X" | "C" | "O" | "I", symbol)) => symbol,
+        Some(_) => return false,
+        None => ticker,
+    };
+    !symbol.is_empty()
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-'))
+}
+
+/// Roughly how far back Polygon's history goes for each market, for markets
+/// where that is known. Requests before these dates come back empty.
+pub fn default_horizons() -> BTreeMap<Market, NaiveDate> {
+    [
+        (Market::Stocks, (2003, 9, 10)),
+        (Market::Options, (2014, 6, 2)),
+        (Market::Forex, (2009, 1, 1)),
+        (Market::Crypto, (2013, 1, 1)),
+    ]
+    .into_iter()
+    .filter_map(|(market, (year, month, day))| {
+        Some((market, NaiveDate::from_ymd_opt(year, month, day)?))
+    })
+    .collect()
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Config {
     /// A list of tickers to download data for.
     pub tickers: Vec<String>,
+    /// Download the tickers in a random order instead of the order they're
+    /// listed in
+    pub shuffle: bool,
+    /// The seed to use when shuffling, to get the same order across runs
+    pub seed: Option<u64>,
+    /// If set, only the first `max_tickers` tickers are downloaded. Applied
+    /// after shuffling
+    pub max_tickers: Option<usize>,
     /// The timespan for each candlestick.
     pub timespan: Timespan,
-    /// The folder to save the results. Results will be saved
-    /// in this structure: `$output_dir/$ticker/$year/$month/$day.csv`
+    /// How many `timespan`s each candlestick covers, e.g. 5 for 5-minute
+    /// bars. Files of bars with a multiplier other than 1 are named after
+    /// it, e.g. `$ticker/5minute`
+    pub multiplier: u32,
+    /// The folder to save the results. Results will be saved in this
+    /// structure: `$output_dir/$ticker/$timespan/$year/$month/$day.csv`,
+    /// depending on `partition_by`
     pub output_dir: PathBuf,
+    /// How each ticker's records are split up into files
+    pub partition_by: PartitionBy,
+    /// The formats to save the results in. Each ticker is saved once per
+    /// format, to its own file
+    pub formats: Vec<OutputFormat>,
+    /// Describe the columns of the files in `$output_dir/dictionary.json`,
+    /// see [`SerializeOptions::columns`](crate::serializer::SerializeOptions::columns)
+    pub data_dictionary: bool,
+    /// How to write the timestamp of each record
+    pub timestamp_unit: TimestampUnit,
+    /// Also write each record's timestamp as an RFC 3339 datetime in UTC, in
+    /// a `datetime` column after the `timestamp`
+    pub with_datetime: bool,
+    /// Also write the UTC date and time of day of each record's timestamp,
+    /// in `date` and `time` columns after the `timestamp`
+    pub with_date_and_time: bool,
+    /// Whether CSV files end with a newline after the last row. They do by
+    /// default, like every other row
+    pub csv_trailing_newline: bool,
+    /// Start CSV files with a UTF-8 byte order mark, for Excel
+    pub csv_bom: bool,
+    /// Which fields of CSV files are quoted. Defaults to
+    /// [`CsvQuoting::Necessary`], only the fields that need it
+    pub csv_quoting: CsvQuoting,
+    /// The delimiter between the fields of CSV files, a comma by default.
+    /// Has to be one of [`CSV_DELIMITERS`](crate::serializer::CSV_DELIMITERS)
+    pub csv_delimiter: char,
+    /// Write the decimals of CSV files with a comma instead of a point,
+    /// which needs a [`csv_delimiter`](Self::csv_delimiter) other than a
+    /// comma
+    pub csv_decimal_comma: bool,
+    /// Compress Parquet files with Zstandard at this level, from 1 to 22,
+    /// instead of with Snappy
+    pub compression_level: Option<u32>,
     /// The starting date to pull data from
     pub from: DateTime<Utc>,
     /// The ending date to pull data to
     pub to: DateTime<Utc>,
+    /// The earliest date Polygon has data for in each market. Requests for
+    /// earlier dates are moved up to it, see [`default_horizons`]
+    pub horizons: Option<BTreeMap<Market, NaiveDate>>,
+    /// More windows to fetch for each ticker besides `from..=to`, e.g.
+    /// around specific events. All windows are saved to the same file.
+    pub extra_ranges: Vec<DateRange>,
+    /// Swap the ends of ranges that end before they start, with a warning,
+    /// instead of failing with [`error::Init::ReversedRange`]
+    pub swap_reversed_ranges: bool,
+    /// Ignore `from` and `to` and download everything from the day each
+    /// ticker was listed up until now. Falls back to `from` for tickers
+    /// without a known listing date.
+    pub full_history: bool,
+    /// If set, the details of tickers looked up on Polygon, like their
+    /// listing dates for `full_history`, are kept in this JSON file and
+    /// reused by later runs instead of being looked up again
+    pub metadata_cache: Option<PathBuf>,
+    /// How long the details kept in `metadata_cache` are reused for before
+    /// they're looked up again
+    pub metadata_ttl: Duration,
+    /// Warn at startup about ranges without a single trading day for the
+    /// market of any of the tickers, which Polygon has no bars for
+    pub warn_on_weekend_range: bool,
+    /// The version of Polygon's aggregates API to use, e.g. `v2`
+    pub aggregates_version: String,
+    /// Where to send requests instead of Polygon's API, e.g. a caching proxy
+    pub base_url: Option<String>,
+    /// Only connect to servers with one of the certificates with these
+    /// SHA-256 fingerprints, see
+    /// [`Client::with_pinned_certificates`](crate::client::Client::with_pinned_certificates)
+    pub pinned_certificates: Vec<String>,
+    /// Only talk to the server in this version of HTTP, instead of
+    /// negotiating it
+    pub http_version: Option<HttpVersion>,
     /// How many records to fetch in one chunk
     pub limit: u32,
+    /// The order to ask Polygon for each ticker's records in
+    pub sort: SortOrder,
+    /// Ask Polygon for prices adjusted for splits, or for the raw prices
+    pub adjusted: bool,
+    /// Adjust prices for the splits up to and including this date only, as
+    /// they would have been adjusted back then. The raw prices are fetched
+    /// and adjusted locally with Polygon's splits, whatever `adjusted` is
+    pub adjusted_as_of: Option<NaiveDate>,
+    /// Only fetch the most recent `last` bars of each range, newest first,
+    /// and stop once there are that many. They're written oldest first
+    pub last: Option<usize>,
+    /// When fetching newest first, buffer each ticker's records in memory and
+    /// write them oldest first
+    pub reverse_descending: bool,
+    /// If set, the raw JSON body of every response is also saved in this
+    /// folder in this structure:
+    /// `$save_raw/$ticker/$timespan/$from-$to/$page.json`, with the range of
+    /// the request in milliseconds
+    pub save_raw: Option<PathBuf>,
+    /// If set, the progress of the run is written to this file as JSON
+    /// lines, see [`Event`](crate::events::Event). Separate from the logs,
+    /// for another process to show the progress with
+    pub progress_events: Option<PathBuf>,
+    /// Whether to draw the progress bar on stderr. `Some(true)` draws it
+    /// even when stderr isn't a terminal, e.g. when it's piped to a file,
+    /// `Some(false)` never draws it, and with `None` it's only drawn to a
+    /// terminal
+    pub progress: Option<bool>,
+    /// Retry requests that fail with a 5xx status, with exponential backoff,
+    /// instead of giving up on the ticker
+    pub retry_server_errors: bool,
+    /// How many times to retry a request that failed with a network error,
+    /// a 429, or a 5xx with `retry_server_errors`, before giving up
+    pub max_retries: u32,
+    /// Also retry requests whose error message from Polygon contains any of
+    /// these, including responses with a 200 status and an error in the
+    /// body
+    pub retry_messages: Vec<String>,
+    /// Stop the whole run the first time a request runs out of retries
+    /// because of Polygon's rate limit, instead of moving on to the next
+    /// ticker
+    pub abort_on_rate_limit: bool,
+    /// Fail the run if less than this fraction of the tickers, between 0 and
+    /// 1, were fetched successfully, even though each failed ticker only
+    /// stops itself
+    pub min_success_rate: Option<f64>,
+    /// The most requests to send a minute, across all tickers, e.g. `5` on
+    /// Polygon's free tier. Requests over the limit wait for their turn
+    pub requests_per_minute: Option<NonZeroU32>,
+    /// The most requests to send for the pages of any one ticker, so a
+    /// ticker with a deep history can't use up the whole budget. A ticker
+    /// that reaches it stops, while the others carry on
+    pub max_requests_per_ticker: Option<NonZeroU32>,
+    /// If set, the duration, records and retries of each ticker are pushed
+    /// to the Prometheus Pushgateway at this URL as it finishes. Needs the
+    /// `metrics` feature
+    pub push_metrics: Option<String>,
+    /// Send a single request for identical requests in flight at the same
+    /// time, e.g. from overlapping ranges, and share the response
+    pub coalesce_requests: bool,
+    /// How many requests to have in flight at once, and how many tickers to
+    /// fetch at the same time. Must be at least 1
+    pub concurrency: usize,
+    /// How many pages of a ticker to fetch ahead of writing them, holding
+    /// them in memory. Must be at least 1
+    pub write_buffer_size: usize,
+    /// How many of a ticker's files to write each batch of records to at the
+    /// same time, when it's saved in several formats or with daily bars
+    /// derived. With `1`, they're written one after another
+    pub write_concurrency: usize,
+    /// How many months of a single ticker to fetch concurrently. With `1`,
+    /// each ticker is fetched sequentially, one page after another
+    pub ticker_parallelism: usize,
+    /// Start with a single request in flight and gradually allow more over
+    /// this long, instead of sending the first burst of requests all at once
+    pub ramp_up: Option<Duration>,
+    /// When fetching intraday bars, also derive daily bars from them as
+    /// they're fetched and save those under `$ticker/day-from-$timespan`.
+    /// Days are New York days, starting at midnight there
+    pub derive_daily: bool,
+    /// After fetching a ticker, look for gaps in its data and re-request the
+    /// missing periods, merging whatever Polygon returns into the file
+    pub fill_gaps: bool,
+    /// The maximum number of passes to make over a ticker when filling gaps
+    pub max_fill_attempts: usize,
+    /// Log summary statistics of each ticker's data once it's downloaded:
+    /// the min/max/average close and the total volume
+    pub summary_stats: bool,
+    /// Once a ticker is downloaded, read back its records and log the gaps
+    /// between them, see [`gaps::find_gaps`](crate::gaps::find_gaps)
+    pub validate: bool,
+    /// Skip the date ranges that a previous run recorded as downloaded in
+    /// the checkpoint, and the tickers its manifest has as successfully
+    /// downloaded, see [`Manifest`](crate::manifest::Manifest). With
+    /// [`PartitionBy::Day`], each day is skipped on its own
+    pub resume: bool,
+    /// Truncate the files of each ticker before fetching it, instead of
+    /// picking up after the last record already in them
+    pub overwrite: bool,
+    /// When picking up after the last record already in a ticker's files,
+    /// fetch this much before it again and replace the records Polygon has
+    /// revised since. Files written newest first aren't picked up after at
+    /// the recent end, so this doesn't apply to them
+    pub revision_window: Option<Duration>,
+    /// The Unix mode to give the files created, e.g. `0o640`. Directories
+    /// also get the execute bit wherever they're readable. Ignored on other
+    /// platforms
+    pub permissions: Option<u32>,
+    /// Where to keep the checkpoint, e.g. on storage shared by several runs.
+    /// Defaults to `$output_dir/.checkpoint.json`
+    pub checkpoint: Option<PathBuf>,
+    /// Check that the timestamps written for each ticker strictly increase,
+    /// and warn or fail when they don't
+    pub monotonic: Option<MonotonicCheck>,
+    /// Check each page from Polygon for records with the same timestamp,
+    /// and warn, drop the duplicates or fail when there are any
+    pub page_duplicates: Option<DuplicateCheck>,
+    /// Sort each batch of records before writing it, and hold back the
+    /// records that come in after later ones to merge them into the file at
+    /// the end, so files are strictly ordered by timestamp however the
+    /// requests for a ticker finish
+    pub strict_order: bool,
+    /// Check responses for fields Polygon didn't use to return, and warn or
+    /// fail when there are any
+    pub schema_check: Option<SchemaCheck>,
+    /// Save grouped daily data the way Polygon's flat files are laid out,
+    /// `$output_dir/us_stocks_sip/day_aggs_v1/$year/$month/$date.csv`, with
+    /// the same columns as the flat files
+    pub flat_file_layout: bool,
+    /// Save grouped daily data to the files of each ticker instead, the same
+    /// files fetching the daily bars of each ticker writes to, in every one
+    /// of the `formats`. Only the `tickers` are saved, unless there are none
+    pub grouped_by_ticker: bool,
+    /// Use each ticker as is for the name of its folder, instead of replacing
+    /// characters that aren't valid in paths on every OS. Without it, folders
+    /// already named after tickers as is are still used
+    pub raw_ticker_paths: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tickers;
+
+    fn tickers(tickers: &[&str]) -> Tickers {
+        Tickers {
+            tickers: tickers.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn duplicate_tickers_are_fetched_once() {
+        let deduped =
+            tickers(&["SPY", "aapl", "spy", "AAPL"]).normalize(false, false);
+        assert_eq!(deduped.unwrap().tickers, ["SPY", "AAPL"]);
+        assert!(tickers(&["SPY", "spy"]).normalize(true, false).is_err());
+        assert!(tickers(&["SPY", "QQQ"]).normalize(true, false).is_ok());
+    }
+
+    #[test]
+    fn case_can_be_kept() {
+        let kept =
+            tickers(&[" x:btcusd ", "Spy", "SPY"]).normalize(false, true);
+        assert_eq!(kept.unwrap().tickers, ["x:btcusd", "Spy"]);
+    }
+
+    #[test]
+    fn blank_and_invalid_tickers_are_skipped() {
+        let normalized = tickers(&[
+            " spy ",
+            "",
+            "   ",
+            "x:btcusd",
+            "C:EURUSD",
+            "O:SPY241220C00500000",
+            "I:SPX",
+            "BRK.A",
+            "Z:FOO",
+            "X:",
+            "SP Y",
+        ])
+        .normalize(true, false);
+        assert_eq!(
+            normalized.unwrap().tickers,
+            [
+                "SPY",
+                "X:BTCUSD",
+                "C:EURUSD",
+                "O:SPY241220C00500000",
+                "I:SPX",
+                "BRK.A"
+            ]
+        );
+    }
 }
@@ -1,3 +1,5 @@
+use std::{sync::Arc, time::Duration};
+
 use displaydoc::Display;
 use thiserror::Error;
 
@@ -17,10 +19,72 @@ pub enum Error {
     Deserialization(reqwest::Error),
     /// Unexpected status code: {0}
     UnexpectedStatus(reqwest::Error),
+    /// Polygon returned {status_code}: {message}
+    ApiError {
+        status_code: reqwest::StatusCode,
+        message: String,
+    },
     /// Failed to deserialize response: {0}
     Serde(#[from] serde_json::Error),
+    /// Response doesn't match the expected schema: {0}
+    UnexpectedSchema(serde_json::Error),
     /// Invalid aggregate request: {0}
     InvalidRequest(#[from] AggregateRequestBuilderError),
+    /// Failed to fetch {0} sub-range(s), re-run with --resume to fetch them
+    IncompleteRanges(usize),
+    /// Timestamp {timestamp} doesn't come after the previous one, {previous}
+    NonMonotonic { previous: i64, timestamp: i64 },
+    /// Timestamp {0} is in the same page more than once
+    DuplicateTimestamp(i64),
+    /// {0}
+    Coalesced(Arc<Error>),
+    /// Rate limited by Polygon: {source}
+    RateLimited {
+        /// How long Polygon asked to wait before trying again
+        retry_after: Option<Duration>,
+        source: Box<Error>,
+    },
+    /// The {0} of the records is too large to add up
+    Overflow(&'static str),
+    /// No output format is configured
+    NoOutputFormat,
+    /// The server's certificate isn't pinned, its fingerprint is {0}
+    UnpinnedCertificate(crate::pinning::Fingerprint),
+    /// Reached the limit of {0} requests for the ticker
+    TickerRequestLimit(std::num::NonZeroU32),
+    /// Adjusting the records for the split on {0} overflows
+    SplitOverflow(chrono::NaiveDate),
+    /// The request URL is {length} characters long, over the limit of {max}
+    UrlTooLong { length: usize, max: usize },
+    /// Only {succeeded} of {total} tickers succeeded, the minimum is {min}
+    LowSuccessRate {
+        succeeded: usize,
+        total: usize,
+        min: f64,
+    },
+}
+
+impl Error {
+    /// The HTTP status Polygon responded with, if this error came from an
+    /// unsuccessful response
+    pub fn status(&self) -> Option<reqwest::StatusCode> {
+        match self {
+            Self::UnexpectedStatus(e) => e.status(),
+            Self::ApiError { status_code, .. } => Some(*status_code),
+            Self::Coalesced(e) => e.status(),
+            Self::RateLimited { source, .. } => source.status(),
+            _ => None,
+        }
+    }
+
+    /// How long Polygon asked to wait before retrying, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } => *retry_after,
+            Self::Coalesced(e) => e.retry_after(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Display, Error)]
@@ -31,14 +95,62 @@ pub enum Init {
     InvalidApiKey(String),
     /// Invalid base URL: {0}
     InvalidBaseUrl(String),
+    /// Invalid URL to send records to: {0}
+    InvalidSinkUrl(String),
+    /// Invalid Pushgateway URL to push metrics to: {0}
+    InvalidMetricsUrl(String),
+    /// Pushing metrics needs the `metrics` feature, which isn't enabled
+    MetricsDisabled,
+    /// The compression level must be between 1 and 22, not {0}
+    InvalidCompressionLevel(u32),
+    /// Invalid header {0}
+    InvalidHeader(String),
+    /// Invalid API version {0}, expected something like `v2`
+    InvalidApiVersion(String),
+    /// Duplicate tickers in the config: {0}
+    DuplicateTickers(String),
+    /// The concurrency limit must be at least 1
+    ZeroConcurrency,
+    /// The write buffer size must be at least 1
+    ZeroWriteBufferSize,
+    /// Invalid certificate fingerprint {0}, expected a SHA-256 hash in hex
+    InvalidCertificatePin(String),
+    /// Failed to set up TLS: {0}
+    TlsConfig(String),
+    /// The minimum success rate must be between 0 and 1, not {0}
+    InvalidSuccessRate(f64),
+    /// {0:?} can't be a CSV delimiter, only a comma, `;`, `|` or a tab
+    InvalidCsvDelimiter(char),
+    /// A decimal comma needs a CSV delimiter other than a comma
+    AmbiguousDecimalComma,
+    /// {0} isn't a span like `minute` or `5minute`
+    InvalidSpan(String),
+    /// The range from {0} to {1} ends before it starts
+    ReversedRange(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
 }
 
 #[derive(Debug, Display, Error)]
 pub enum FileIo {
     /// Error writing CSV: {0}
     Csv(#[from] csv::Error),
+    /// Error reading CSV: {0}
+    CsvRead(csv::Error),
     /// Error writing file: {0}
     FileWrite(std::io::Error),
+    /// Error reading file: {0}
+    ReadFile(std::io::Error),
+    /// Invalid JSON: {0}
+    Json(#[from] serde_json::Error),
+    /// Can't read back records from a `.{0}` file
+    UnreadableFormat(String),
+    /// Invalid timestamp in file: {0}
+    InvalidTimestamp(String),
     /// Error creating file: {0}
     CreateFile(std::io::Error),
+    /// Parquet error: {0}
+    Parquet(#[from] parquet::errors::ParquetError),
+    /// Decimal out of range for Parquet: {0}
+    DecimalOutOfRange(String),
+    /// The file has no `{0}` column
+    UnknownColumn(String),
 }
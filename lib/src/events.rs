@@ -0,0 +1,145 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::NaiveDate;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::error;
+
+/// Something that happened during a run, for another process to follow its
+/// progress by. Written as a line of JSON, with the kind of event under
+/// `event`
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event<'a> {
+    /// The run started, with `total` chunks to fetch
+    Started { total: u64 },
+    /// A chunk of a ticker, or a day of grouped daily bars, was fetched.
+    /// `total` can grow during the run when things take more chunks than
+    /// estimated
+    Progress {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ticker: Option<&'a str>,
+        done: u64,
+        total: u64,
+    },
+    /// A ticker or a day failed, and the run moved on
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ticker: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        date: Option<NaiveDate>,
+        message: String,
+    },
+    /// The run is over. `aborted` if it stopped early because of Polygon's
+    /// rate limit
+    Finished {
+        done: u64,
+        total: u64,
+        aborted: bool,
+    },
+}
+
+/// Writes [`Event`]s as JSON lines, e.g. to a pipe a GUI reads from
+pub struct EventWriter {
+    out: Mutex<Box<dyn Write + Send>>,
+    /// Set once writing fails, so it's only logged once
+    failed: AtomicBool,
+}
+
+impl EventWriter {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Write events to the file at `path`, which can be an inherited file
+    /// descriptor like `/dev/fd/3` on Unix
+    pub fn create(path: &Path) -> Result<Self, error::FileIo> {
+        let file = File::create(path).map_err(error::FileIo::CreateFile)?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+
+    /// Write `event` and flush it, so it's seen straight away. Failures are
+    /// logged rather than stopping the run
+    pub fn emit(&self, event: &Event) {
+        let mut out = self.out.lock().expect("not poisoned");
+        let result = serde_json::to_writer(&mut *out, event)
+            .map_err(std::io::Error::from)
+            .and_then(|()| out.write_all(b"\n"))
+            .and_then(|()| out.flush());
+        if let Err(e) = result {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                warn!(error = %e, "Failed to write a progress event");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{Event, EventWriter};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn events_are_written_as_json_lines() {
+        let out = Shared::default();
+        let events = EventWriter::new(out.clone());
+        events.emit(&Event::Started { total: 2 });
+        events.emit(&Event::Progress {
+            ticker: Some("SPY"),
+            done: 1,
+            total: 2,
+        });
+        events.emit(&Event::Error {
+            ticker: Some("SPY"),
+            date: None,
+            message: "Polygon returned 404".into(),
+        });
+        events.emit(&Event::Finished {
+            done: 2,
+            total: 2,
+            aborted: false,
+        });
+        assert_eq!(
+            String::from_utf8(out.0.lock().unwrap().clone()).unwrap(),
+            concat!(
+                r#"{"event":"started","total":2}"#,
+                "\n",
+                r#"{"event":"progress","ticker":"SPY","done":1,"total":2}"#,
+                "\n",
+                r#"{"event":"error","ticker":"SPY","message":"Polygon returned 404"}"#,
+                "\n",
+                r#"{"event":"finished","done":2,"total":2,"aborted":false}"#,
+                "\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use chrono::{DateTime, Datelike, NaiveDate, Utc, Weekday};
+
+use crate::{
+    calendar,
+    types::{Market, Timespan},
+};
+
+/// A stretch of time missing from a series of records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    /// The timestamp of the last record before the gap
+    pub start: DateTime<Utc>,
+    /// The timestamp of the first record after the gap
+    pub end: DateTime<Utc>,
+    /// How many `timespan` intervals are missing between `start` and `end`
+    pub missing: i64,
+}
+
+/// Find the gaps in `timestamps`, the sorted Unix Msec timestamps of a
+/// series of aggregate bars of size `timespan`.
+///
+/// Markets aren't open around the clock, so this is only a heuristic:
+/// - intraday bars are only compared to bars from the same day, so
+///   overnight gaps are ignored
+/// - daily bars ignore weekends, but holidays will show up as gaps
+pub fn find_gaps(timespan: Timespan, timestamps: &[i64]) -> Vec<Gap> {
+    find_gaps_with_multiplier(timespan, 1, timestamps)
+}
+
+/// Same as [`find_gaps`], for bars that each cover `multiplier` of
+/// `timespan`, e.g. 5-minute bars
+pub fn find_gaps_with_multiplier(
+    timespan: Timespan,
+    multiplier: u32,
+    timestamps: &[i64],
+) -> Vec<Gap> {
+    let multiplier = i64::from(multiplier.max(1));
+    timestamps
+        .windows(2)
+        .filter_map(|pair| {
+            let start = DateTime::from_timestamp_millis(pair[0])?;
+            let end = DateTime::from_timestamp_millis(pair[1])?;
+            let steps = missing_intervals(timespan, start, end) + 1;
+            let missing = steps / multiplier - 1;
+            (missing > 0).then_some(Gap {
+                start,
+                end,
+                missing,
+            })
+        })
+        .collect()
+}
+
+/// The weekdays strictly between the days of the bars either side of `gap`,
+/// which are the days missing from a gap in daily bars
+pub fn missing_weekdays(gap: &Gap) -> Vec<NaiveDate> {
+    gap.start
+        .date_naive()
+        .iter_days()
+        .skip(1)
+        .take_while(|day| *day < gap.end.date_naive())
+        .filter(|day| !matches!(day.weekday(), Weekday::Sat | Weekday::Sun))
+        .collect()
+}
+
+/// The days strictly between the days of the bars either side of `gap` that
+/// `market` is open on, see [`calendar::is_trading_day`]
+pub fn missing_trading_days(market: Market, gap: &Gap) -> Vec<NaiveDate> {
+    missing_weekdays(gap)
+        .into_iter()
+        .filter(|day| calendar::is_trading_day(market, *day))
+        .collect()
+}
+
+/// How many bars of size `timespan` are expected strictly between the bars
+/// starting at `start` and `end`
+fn missing_intervals(
+    timespan: Timespan,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> i64 {
+    let duration = end - start;
+    let intraday = |interval: i64, elapsed: i64| {
+        if start.date_naive() == end.date_naive() {
+            elapsed / interval - 1
+        } else {
+            0
+        }
+    };
+    let months = |start: NaiveDate, end: NaiveDate| {
+        i64::from(end.year() - start.year()) * 12 + i64::from(end.month())
+            - i64::from(start.month())
+    };
+    match timespan {
+        Timespan::Second => intraday(1, duration.num_seconds()),
+        Timespan::Minute => intraday(1, duration.num_minutes()),
+        Timespan::Hour => intraday(1, duration.num_hours()),
+        Timespan::Day => start
+            .date_naive()
+            .iter_days()
+            .skip(1)
+            .take_while(|day| *day < end.date_naive())
+            .filter(|day| !matches!(day.weekday(), Weekday::Sat | Weekday::Sun))
+            .count() as i64,
+        Timespan::Week => duration.num_days() / 7 - 1,
+        Timespan::Month => months(start.date_naive(), end.date_naive()) - 1,
+        Timespan::Quarter => {
+            months(start.date_naive(), end.date_naive()) / 3 - 1
+        }
+        Timespan::Year => i64::from(end.year() - start.year()) - 1,
+    }
+    .max(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use chrono::NaiveDate;
+
+    use super::{
+        find_gaps, find_gaps_with_multiplier, missing_trading_days,
+        missing_weekdays,
+    };
+    use crate::types::{Market, Timespan};
+
+    fn millis(y: i32, m: u32, d: u32, h: u32, min: u32) -> i64 {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0)
+            .unwrap()
+            .timestamp_millis()
+    }
+
+    #[test]
+    fn daily_gaps_ignore_weekends() {
+        // Friday, Monday, then Thursday, so Tuesday and Wednesday are missing
+        let timestamps = [
+            millis(2024, 1, 5, 5, 0),
+            millis(2024, 1, 8, 5, 0),
+            millis(2024, 1, 11, 5, 0),
+        ];
+        let gaps = find_gaps(Timespan::Day, &timestamps);
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].start.timestamp_millis(), timestamps[1]);
+        assert_eq!(gaps[0].end.timestamp_millis(), timestamps[2]);
+        assert_eq!(gaps[0].missing, 2);
+        let day = |d| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+        assert_eq!(missing_weekdays(&gaps[0]), [day(9), day(10)]);
+
+        // Martin Luther King Jr. Day isn't a trading day
+        let timestamps = [millis(2024, 1, 12, 5, 0), millis(2024, 1, 17, 5, 0)];
+        let gaps = find_gaps(Timespan::Day, &timestamps);
+        assert_eq!(gaps[0].missing, 2);
+        assert_eq!(missing_trading_days(Market::Stocks, &gaps[0]), [day(16)]);
+    }
+
+    #[test]
+    fn minute_gaps_ignore_overnight() {
+        let timestamps = [
+            millis(2024, 1, 8, 20, 58),
+            millis(2024, 1, 8, 20, 59),
+            millis(2024, 1, 9, 9, 0),
+            millis(2024, 1, 9, 9, 4),
+        ];
+        let gaps = find_gaps(Timespan::Minute, &timestamps);
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].missing, 3);
+    }
+
+    #[test]
+    fn gaps_are_counted_in_bars_of_the_multiplier() {
+        let timestamps = [
+            millis(2024, 1, 8, 9, 30),
+            millis(2024, 1, 8, 9, 35),
+            millis(2024, 1, 8, 9, 50),
+        ];
+        let gaps = find_gaps_with_multiplier(Timespan::Minute, 5, &timestamps);
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].missing, 2);
+    }
+}
@@ -0,0 +1,273 @@
+use std::num::NonZeroUsize;
+
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use tokio::time::sleep;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{
+    error::{self, Error},
+    retry::RetryPolicy,
+    serializer::{JsonArraySerializer, RecordSerializer, SerializeOptions},
+    types::AggregateRecord,
+};
+
+/// How many records to send in one request unless configured otherwise
+pub const DEFAULT_BATCH_SIZE: usize = 1_000;
+
+/// POSTs records to an HTTP endpoint instead of writing them to files, see
+/// [`Service::fetch_to_http`](crate::service::Service::fetch_to_http).
+///
+/// Each request has a batch of records of a single ticker as a JSON array,
+/// like the `.json` files, with the ticker in the `ticker` query parameter
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: Url,
+    batch_size: NonZeroUsize,
+    retry_policy: RetryPolicy,
+}
+
+impl HttpSink {
+    /// Send records to `url`, which has to be an HTTP(S) URL
+    pub fn new(url: &str) -> Result<Self, error::Init> {
+        Self::build(url, HeaderMap::new())
+    }
+
+    /// Send records to `url` with `headers` on every request, e.g. an
+    /// `Authorization` header. Their values are kept out of the logs
+    pub fn with_headers<'h>(
+        url: &str,
+        headers: impl IntoIterator<Item = (&'h str, &'h str)>,
+    ) -> Result<Self, error::Init> {
+        let headers = headers
+            .into_iter()
+            .map(|(name, value)| {
+                let invalid = || error::Init::InvalidHeader(name.to_string());
+                let name = HeaderName::try_from(name).map_err(|_| invalid())?;
+                let mut value =
+                    HeaderValue::try_from(value).map_err(|_| invalid())?;
+                value.set_sensitive(true);
+                Ok((name, value))
+            })
+            .collect::<Result<_, error::Init>>()?;
+        Self::build(url, headers)
+    }
+
+    fn build(url: &str, mut headers: HeaderMap) -> Result<Self, error::Init> {
+        let invalid = || error::Init::InvalidSinkUrl(url.to_string());
+        let url = Url::parse(url).map_err(|_| invalid())?;
+        if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+            return Err(invalid());
+        }
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .map_err(error::Init::ClientInitialization)?;
+        Ok(Self {
+            client,
+            url,
+            batch_size: NonZeroUsize::new(DEFAULT_BATCH_SIZE)
+                .expect("not zero"),
+            retry_policy: RetryPolicy {
+                retry_server_errors: true,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Send at most `batch_size` records in one request, instead of
+    /// [`DEFAULT_BATCH_SIZE`]
+    pub fn with_batch_size(mut self, batch_size: NonZeroUsize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Retry failed requests according to `retry_policy`. By default
+    /// requests that fail to send or with a 429 or 5xx status are retried
+    /// three times
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.get()
+    }
+
+    /// POST `records` of `ticker`, in batches of at most
+    /// [`batch_size`](Self::batch_size), written with `options`. Nothing is
+    /// sent if there are no records
+    pub async fn send(
+        &self,
+        ticker: &str,
+        records: &[AggregateRecord],
+        options: &SerializeOptions,
+    ) -> Result<(), Error> {
+        let serializer = JsonArraySerializer {
+            options: options.clone(),
+        };
+        let mut url = self.url.clone();
+        url.query_pairs_mut().append_pair("ticker", ticker);
+        for batch in records.chunks(self.batch_size()) {
+            let mut body = Vec::new();
+            serializer.begin(&batch[0], &mut body)?;
+            serializer.serialize_all(batch, &mut body)?;
+            body.extend_from_slice(serializer.footer());
+            self.post(&url, body).await?;
+            debug!(num_records = batch.len(), "Sent batch of records");
+        }
+        Ok(())
+    }
+
+    async fn post(&self, url: &Url, body: Vec<u8>) -> Result<(), Error> {
+        let mut attempt = 0;
+        loop {
+            let response = self
+                .client
+                .post(url.clone())
+                .body(body.clone())
+                .send()
+                .await
+                .map_err(Error::SendRequest)
+                .and_then(|response| {
+                    response.error_for_status().map_err(Error::UnexpectedStatus)
+                });
+            match response {
+                Err(e)
+                    if attempt < self.retry_policy.max_retries
+                        && self.retry_policy.is_retryable(&e) =>
+                {
+                    let delay = self.retry_policy.backoff(attempt);
+                    warn!(error = %e, attempt, ?delay, "Retrying sending records");
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result.map(|_| ()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use rust_decimal::Decimal;
+    use wiremock::{
+        matchers::{header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::HttpSink;
+    use crate::{
+        error::Error,
+        retry::RetryPolicy,
+        serializer::SerializeOptions,
+        types::{AggregateRecord, CsvQuoting, TimestampUnit},
+    };
+
+    fn options() -> SerializeOptions {
+        SerializeOptions {
+            timestamp_unit: TimestampUnit::Millis,
+            trailing_newline: true,
+            with_datetime: false,
+            with_date_and_time: false,
+            bom: false,
+            quoting: CsvQuoting::Necessary,
+            delimiter: b',',
+            decimal_comma: false,
+            compression_level: None,
+        }
+    }
+
+    fn record(timestamp: i64) -> AggregateRecord {
+        AggregateRecord {
+            timestamp,
+            open: Decimal::ONE,
+            high: Decimal::ONE,
+            low: Decimal::ONE,
+            close: Decimal::ONE,
+            volume: Decimal::TEN,
+            transactions: None,
+            otc: None,
+            vwap: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        let sink = HttpSink::with_headers(
+            &server.uri(),
+            [("Authorization", "Bearer token")],
+        )
+        .unwrap()
+        .with_retry_policy(RetryPolicy {
+            max_retries: 1,
+            base_delay: Duration::from_millis(1),
+            retry_server_errors: true,
+            ..Default::default()
+        });
+        sink.send("SPY", &[record(1)], &options()).await.unwrap();
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+
+        // Client errors aren't retried
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&server)
+            .await;
+        let sink = HttpSink::new(&server.uri()).unwrap();
+        let result = sink.send("SPY", &[record(1)], &options()).await;
+        assert!(matches!(result, Err(Error::UnexpectedStatus(_))));
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn records_are_sent_in_batches() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/ingest"))
+            .and(query_param("ticker", "SPY"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        let sink = HttpSink::new(&format!("{}/ingest", server.uri()))
+            .unwrap()
+            .with_batch_size(NonZeroUsize::new(2).unwrap());
+        let records: Vec<_> = (1..=3).map(record).collect();
+        sink.send("SPY", &records, &options()).await.unwrap();
+        let requests = server.received_requests().await.unwrap();
+        let bodies: Vec<serde_json::Value> = requests
+            .iter()
+            .map(|request| serde_json::from_slice(&request.body).unwrap())
+            .collect();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0].as_array().unwrap().len(), 2);
+        assert_eq!(
+            bodies[1],
+            serde_json::json!([{
+                "timestamp": 3, "open": "1", "high": "1", "low": "1",
+                "close": "1", "volume": "10"
+            }])
+        );
+    }
+}
@@ -1,5 +1,27 @@
+pub mod adjust;
+pub mod calendar;
+pub mod checkpoint;
 pub mod client;
+pub mod coalesce;
 pub mod config;
 pub mod error;
+pub mod events;
+pub mod gaps;
+pub mod http_sink;
+pub mod manifest;
+pub mod metadata_cache;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod pinning;
+pub mod probe;
+pub mod rate_limit;
+pub mod reader;
+pub mod resample;
+pub mod retry;
+pub mod schema;
+pub mod serializer;
 pub mod service;
+pub mod stats;
+#[cfg(test)]
+mod test_util;
 pub mod types;
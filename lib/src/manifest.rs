@@ -0,0 +1,183 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    calendar, error,
+    types::{Market, Timespan, WorkItem},
+};
+
+/// A record of how each ticker of a run went, saved as each ticker finishes
+/// so an interrupted run can be audited and picked up again. Each span has
+/// a manifest of its own
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    /// When the run started
+    pub started: DateTime<Utc>,
+    /// When the run finished, `None` if it's still going or was interrupted
+    pub finished: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub tickers: BTreeMap<String, TickerRun>,
+}
+
+/// What was asked for a ticker and how fetching it went
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TickerRun {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub timespan: Timespan,
+    pub multiplier: u32,
+    pub status: Status,
+    /// The records fetched and saved, not counting the ranges that failed
+    pub records: usize,
+    /// The last error fetching the ticker, if it failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The days asked for that the ticker's market is closed on, so no data
+    /// is expected for them, see [`calendar::is_trading_day`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub non_trading_days: Vec<NaiveDate>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Success,
+    Failed,
+    /// Not fetched, or not all of it, because the run was aborted first
+    Skipped,
+}
+
+impl TickerRun {
+    /// A successful run of `items`, all of the same ticker, until something
+    /// goes wrong
+    pub fn new(items: &[WorkItem], multiplier: u32) -> Self {
+        let from = items.iter().map(|item| item.from).min();
+        let to = items.iter().map(|item| item.to).max();
+        let mut non_trading_days: Vec<_> = items
+            .iter()
+            .flat_map(|item| {
+                calendar::non_trading_days(
+                    Market::of(&item.ticker),
+                    item.from.date_naive(),
+                    item.to.date_naive(),
+                )
+            })
+            .collect();
+        non_trading_days.sort_unstable();
+        non_trading_days.dedup();
+        Self {
+            from: from.unwrap_or_default(),
+            to: to.unwrap_or_default(),
+            timespan: items
+                .first()
+                .map(|item| item.timespan)
+                .unwrap_or_default(),
+            multiplier,
+            status: Status::Success,
+            records: 0,
+            error: None,
+            non_trading_days,
+        }
+    }
+
+    /// Mark the run as failed because of `e`
+    pub fn fail(&mut self, e: &impl ToString) {
+        self.status = Status::Failed;
+        self.error = Some(e.to_string());
+    }
+
+    /// Whether this run succeeded and covered everything `other` asks for
+    pub fn covers(&self, other: &Self) -> bool {
+        self.status == Status::Success
+            && self.timespan == other.timespan
+            && self.multiplier == other.multiplier
+            && self.from <= other.from
+            && other.to <= self.to
+    }
+}
+
+impl Manifest {
+    /// Load the manifest at `path`, or an empty one if there isn't one yet
+    pub fn load(path: &Path) -> Result<Self, error::FileIo> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            Err(e) => Err(error::FileIo::ReadFile(e)),
+        }
+    }
+
+    /// Save the manifest to `path`, through a temporary file like the
+    /// [`Checkpoint`](crate::checkpoint::Checkpoint)
+    pub async fn save(&self, path: &Path) -> Result<(), error::FileIo> {
+        let tmp_path = path.with_extension("json.tmp");
+        let contents = serde_json::to_string_pretty(self)?;
+        tokio::fs::write(&tmp_path, contents)
+            .await
+            .map_err(error::FileIo::FileWrite)?;
+        tokio::fs::rename(&tmp_path, path)
+            .await
+            .map_err(error::FileIo::FileWrite)
+    }
+
+    /// Whether a previous run already fetched everything `run` asks for of
+    /// `ticker`
+    pub fn is_done(&self, ticker: &str, run: &TickerRun) -> bool {
+        self.tickers
+            .get(ticker)
+            .is_some_and(|previous| previous.covers(run))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone, Utc};
+
+    use super::{Manifest, Status, TickerRun};
+    use crate::types::{Timespan, WorkItem};
+
+    #[test]
+    fn only_successful_runs_of_the_whole_range_are_done() {
+        let item = |month| WorkItem {
+            ticker: "SPY".into(),
+            timespan: Timespan::Day,
+            from: Utc.with_ymd_and_hms(2024, month, 1, 0, 0, 0).unwrap(),
+            to: Utc.with_ymd_and_hms(2024, month, 28, 0, 0, 0).unwrap(),
+        };
+        let run = TickerRun::new(&[item(1), item(3)], 1);
+        assert_eq!(run.from, item(1).from);
+        assert_eq!(run.to, item(3).to);
+
+        let mut manifest = Manifest::default();
+        manifest.tickers.insert("SPY".into(), run.clone());
+        assert!(manifest.is_done("SPY", &TickerRun::new(&[item(2)], 1)));
+        assert!(!manifest.is_done("SPY", &TickerRun::new(&[item(4)], 1)));
+        assert!(!manifest.is_done("SPY", &TickerRun::new(&[item(2)], 5)));
+        assert!(!manifest.is_done("QQQ", &run));
+
+        let mut failed = run.clone();
+        failed.fail(&"Polygon returned 500");
+        assert_eq!(failed.status, Status::Failed);
+        manifest.tickers.insert("SPY".into(), failed);
+        assert!(!manifest.is_done("SPY", &run));
+    }
+
+    #[test]
+    fn runs_list_the_days_the_market_is_closed() {
+        let item = |ticker: &str| WorkItem {
+            ticker: ticker.into(),
+            timespan: Timespan::Day,
+            from: Utc.with_ymd_and_hms(2024, 1, 12, 0, 0, 0).unwrap(),
+            to: Utc.with_ymd_and_hms(2024, 1, 16, 0, 0, 0).unwrap(),
+        };
+        let day = |d| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+        // Martin Luther King Jr. Day and the weekend before it
+        let run = TickerRun::new(&[item("SPY")], 1);
+        assert_eq!(run.non_trading_days, [day(13), day(14), day(15)]);
+        let run = TickerRun::new(&[item("X:BTCUSD")], 1);
+        assert!(run.non_trading_days.is_empty());
+    }
+}
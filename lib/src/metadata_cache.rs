@@ -0,0 +1,111 @@
+use std::{collections::BTreeMap, fs, path::Path, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{error, types::TickerDetails};
+
+/// The details of tickers looked up in previous runs, so they aren't looked
+/// up again until they're older than the TTL
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MetadataCache {
+    #[serde(default)]
+    pub tickers: BTreeMap<String, CachedDetails>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CachedDetails {
+    /// When the details were fetched from Polygon
+    pub fetched: DateTime<Utc>,
+    pub details: TickerDetails,
+}
+
+impl MetadataCache {
+    /// Load the cache at `path`, or an empty one if there isn't one yet
+    pub fn load(path: &Path) -> Result<Self, error::FileIo> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            Err(e) => Err(error::FileIo::ReadFile(e)),
+        }
+    }
+
+    /// Save the cache to `path`, through a temporary file like the
+    /// [`Checkpoint`](crate::checkpoint::Checkpoint)
+    pub fn save(&self, path: &Path) -> Result<(), error::FileIo> {
+        let tmp_path = path.with_extension("json.tmp");
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(&tmp_path, contents).map_err(error::FileIo::FileWrite)?;
+        fs::rename(&tmp_path, path).map_err(error::FileIo::FileWrite)
+    }
+
+    /// The details of `ticker`, if they were fetched less than `ttl` before
+    /// `now`
+    pub fn get(
+        &self,
+        ticker: &str,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> Option<&TickerDetails> {
+        let ttl = TimeDelta::from_std(ttl).unwrap_or(TimeDelta::MAX);
+        self.tickers
+            .get(ticker)
+            .filter(|cached| now.signed_duration_since(cached.fetched) < ttl)
+            .map(|cached| &cached.details)
+    }
+
+    /// Record the `details` of `ticker`, fetched at `now`
+    pub fn insert(
+        &mut self,
+        ticker: &str,
+        details: TickerDetails,
+        now: DateTime<Utc>,
+    ) {
+        let cached = CachedDetails {
+            fetched: now,
+            details,
+        };
+        self.tickers.insert(ticker.to_string(), cached);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{NaiveDate, TimeDelta, TimeZone, Utc};
+
+    use super::MetadataCache;
+    use crate::types::TickerDetails;
+
+    #[test]
+    fn details_expire_after_the_ttl() {
+        let details = TickerDetails {
+            ticker: "SPY".into(),
+            name: "SPDR S&P 500 ETF Trust".into(),
+            market: "stocks".into(),
+            kind: Some("ETF".into()),
+            list_date: NaiveDate::from_ymd_opt(1993, 1, 29),
+        };
+        let fetched = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let mut cache = MetadataCache::default();
+        cache.insert("SPY", details, fetched);
+
+        let an_hour_later = fetched + TimeDelta::hours(1);
+        let cached = cache.get("SPY", day, an_hour_later).unwrap();
+        assert_eq!(cached.list_date, NaiveDate::from_ymd_opt(1993, 1, 29));
+        assert!(cache.get("QQQ", day, an_hour_later).is_none());
+        assert!(cache
+            .get("SPY", day, fetched + TimeDelta::days(1))
+            .is_none());
+
+        // The serialized type is read back under the same name
+        let json = serde_json::to_string(&cache).unwrap();
+        assert!(json.contains(r#""type":"ETF""#));
+        let cache: MetadataCache = serde_json::from_str(&json).unwrap();
+        assert!(cache.get("SPY", day, an_hour_later).is_some());
+    }
+}
@@ -0,0 +1,172 @@
+use std::{cell::Cell, fmt::Write, future::Future, time::Duration};
+
+use tracing::debug;
+use url::Url;
+
+use crate::error::{self, Error};
+
+tokio::task_local! {
+    /// The retries of the ticker being fetched, see [`count_retries`]
+    static RETRIES: Cell<u32>;
+}
+
+/// How fetching a single ticker went, as pushed by [`MetricsPusher`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TickerMetrics {
+    pub ticker: String,
+    /// How long fetching and saving the ticker took
+    pub duration: Duration,
+    /// The records fetched and saved
+    pub records: usize,
+    /// How many requests for the ticker were retried
+    pub retries: u32,
+    pub failed: bool,
+}
+
+impl TickerMetrics {
+    /// The metrics in Prometheus' text format
+    pub fn to_text(&self) -> String {
+        let metrics = [
+            (
+                "polygon_data_fetch_duration_seconds",
+                "How long fetching the ticker took",
+                self.duration.as_secs_f64(),
+            ),
+            (
+                "polygon_data_records",
+                "The records fetched and saved",
+                self.records as f64,
+            ),
+            (
+                "polygon_data_retries",
+                "The requests that were retried",
+                f64::from(self.retries),
+            ),
+            (
+                "polygon_data_failed",
+                "Whether fetching the ticker failed",
+                f64::from(u8::from(self.failed)),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, help, value) in metrics {
+            let _ = writeln!(text, "# HELP {name} {help}");
+            let _ = writeln!(text, "# TYPE {name} gauge");
+            let _ = writeln!(text, "{name} {value}");
+        }
+        text
+    }
+}
+
+/// Pushes the [`TickerMetrics`] of each ticker to a Prometheus Pushgateway,
+/// grouped by the `polygon-data` job and the ticker
+pub struct MetricsPusher {
+    client: reqwest::Client,
+    url: Url,
+}
+
+impl MetricsPusher {
+    /// Push to the Pushgateway at `url`, e.g. `http://localhost:9091`
+    pub fn new(url: &str) -> Result<Self, error::Init> {
+        let invalid = || error::Init::InvalidMetricsUrl(url.to_string());
+        let url = Url::parse(url).map_err(|_| invalid())?;
+        if !matches!(url.scheme(), "http" | "https") || url.cannot_be_a_base() {
+            return Err(invalid());
+        }
+        let client = reqwest::Client::builder()
+            .build()
+            .map_err(error::Init::ClientInitialization)?;
+        Ok(Self { client, url })
+    }
+
+    /// The URL of the group the metrics of `ticker` are pushed to
+    fn group_url(&self, ticker: &str) -> Url {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .expect("checked to be a base")
+            .pop_if_empty()
+            .extend(["metrics", "job", "polygon-data", "ticker", ticker]);
+        url
+    }
+
+    /// Replace the metrics of the ticker with `metrics`
+    pub async fn push(&self, metrics: &TickerMetrics) -> Result<(), Error> {
+        self.client
+            .put(self.group_url(&metrics.ticker))
+            .body(metrics.to_text())
+            .send()
+            .await
+            .map_err(Error::SendRequest)?
+            .error_for_status()
+            .map_err(Error::UnexpectedStatus)?;
+        debug!(ticker = %metrics.ticker, "Pushed metrics");
+        Ok(())
+    }
+}
+
+/// Run `fetch`, counting the retries [`retried`] is told about while it
+/// runs
+pub(crate) async fn count_retries<F: Future>(fetch: F) -> (F::Output, u32) {
+    RETRIES
+        .scope(Cell::new(0), async {
+            let output = fetch.await;
+            (output, RETRIES.with(Cell::get))
+        })
+        .await
+}
+
+/// Count a retry for the ticker being fetched, if any
+pub(crate) fn retried() {
+    let _ = RETRIES.try_with(|retries| retries.set(retries.get() + 1));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{count_retries, retried, MetricsPusher, TickerMetrics};
+
+    #[tokio::test]
+    async fn metrics_are_pushed_to_the_tickers_group() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/metrics/job/polygon-data/ticker/X:BTCUSD"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let pusher = MetricsPusher::new(&format!("{}/", server.uri())).unwrap();
+        let metrics = TickerMetrics {
+            ticker: "X:BTCUSD".into(),
+            duration: Duration::from_millis(1500),
+            records: 10,
+            retries: 2,
+            failed: false,
+        };
+        pusher.push(&metrics).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let body = String::from_utf8(requests[0].body.clone()).unwrap();
+        assert!(body.contains("\npolygon_data_fetch_duration_seconds 1.5\n"));
+        assert!(body.contains("\npolygon_data_retries 2\n"));
+        assert!(body.ends_with("\npolygon_data_failed 0\n"));
+        assert!(MetricsPusher::new("udp://localhost:8125").is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_are_counted_for_each_fetch() {
+        retried();
+        let ((), first) = count_retries(async {
+            retried();
+            retried();
+        })
+        .await;
+        let ((), second) = count_retries(async {}).await;
+        assert_eq!((first, second), (2, 0));
+    }
+}
@@ -0,0 +1,245 @@
+use std::{error::Error as StdError, fmt, str::FromStr, sync::Arc};
+
+use displaydoc::Display;
+use rustls::{
+    client::{
+        danger::{
+            HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+        },
+        WebPkiServerVerifier,
+    },
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, OtherError,
+    RootCertStore, SignatureScheme,
+};
+use thiserror::Error;
+
+use crate::error;
+
+/// The SHA-256 fingerprint of a certificate, in hex like `openssl x509
+/// -fingerprint -sha256` shows it, with or without the colons
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    /// The fingerprint of the DER encoded `certificate`
+    pub fn of(certificate: &[u8]) -> Self {
+        let digest =
+            ::ring::digest::digest(&::ring::digest::SHA256, certificate);
+        let mut fingerprint = [0; 32];
+        fingerprint.copy_from_slice(digest.as_ref());
+        Self(fingerprint)
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = error::Init;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || error::Init::InvalidCertificatePin(s.to_string());
+        let digits: Vec<_> = s.bytes().filter(|b| *b != b':').collect();
+        if digits.len() != 64 {
+            return Err(invalid());
+        }
+        let mut fingerprint = [0; 32];
+        for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(fingerprint))
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{byte:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// No certificate the server sent is pinned, the server's own is {0}
+#[derive(Debug, Display, Error)]
+struct Unpinned(Fingerprint);
+
+/// Checks certificates against Mozilla's root certificates as usual, and
+/// that one of the certificates the server sent, its own or one of the
+/// intermediates, is pinned
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<Fingerprint>,
+}
+
+impl PinnedVerifier {
+    fn new(
+        pins: Vec<Fingerprint>,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Self, error::Init> {
+        let roots = Arc::new(RootCertStore::from_iter(
+            webpki_roots::TLS_SERVER_ROOTS.iter().cloned(),
+        ));
+        let inner =
+            WebPkiServerVerifier::builder_with_provider(roots, provider)
+                .build()
+                .map_err(|e| error::Init::TlsConfig(e.to_string()))?;
+        Ok(Self { inner, pins })
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let pinned = std::iter::once(end_entity).chain(intermediates).any(
+            |certificate| self.pins.contains(&Fingerprint::of(certificate)),
+        );
+        if !pinned {
+            let unpinned = Unpinned(Fingerprint::of(end_entity));
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::Other(OtherError(Arc::new(unpinned))),
+            ));
+        }
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// A TLS config that only completes handshakes with servers that send one
+/// of the certificates in `pins`
+pub(crate) fn tls_config(
+    pins: Vec<Fingerprint>,
+) -> Result<ClientConfig, error::Init> {
+    let provider = Arc::new(ring::default_provider());
+    let verifier = PinnedVerifier::new(pins, provider.clone())?;
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| error::Init::TlsConfig(e.to_string()))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// The fingerprint of the server's certificate, if `error` is down to none
+/// of the certificates it sent being pinned
+pub(crate) fn unpinned(
+    error: &(dyn StdError + 'static),
+) -> Option<Fingerprint> {
+    let mut next = Some(error);
+    while let Some(mut error) = next {
+        // The source of an IO error is the source of the error it wraps,
+        // which would skip over the TLS error
+        while let Some(inner) = error
+            .downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::get_ref)
+        {
+            error = inner;
+        }
+        if let Some(rustls::Error::InvalidCertificate(
+            CertificateError::Other(OtherError(e)),
+        )) = error.downcast_ref()
+        {
+            if let Some(Unpinned(fingerprint)) = e.downcast_ref() {
+                return Some(*fingerprint);
+            }
+        }
+        next = error.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::{
+        client::danger::ServerCertVerifier,
+        crypto::ring,
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        CertificateError,
+    };
+
+    use super::{unpinned, Fingerprint, PinnedVerifier};
+
+    #[test]
+    fn fingerprints_are_read_with_or_without_colons() {
+        let hex = "0123456789abcdef".repeat(4);
+        let fingerprint: Fingerprint = hex.parse().unwrap();
+        let with_colons = fingerprint.to_string();
+        assert!(with_colons.starts_with("01:23:45:67:89:AB:CD:EF:01"));
+        assert_eq!(with_colons.parse::<Fingerprint>().unwrap(), fingerprint);
+        assert!("0123".parse::<Fingerprint>().is_err());
+        assert!("zz".repeat(32).parse::<Fingerprint>().is_err());
+    }
+
+    #[test]
+    fn certificates_that_arent_pinned_are_rejected() {
+        let certificate = CertificateDer::from(b"certificate".to_vec());
+        let other = Fingerprint::of(b"another certificate");
+        let verify = |pins| {
+            let provider = Arc::new(ring::default_provider());
+            PinnedVerifier::new(pins, provider)
+                .unwrap()
+                .verify_server_cert(
+                    &certificate,
+                    &[],
+                    &ServerName::try_from("api.polygon.io").unwrap(),
+                    &[],
+                    UnixTime::now(),
+                )
+        };
+        let e = verify(vec![other]).unwrap_err();
+        assert_eq!(unpinned(&e), Some(Fingerprint::of(&certificate)));
+        // Like the errors of failed connections
+        let wrapped = std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        let wrapped = std::io::Error::other(wrapped);
+        assert!(unpinned(&wrapped).is_some());
+
+        // A pinned certificate still has to be valid
+        let e = verify(vec![other, Fingerprint::of(&certificate)]).unwrap_err();
+        assert!(matches!(
+            e,
+            rustls::Error::InvalidCertificate(CertificateError::BadEncoding)
+        ));
+        assert_eq!(unpinned(&e), None);
+    }
+}
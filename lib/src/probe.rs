@@ -0,0 +1,187 @@
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use reqwest::StatusCode;
+use tracing::{info, warn};
+
+use crate::{
+    client::Client,
+    error::{self, Error},
+    types::{AggregateRequestBuilder, Timespan},
+};
+
+/// A bar size, written like the folders of its bars, e.g. `5minute` or just
+/// `minute` for a multiplier of 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub timespan: Timespan,
+    pub multiplier: u32,
+}
+
+impl FromStr for Span {
+    type Err = error::Init;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || error::Init::InvalidSpan(s.to_string());
+        let digits =
+            s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let (multiplier, timespan) = s.split_at(digits);
+        let multiplier = match multiplier {
+            "" => 1,
+            multiplier => multiplier.parse().map_err(|_| invalid())?,
+        };
+        if multiplier == 0 {
+            return Err(invalid());
+        }
+        let timespan = timespan.parse().map_err(|_| invalid())?;
+        Ok(Self {
+            timespan,
+            multiplier,
+        })
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.timespan.with_multiplier(self.multiplier))
+    }
+}
+
+/// Whether the Polygon plan covers bars of a [`Span`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Support {
+    Supported,
+    /// Polygon refused the request, with this message
+    Unsupported(String),
+    /// The request failed for another reason, so there's no telling
+    Unknown(String),
+}
+
+/// How probing a [`Span`] went, see [`probe_spans`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanSupport {
+    pub span: Span,
+    pub support: Support,
+}
+
+/// Request a single bar of `ticker` from the week before `to` with each of
+/// `spans`, to find out which of them the plan covers. Polygon refuses the
+/// others with a 403
+pub async fn probe_spans(
+    client: &Client,
+    ticker: &str,
+    spans: &[Span],
+    to: DateTime<Utc>,
+) -> Vec<SpanSupport> {
+    let mut supports = Vec::with_capacity(spans.len());
+    for &span in spans {
+        let support = match probe(client, ticker, span, to).await {
+            Ok(()) => {
+                info!(%span, "The plan covers the span");
+                Support::Supported
+            }
+            Err(e) if e.status() == Some(StatusCode::FORBIDDEN) => {
+                warn!(%span, error = %e, "The plan doesn't cover the span");
+                Support::Unsupported(e.to_string())
+            }
+            Err(e) => {
+                warn!(%span, error = %e, "Failed to probe the span");
+                Support::Unknown(e.to_string())
+            }
+        };
+        supports.push(SpanSupport { span, support });
+    }
+    supports
+}
+
+async fn probe(
+    client: &Client,
+    ticker: &str,
+    span: Span,
+    to: DateTime<Utc>,
+) -> Result<(), Error> {
+    let request = AggregateRequestBuilder::default()
+        .ticker(ticker)
+        .timespan(span.timespan)
+        .multiplier(span.multiplier)
+        .from(to - TimeDelta::weeks(1))
+        .to(to)
+        .limit(1)
+        .build()?;
+    client.get_aggregate(&request).await.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use wiremock::{
+        matchers::{method, path_regex},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{probe_spans, Span, Support};
+    use crate::{client::Client, types::Timespan};
+
+    #[test]
+    fn spans_are_read_like_their_folders() {
+        let span: Span = "5minute".parse().unwrap();
+        assert_eq!(
+            span,
+            Span {
+                timespan: Timespan::Minute,
+                multiplier: 5
+            }
+        );
+        assert_eq!(span.to_string(), "5minute");
+        assert_eq!("day".parse::<Span>().unwrap().to_string(), "day");
+        assert!("0minute".parse::<Span>().is_err());
+        assert!("5fortnight".parse::<Span>().is_err());
+    }
+
+    #[tokio::test]
+    async fn spans_the_plan_refuses_are_unsupported() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path_regex("/range/1/second/"))
+            .respond_with(ResponseTemplate::new(403).set_body_json(
+                serde_json::json!({
+                    "status": "NOT_AUTHORIZED",
+                    "request_id": "1",
+                    "message": "You are not entitled to this data."
+                }),
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex("/range/1/minute/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                serde_json::json!({
+                    "ticker": "SPY",
+                    "adjusted": true,
+                    "queryCount": 0,
+                    "request_id": "2",
+                    "resultsCount": 0,
+                    "status": "OK",
+                }),
+            ))
+            .mount(&server)
+            .await;
+        let client = Client::new("key")
+            .unwrap()
+            .with_base_url(&server.uri())
+            .unwrap();
+        let spans = ["second", "minute", "5hour"].map(|s| s.parse().unwrap());
+        let to = Utc.with_ymd_and_hms(2024, 1, 8, 0, 0, 0).unwrap();
+        let supports = probe_spans(&client, "SPY", &spans, to).await;
+
+        assert_eq!(supports.len(), 3);
+        assert!(matches!(
+            &supports[0].support,
+            Support::Unsupported(message) if message.contains("not entitled")
+        ));
+        assert_eq!(supports[1].support, Support::Supported);
+        // Other failures say nothing about the plan
+        assert!(matches!(supports[2].support, Support::Unknown(_)));
+        assert_eq!(supports[2].span.to_string(), "5hour");
+    }
+}
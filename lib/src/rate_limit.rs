@@ -0,0 +1,62 @@
+use std::{num::NonZeroU32, time::Duration};
+
+use tokio::{
+    sync::Mutex,
+    time::{sleep_until, Instant},
+};
+
+/// A token bucket shared by every request, refilled at a steady rate. It
+/// holds a single token, so requests are spread out evenly instead of going
+/// out in bursts that Polygon would count against the next minute anyway
+pub struct RateLimiter {
+    /// How long it takes to refill a token
+    interval: Duration,
+    /// When the next token is available. Held while waiting for it, so
+    /// requests get their tokens in the order they asked for them
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Allow up to `requests_per_minute` requests a minute
+    pub fn per_minute(requests_per_minute: NonZeroU32) -> Self {
+        Self {
+            interval: Duration::from_secs(60) / requests_per_minute.get(),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until a token is available and take it
+    pub async fn acquire(&self) {
+        let mut next = self.next.lock().await;
+        sleep_until(*next).await;
+        *next = Instant::now().max(*next) + self.interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU32, sync::Arc, time::Duration};
+
+    use tokio::time::Instant;
+
+    use super::RateLimiter;
+
+    #[tokio::test]
+    async fn concurrent_requests_share_the_budget() {
+        let limiter =
+            Arc::new(RateLimiter::per_minute(NonZeroU32::new(1_200).unwrap()));
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.acquire().await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // The first token is there straight away, the other three are 50ms
+        // apart
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
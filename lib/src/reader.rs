@@ -0,0 +1,574 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use chrono::DateTime;
+use csv::{ReaderBuilder, StringRecord};
+use parquet::{
+    file::reader::{FileReader, SerializedFileReader},
+    record::{Field, Row},
+    schema::types::Type,
+};
+use rust_decimal::Decimal;
+use serde_json::Value;
+
+use crate::{
+    error,
+    serializer::CSV_DELIMITERS,
+    types::{AggregateRecord, TimestampUnit},
+};
+
+/// Read back the records of a file written by
+/// [`Service`](crate::service::Service) in one of the built-in formats,
+/// going by the extension of `path`. Timestamps were written in `unit` and
+/// are converted back to Unix Msec.
+pub fn read_records(
+    path: &Path,
+    unit: TimestampUnit,
+) -> Result<Vec<AggregateRecord>, error::FileIo> {
+    match path.extension().and_then(std::ffi::OsStr::to_str) {
+        Some("csv") => read_csv(path, unit),
+        Some("ndjson") => read_json(path, unit),
+        Some("json") => read_json_array(path, unit),
+        Some("parquet") => read_parquet(path, None),
+        extension => Err(error::FileIo::UnreadableFormat(
+            extension.unwrap_or_default().to_string(),
+        )),
+    }
+}
+
+/// The timestamp of the last record in a file written by
+/// [`Service`](crate::service::Service), in Unix Msec, or `None` if the file
+/// doesn't exist or has no records. Only the end of CSV and newline
+/// delimited JSON files is read, and only the timestamps of Parquet files.
+pub fn last_timestamp(
+    path: &Path,
+    unit: TimestampUnit,
+) -> Result<Option<i64>, error::FileIo> {
+    let extension = path.extension().and_then(std::ffi::OsStr::to_str);
+    if matches!(extension, Some("json" | "parquet")) {
+        let records = if extension == Some("parquet") {
+            read_parquet_columns(path, &["timestamp"])
+        } else {
+            read_records(path, unit)
+        };
+        return match records {
+            Ok(records) => Ok(records.last().map(|record| record.timestamp)),
+            Err(error::FileIo::ReadFile(e))
+                if e.kind() == std::io::ErrorKind::NotFound =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        };
+    }
+    let Some(line) = last_line(path)? else {
+        return Ok(None);
+    };
+    match extension {
+        Some("csv") => {
+            // The timestamp is always the first column
+            let field = line
+                .split(|c| CSV_DELIMITERS.contains(&c))
+                .next()
+                .unwrap_or_default();
+            let field = field.trim().trim_matches('"');
+            if field == "timestamp" {
+                return Ok(None);
+            }
+            parse_timestamp(field, unit).map(Some)
+        }
+        Some("ndjson") => Ok(Some(
+            from_json(serde_json::from_str(&line)?, unit)?.timestamp,
+        )),
+        extension => Err(error::FileIo::UnreadableFormat(
+            extension.unwrap_or_default().to_string(),
+        )),
+    }
+}
+
+/// The last line of the file at `path` that isn't blank, reading as little
+/// of the end of the file as it takes
+fn last_line(path: &Path) -> Result<Option<String>, error::FileIo> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(error::FileIo::ReadFile(e)),
+    };
+    let len = file.metadata().map_err(error::FileIo::ReadFile)?.len();
+    let mut window = 4096;
+    loop {
+        let start = len.saturating_sub(window);
+        file.seek(SeekFrom::Start(start))
+            .map_err(error::FileIo::ReadFile)?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)
+            .map_err(error::FileIo::ReadFile)?;
+        let tail = String::from_utf8_lossy(&tail);
+        let mut lines =
+            tail.lines().rev().filter(|line| !line.trim().is_empty());
+        let last = lines.next();
+        // Unless the whole file was read, the last line is only known to be
+        // complete if there's another line before it
+        if start == 0 || lines.next().is_some() {
+            return Ok(last.map(str::to_string));
+        }
+        window *= 2;
+    }
+}
+
+/// Parse a timestamp written in `unit` into Unix Msec
+fn parse_timestamp(
+    timestamp: &str,
+    unit: TimestampUnit,
+) -> Result<i64, error::FileIo> {
+    let invalid = || error::FileIo::InvalidTimestamp(timestamp.to_string());
+    match unit {
+        TimestampUnit::Millis => timestamp.parse().map_err(|_| invalid()),
+        TimestampUnit::Seconds => timestamp
+            .parse::<i64>()
+            .ok()
+            .and_then(|seconds| seconds.checked_mul(1000))
+            .ok_or_else(invalid),
+        TimestampUnit::Rfc3339 => DateTime::parse_from_rfc3339(timestamp)
+            .map(|datetime| datetime.timestamp_millis())
+            .map_err(|_| invalid()),
+    }
+}
+
+/// Read back the records of a newline delimited JSON file
+fn read_json(
+    path: &Path,
+    unit: TimestampUnit,
+) -> Result<Vec<AggregateRecord>, error::FileIo> {
+    let file = File::open(path).map_err(error::FileIo::ReadFile)?;
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(error::FileIo::ReadFile)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(from_json(serde_json::from_str(&line)?, unit)?);
+    }
+    Ok(records)
+}
+
+/// Read back the records of a file with a single JSON array
+fn read_json_array(
+    path: &Path,
+    unit: TimestampUnit,
+) -> Result<Vec<AggregateRecord>, error::FileIo> {
+    let file = File::open(path).map_err(error::FileIo::ReadFile)?;
+    let values: Vec<Value> = serde_json::from_reader(BufReader::new(file))?;
+    values
+        .into_iter()
+        .map(|value| from_json(value, unit))
+        .collect()
+}
+
+/// Read back only `columns` of the records of a Parquet file written by
+/// [`Service`](crate::service::Service), e.g. just `close`, without reading
+/// the other columns from disk. The fields of the other columns are left
+/// zero or empty
+pub fn read_parquet_columns(
+    path: &Path,
+    columns: &[&str],
+) -> Result<Vec<AggregateRecord>, error::FileIo> {
+    read_parquet(path, Some(columns))
+}
+
+/// Read back the records of a Parquet file, all of their columns unless
+/// only some are asked for. Timestamps are always Unix Msec in Parquet
+/// files. An empty file, e.g. one that was truncated, has no records
+fn read_parquet(
+    path: &Path,
+    columns: Option<&[&str]>,
+) -> Result<Vec<AggregateRecord>, error::FileIo> {
+    let file = File::open(path).map_err(error::FileIo::ReadFile)?;
+    if file.metadata().map_err(error::FileIo::ReadFile)?.len() == 0 {
+        return Ok(Vec::new());
+    }
+    let reader = SerializedFileReader::new(file)?;
+    let projection = columns
+        .map(|columns| {
+            projection(reader.metadata().file_metadata().schema(), columns)
+        })
+        .transpose()?;
+    reader
+        .get_row_iter(projection)?
+        .map(|row| from_parquet(&row?))
+        .collect()
+}
+
+/// The part of `schema` with only `columns`, in the order of the file
+fn projection(schema: &Type, columns: &[&str]) -> Result<Type, error::FileIo> {
+    let fields = schema.get_fields();
+    if let Some(unknown) = columns
+        .iter()
+        .find(|column| !fields.iter().any(|field| field.name() == **column))
+    {
+        return Err(error::FileIo::UnknownColumn(unknown.to_string()));
+    }
+    let fields = fields
+        .iter()
+        .filter(|field| columns.contains(&field.name()))
+        .cloned()
+        .collect();
+    Ok(Type::group_type_builder(schema.name())
+        .with_fields(fields)
+        .build()?)
+}
+
+fn from_parquet(row: &Row) -> Result<AggregateRecord, error::FileIo> {
+    let mut record = AggregateRecord {
+        timestamp: 0,
+        open: Decimal::ZERO,
+        high: Decimal::ZERO,
+        low: Decimal::ZERO,
+        close: Decimal::ZERO,
+        volume: Decimal::ZERO,
+        transactions: None,
+        otc: None,
+        vwap: None,
+    };
+    for (name, field) in row.get_column_iter() {
+        match (name.as_str(), field) {
+            ("timestamp", Field::TimestampMillis(timestamp)) => {
+                record.timestamp = *timestamp;
+            }
+            ("open", Field::Decimal(open)) => record.open = from_decimal(open)?,
+            ("high", Field::Decimal(high)) => record.high = from_decimal(high)?,
+            ("low", Field::Decimal(low)) => record.low = from_decimal(low)?,
+            ("close", Field::Decimal(close)) => {
+                record.close = from_decimal(close)?;
+            }
+            ("volume", Field::Decimal(volume)) => {
+                record.volume = from_decimal(volume)?;
+            }
+            ("transactions", Field::Long(transactions)) => {
+                record.transactions = usize::try_from(*transactions).ok();
+            }
+            ("otc", Field::Bool(otc)) => record.otc = Some(*otc),
+            ("vwap", Field::Decimal(vwap)) => {
+                record.vwap = Some(from_decimal(vwap)?);
+            }
+            _ => {}
+        }
+    }
+    Ok(record)
+}
+
+/// A Parquet decimal, stored as its unscaled big-endian bytes
+fn from_decimal(
+    decimal: &parquet::data_type::Decimal,
+) -> Result<Decimal, error::FileIo> {
+    let bytes = decimal.data();
+    let out_of_range = || {
+        error::FileIo::DecimalOutOfRange(format!(
+            "{bytes:?} with a scale of {}",
+            decimal.scale()
+        ))
+    };
+    if bytes.len() > 16 {
+        return Err(out_of_range());
+    }
+    // Sign extend to the 16 bytes of an i128
+    let fill = if bytes.first().is_some_and(|byte| *byte >= 0x80) {
+        0xff
+    } else {
+        0
+    };
+    let mut unscaled = [fill; 16];
+    unscaled[16 - bytes.len()..].copy_from_slice(bytes);
+    let scale = u32::try_from(decimal.scale()).map_err(|_| out_of_range())?;
+    Decimal::try_from_i128_with_scale(i128::from_be_bytes(unscaled), scale)
+        .map_err(|_| out_of_range())
+}
+
+/// Deserialize a record written with its timestamp in `unit`
+fn from_json(
+    mut value: Value,
+    unit: TimestampUnit,
+) -> Result<AggregateRecord, error::FileIo> {
+    if unit != TimestampUnit::Millis {
+        if let Some(timestamp) = value.get_mut("timestamp") {
+            let text = match &*timestamp {
+                Value::String(text) => text.clone(),
+                other => other.to_string(),
+            };
+            *timestamp = parse_timestamp(&text, unit)?.into();
+        }
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// The delimiter of the CSV file at `path`, going by what follows the
+/// `timestamp` column the header starts with. A comma if there's no telling
+fn csv_delimiter(path: &Path) -> u8 {
+    let mut header = String::new();
+    if let Ok(file) = File::open(path) {
+        let _ = BufReader::new(file).read_line(&mut header);
+    }
+    let header = header.strip_prefix("\u{feff}").unwrap_or(&header);
+    header
+        .trim_start_matches('"')
+        .strip_prefix("timestamp")
+        .map(|rest| rest.trim_start_matches('"'))
+        .and_then(|rest| rest.chars().next())
+        .filter(|c| CSV_DELIMITERS.contains(c))
+        .map_or(b',', |c| c as u8)
+}
+
+/// Read back the records of a CSV file.
+///
+/// Older versions appended a header row on every run, so any row repeating
+/// a header is used as the header for the rows that follow it.
+fn read_csv(
+    path: &Path,
+    unit: TimestampUnit,
+) -> Result<Vec<AggregateRecord>, error::FileIo> {
+    let delimiter = csv_delimiter(path);
+    let mut reader = ReaderBuilder::new()
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_path(path)
+        .map_err(error::FileIo::CsvRead)?;
+    let mut headers = reader.headers().map_err(error::FileIo::CsvRead)?.clone();
+    let mut records = Vec::new();
+    for row in reader.records() {
+        let row = row.map_err(error::FileIo::CsvRead)?;
+        if row.get(0) == headers.get(0) {
+            headers = row;
+            continue;
+        }
+        // Files with another delimiter can have decimal commas, which are
+        // the only commas they can have outside the header
+        let row = if delimiter == b',' {
+            row
+        } else {
+            row.iter().map(|field| field.replace(',', ".")).collect()
+        };
+        let row = match headers.iter().position(|h| h == "timestamp") {
+            Some(column) if unit != TimestampUnit::Millis => row
+                .iter()
+                .enumerate()
+                .map(|(i, field)| {
+                    if i == column {
+                        parse_timestamp(field, unit).map(|ms| ms.to_string())
+                    } else {
+                        Ok(field.to_string())
+                    }
+                })
+                .collect::<Result<StringRecord, _>>()?,
+            _ => row,
+        };
+        records.push(
+            row.deserialize(Some(&headers))
+                .map_err(error::FileIo::CsvRead)?,
+        );
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rust_decimal::Decimal;
+
+    use super::{last_timestamp, read_parquet_columns, read_records};
+    use crate::{
+        error,
+        serializer::{
+            CsvSerializer, ParquetSerializer, RecordWriter, SerializeOptions,
+        },
+        types::{AggregateRecord, TimestampUnit},
+    };
+
+    #[test]
+    fn read_records_skips_repeated_headers() {
+        let path = std::env::temp_dir().join("polygon-data-read-records.csv");
+        fs::write(
+            &path,
+            "timestamp,open,high,low,close,volume\n\
+             1,1.5,2,1,1.75,100\n\
+             timestamp,open,high,low,close,volume,transactions\n\
+             2,1.75,2,1.5,2,200,3\n",
+        )
+        .unwrap();
+        let records = read_records(&path, TimestampUnit::Millis).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].timestamp, 1);
+        assert_eq!(records[0].transactions, None);
+        assert_eq!(records[1].timestamp, 2);
+        assert_eq!(records[1].transactions, Some(3));
+    }
+
+    #[test]
+    fn last_timestamp_reads_the_end_of_the_file() {
+        let path = std::env::temp_dir().join("polygon-data-last.csv");
+        let _ = fs::remove_file(&path);
+        assert_eq!(last_timestamp(&path, TimestampUnit::Millis).unwrap(), None);
+
+        let header = "timestamp,open,high,low,close,volume\n";
+        fs::write(&path, header).unwrap();
+        assert_eq!(last_timestamp(&path, TimestampUnit::Millis).unwrap(), None);
+
+        // Longer than the first window read from the end of the file
+        let rows: String = (1..=500)
+            .map(|t| format!("{t},1.5,2,1,1.75,100\n"))
+            .collect();
+        fs::write(&path, format!("{header}{rows}")).unwrap();
+        let last = last_timestamp(&path, TimestampUnit::Millis).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(last, Some(500));
+
+        let path = std::env::temp_dir().join("polygon-data-last.ndjson");
+        fs::write(
+            &path,
+            "{\"timestamp\":1,\"open\":1,\"high\":1,\"low\":1,\"close\":1}\n\
+             {\"timestamp\":2,\"open\":1,\"high\":1,\"low\":1,\"close\":1}",
+        )
+        .unwrap();
+        let last = last_timestamp(&path, TimestampUnit::Seconds).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(last, Some(2_000));
+    }
+
+    #[test]
+    fn decimal_commas_are_read_back() {
+        let path = std::env::temp_dir().join("polygon-data-decimal-comma.csv");
+        let _ = fs::remove_file(&path);
+        let records: Vec<_> = (1..=2)
+            .map(|i| AggregateRecord {
+                timestamp: i,
+                open: Decimal::new(123, 2),
+                high: Decimal::TWO,
+                low: Decimal::ONE,
+                close: Decimal::new(i * 125, 2),
+                volume: Decimal::new(10_005, 1),
+                transactions: Some(5),
+                otc: None,
+                vwap: Some(Decimal::new(15, 1)),
+            })
+            .collect();
+        let serializer = CsvSerializer {
+            options: SerializeOptions {
+                delimiter: b';',
+                decimal_comma: true,
+                ..Default::default()
+            },
+        };
+        let mut writer = RecordWriter::append(&path, &serializer).unwrap();
+        writer.write(&records).unwrap();
+        writer.finish().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let read = read_records(&path, TimestampUnit::Millis).unwrap();
+        let last = last_timestamp(&path, TimestampUnit::Millis).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            contents.lines().nth(1),
+            Some("1;1,23;2;1;1,25;1000,5;5;1,5")
+        );
+        let closes: Vec<_> = read.iter().map(|record| record.close).collect();
+        assert_eq!(closes, [Decimal::new(125, 2), Decimal::new(250, 2)]);
+        assert_eq!(read[0].vwap, Some(Decimal::new(15, 1)));
+        assert_eq!(last, Some(2));
+    }
+
+    #[test]
+    fn only_some_parquet_columns_can_be_read() {
+        let path = std::env::temp_dir().join("polygon-data-columns.parquet");
+        let _ = fs::remove_file(&path);
+        let records: Vec<_> = (1..=3)
+            .map(|i| AggregateRecord {
+                timestamp: i,
+                open: Decimal::ONE,
+                high: Decimal::TWO,
+                low: Decimal::ONE,
+                close: Decimal::new(i * 125, 2),
+                volume: Decimal::TEN,
+                transactions: Some(5),
+                otc: None,
+                vwap: Some(Decimal::ONE),
+            })
+            .collect();
+        let serializer = ParquetSerializer::default();
+        let mut writer = RecordWriter::append(&path, &serializer).unwrap();
+        writer.write(&records).unwrap();
+        writer.finish().unwrap();
+
+        let read = read_parquet_columns(&path, &["close"]).unwrap();
+        let unknown = read_parquet_columns(&path, &["close", "bid"]);
+        let last = last_timestamp(&path, TimestampUnit::Millis).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let closes: Vec<_> = read.iter().map(|record| record.close).collect();
+        assert_eq!(
+            closes,
+            ["1.25", "2.50", "3.75"].map(|c| c.parse().unwrap())
+        );
+        // The other columns aren't read
+        assert!(read.iter().all(|record| record.timestamp == 0
+            && record.high.is_zero()
+            && record.transactions.is_none()
+            && record.vwap.is_none()));
+        assert!(matches!(
+            unknown,
+            Err(error::FileIo::UnknownColumn(column)) if column == "bid"
+        ));
+        assert_eq!(last, Some(3));
+    }
+
+    #[test]
+    fn read_records_converts_timestamps_back_to_millis() {
+        let path = std::env::temp_dir().join("polygon-data-rfc3339.ndjson");
+        fs::write(
+            &path,
+            r#"{"timestamp":"2024-01-02T14:31:00Z","open":1,"high":1,"low":1,"close":1,"volume":1}"#,
+        )
+        .unwrap();
+        let records = read_records(&path, TimestampUnit::Rfc3339).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(records[0].timestamp, 1_704_205_860_000);
+    }
+
+    #[test]
+    fn read_records_parses_scientific_notation() {
+        let path = std::env::temp_dir().join("polygon-data-scientific.csv");
+        fs::write(
+            &path,
+            "timestamp,open,high,low,close,volume,vwap\n\
+             1,1.23E2,1.5e1,1E-3,123,2.5E+6,1.2345e2\n",
+        )
+        .unwrap();
+        let records = read_records(&path, TimestampUnit::Millis).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let record = &records[0];
+        assert_eq!(record.open, Decimal::new(123, 0));
+        assert_eq!(record.open, record.close);
+        assert_eq!(record.high, Decimal::new(15, 0));
+        assert_eq!(record.low, Decimal::new(1, 3));
+        assert_eq!(record.volume, Decimal::new(2_500_000, 0));
+        assert_eq!(record.vwap, Some(Decimal::new(12345, 2)));
+    }
+
+    #[test]
+    fn json_numbers_in_scientific_notation_are_decimals() {
+        let record: AggregateRecord = serde_json::from_str(
+            r#"{"t":1,"o":1.23E2,"h":1.5e1,"l":1E-3,"c":123,"v":2.5E+6}"#,
+        )
+        .unwrap();
+        assert_eq!(record.open, Decimal::new(123, 0));
+        assert_eq!(record.low, Decimal::new(1, 3));
+        assert_eq!(record.volume, Decimal::new(2_500_000, 0));
+    }
+}
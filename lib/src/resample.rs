@@ -0,0 +1,235 @@
+use chrono::{DateTime, TimeZone};
+use chrono_tz::America::New_York;
+use rust_decimal::Decimal;
+
+use crate::{error::Error, types::AggregateRecord};
+
+/// Combine runs of consecutive bars that `bucket` puts in the same bucket,
+/// e.g. the same day, into a single bar per run. `bars` should be sorted by
+/// timestamp.
+pub fn resample<K: PartialEq>(
+    bars: &[AggregateRecord],
+    bucket: impl Fn(&AggregateRecord) -> K,
+) -> Result<Vec<AggregateRecord>, Error> {
+    bars.chunk_by(|a, b| bucket(a) == bucket(b))
+        .filter_map(|bars| combine(bars).transpose())
+        .collect()
+}
+
+/// Resamples bars batch by batch as they're fetched, only keeping the bars of
+/// the bucket in progress in memory. Same as [`resample`] over all the bars
+/// pushed, as long as they're pushed in order
+pub struct Resampler<K, F> {
+    bucket: F,
+    current: Option<K>,
+    pending: Vec<AggregateRecord>,
+}
+
+impl<K: PartialEq, F: Fn(&AggregateRecord) -> K> Resampler<K, F> {
+    pub fn new(bucket: F) -> Self {
+        Self {
+            bucket,
+            current: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Add the next batch of `bars`, returning the combined bars of the
+    /// buckets they complete
+    pub fn push(
+        &mut self,
+        bars: &[AggregateRecord],
+    ) -> Result<Vec<AggregateRecord>, Error> {
+        let mut completed = Vec::new();
+        for bar in bars {
+            let key = (self.bucket)(bar);
+            if self.current.as_ref() != Some(&key) {
+                completed.extend(combine(&self.pending)?);
+                self.pending.clear();
+                self.current = Some(key);
+            }
+            self.pending.push(bar.clone());
+        }
+        Ok(completed)
+    }
+
+    /// The combined bar of the last bucket, if any bars were pushed
+    pub fn finish(self) -> Result<Option<AggregateRecord>, Error> {
+        combine(&self.pending)
+    }
+}
+
+/// The start of the day in New York that `bar` starts in, in Unix Msec. US
+/// markets trade by New York dates, so e.g. after-hours bars past midnight
+/// UTC still belong to the day before, like in Polygon's daily bars
+pub fn new_york_day(bar: &AggregateRecord) -> i64 {
+    let midnight = DateTime::from_timestamp_millis(bar.timestamp)
+        .map(|time| time.with_timezone(&New_York).date_naive())
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| {
+            New_York.from_local_datetime(&midnight).earliest()
+        });
+    midnight.map_or(bar.timestamp, |midnight| midnight.timestamp_millis())
+}
+
+/// Combine consecutive bars into a single bar covering all of them.
+///
+/// The VWAP of the combined bar is the volume weighted average of the bars'
+/// VWAPs, `sum(vwap * volume) / sum(volume)`, which is the VWAP of all the
+/// trades in the combined window. It's left out if any of the bars doesn't
+/// have a VWAP, or if there was no volume at all.
+///
+/// Fails with [`Error::Overflow`] instead of panicking if the volume, the
+/// volume weighted prices or the transactions are too large to add up. `None`
+/// if there are no `bars`
+pub fn combine(
+    bars: &[AggregateRecord],
+) -> Result<Option<AggregateRecord>, Error> {
+    let (Some(first), Some(last)) = (bars.first(), bars.last()) else {
+        return Ok(None);
+    };
+    let volume = bars
+        .iter()
+        .try_fold(Decimal::ZERO, |total, bar| total.checked_add(bar.volume))
+        .ok_or(Error::Overflow("volume"))?;
+    let vwap = match bars.iter().map(|bar| bar.vwap).collect::<Option<Vec<_>>>()
+    {
+        Some(vwaps) if !volume.is_zero() => Some(
+            vwaps
+                .iter()
+                .zip(bars)
+                .try_fold(Decimal::ZERO, |total, (vwap, bar)| {
+                    total.checked_add(vwap.checked_mul(bar.volume)?)
+                })
+                .and_then(|weighted| weighted.checked_div(volume))
+                .ok_or(Error::Overflow("volume weighted price"))?,
+        ),
+        _ => None,
+    };
+    let transactions = bars
+        .iter()
+        .filter_map(|bar| bar.transactions)
+        .try_fold(None, |total: Option<usize>, n| {
+            Some(Some(total.unwrap_or_default().checked_add(n)?))
+        })
+        .ok_or(Error::Overflow("transactions"))?;
+    Ok(Some(AggregateRecord {
+        timestamp: first.timestamp,
+        open: first.open,
+        high: bars.iter().map(|bar| bar.high).max().unwrap_or(first.high),
+        low: bars.iter().map(|bar| bar.low).min().unwrap_or(first.low),
+        close: last.close,
+        volume,
+        transactions,
+        otc: first.otc,
+        vwap,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use rust_decimal::Decimal;
+
+    use super::{combine, new_york_day, resample, Resampler};
+    use crate::{error::Error, types::AggregateRecord};
+
+    fn bar(timestamp: i64, vwap: &str, volume: i64) -> AggregateRecord {
+        let price = vwap.parse().unwrap();
+        AggregateRecord {
+            timestamp,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: Decimal::from(volume),
+            transactions: Some(1),
+            otc: None,
+            vwap: Some(price),
+        }
+    }
+
+    #[test]
+    fn vwap_is_weighted_by_volume() {
+        let bars = [bar(0, "10", 100), bar(1, "11", 300), bar(2, "12", 100)];
+        let combined = combine(&bars).unwrap().unwrap();
+        // (10 * 100 + 11 * 300 + 12 * 100) / 500, not (10 + 11 + 12) / 3
+        assert_eq!(combined.vwap, Some("11".parse().unwrap()));
+        let bars = [bar(0, "10", 300), bar(1, "12", 100)];
+        assert_eq!(
+            combine(&bars).unwrap().unwrap().vwap,
+            Some("10.5".parse().unwrap())
+        );
+        assert_eq!(combined.open, Decimal::from(10));
+        assert_eq!(combined.close, Decimal::from(12));
+        assert_eq!(combined.volume, Decimal::from(500));
+        assert_eq!(combined.transactions, Some(3));
+    }
+
+    #[test]
+    fn vwap_needs_every_bar_to_have_one() {
+        let mut bars = [bar(0, "10", 100), bar(1, "11", 300)];
+        bars[1].vwap = None;
+        assert_eq!(combine(&bars).unwrap().unwrap().vwap, None);
+        assert_eq!(combine(&[bar(0, "10", 0)]).unwrap().unwrap().vwap, None);
+    }
+
+    #[test]
+    fn overflowing_volume_is_an_error() {
+        let mut bars = [bar(0, "10", 0), bar(1, "10", 0)];
+        bars[0].volume = Decimal::MAX;
+        bars[1].volume = Decimal::MAX;
+        assert!(matches!(combine(&bars), Err(Error::Overflow("volume"))));
+
+        // The volume fits but the volume weighted prices don't
+        bars[0].volume = Decimal::MAX / Decimal::from(4);
+        bars[1].volume = bars[0].volume;
+        assert!(matches!(
+            combine(&bars),
+            Err(Error::Overflow("volume weighted price"))
+        ));
+        bars.iter_mut().for_each(|bar| bar.vwap = None);
+        let combined = combine(&bars).unwrap().unwrap();
+        assert_eq!(combined.volume, bars[0].volume * Decimal::from(2));
+    }
+
+    #[test]
+    fn days_start_at_midnight_in_new_york() {
+        let at = |d, h, m| {
+            Utc.with_ymd_and_hms(2024, 1, d, h, m, 0)
+                .unwrap()
+                .timestamp_millis()
+        };
+        // 18:30 and 20:30 in New York, after the close of January 2nd
+        let midnight = at(2, 5, 0);
+        assert_eq!(new_york_day(&bar(at(2, 23, 30), "1", 1)), midnight);
+        assert_eq!(new_york_day(&bar(at(3, 1, 30), "1", 1)), midnight);
+        assert_eq!(new_york_day(&bar(at(3, 5, 0), "1", 1)), at(3, 5, 0));
+    }
+
+    #[test]
+    fn resample_combines_each_bucket() {
+        let bars = [bar(0, "1", 1), bar(1, "2", 1), bar(10, "3", 1)];
+        let resampled = resample(&bars, |bar| bar.timestamp / 10).unwrap();
+        assert_eq!(resampled.len(), 2);
+        assert_eq!(resampled[0].volume, Decimal::from(2));
+        assert_eq!(resampled[1].timestamp, 10);
+    }
+
+    #[test]
+    fn resampling_batches_matches_resampling_everything() {
+        let bars: Vec<_> = (0..25).map(|t| bar(t, "1", t + 1)).collect();
+        let mut resampler = Resampler::new(|bar| bar.timestamp / 10);
+        let mut resampled: Vec<_> = bars
+            .chunks(7)
+            .flat_map(|batch| resampler.push(batch).unwrap())
+            .collect();
+        resampled.extend(resampler.finish().unwrap());
+        let expected = resample(&bars, |bar| bar.timestamp / 10).unwrap();
+        let volumes = |bars: &[AggregateRecord]| {
+            bars.iter().map(|bar| bar.volume).collect::<Vec<_>>()
+        };
+        assert_eq!(volumes(&resampled), volumes(&expected));
+        assert_eq!(resampled.len(), 3);
+    }
+}
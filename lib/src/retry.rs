@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::StatusCode;
+
+use crate::error::Error;
+
+/// When and how often [`Client`](crate::client::Client) retries a request
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// How many times to retry a request before giving up
+    pub max_retries: u32,
+    /// The delay before the first retry, doubled for every retry after that
+    pub base_delay: Duration,
+    /// The longest to ever wait between two retries
+    pub max_delay: Duration,
+    /// Retry requests that failed with a 5xx status. Polygon occasionally
+    /// returns these for a short while during upstream hiccups.
+    pub retry_server_errors: bool,
+    /// Retry requests that Polygon sent back an error message containing
+    /// any of these for, including soft failures it sends with a 200 status
+    pub retry_messages: Vec<String>,
+}
+
+/// What [`Client`](crate::client::Client) is doing about a failed request,
+/// so callers can let the user know why nothing seems to be happening
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryState {
+    /// Waiting `delay` before retrying after `attempt` failed attempts
+    Backoff { attempt: u32, delay: Duration },
+    /// Done waiting, the request is being sent again
+    Retrying,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            retry_server_errors: false,
+            retry_messages: Vec::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a request that failed with `error` is worth retrying. Failing
+    /// to send the request and being rate limited are always worth another
+    /// try, anything else the client did wrong, like a bad API key, isn't
+    pub fn is_retryable(&self, error: &Error) -> bool {
+        match error {
+            Error::SendRequest(_) => true,
+            Error::ApiError { message, .. }
+                if self.retries_message(message) =>
+            {
+                true
+            }
+            _ => error.status().is_some_and(|status| {
+                status == StatusCode::TOO_MANY_REQUESTS
+                    || (self.retry_server_errors && status.is_server_error())
+            }),
+        }
+    }
+
+    /// Whether `message` from Polygon contains one of `retry_messages`
+    pub fn retries_message(&self, message: &str) -> bool {
+        self.retry_messages
+            .iter()
+            .any(|retryable| message.contains(retryable.as_str()))
+    }
+
+    /// How long to wait before the retry after `attempt` failed attempts.
+    /// The delay grows exponentially, with jitter so that concurrent
+    /// requests don't all retry at the same moment.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+
+    use super::RetryPolicy;
+    use crate::error::Error;
+
+    fn api_error(status_code: StatusCode) -> Error {
+        Error::ApiError {
+            status_code,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn server_errors_are_retryable_when_enabled() {
+        let policy = RetryPolicy {
+            retry_server_errors: true,
+            ..Default::default()
+        };
+        assert!(
+            policy.is_retryable(&api_error(StatusCode::SERVICE_UNAVAILABLE))
+        );
+        assert!(!policy.is_retryable(&api_error(StatusCode::FORBIDDEN)));
+        assert!(!RetryPolicy::default()
+            .is_retryable(&api_error(StatusCode::SERVICE_UNAVAILABLE)));
+    }
+
+    #[test]
+    fn rate_limits_are_retried_but_auth_errors_are_not() {
+        let policy = RetryPolicy::default();
+        let rate_limited = Error::RateLimited {
+            retry_after: Some(Duration::from_secs(5)),
+            source: Box::new(api_error(StatusCode::TOO_MANY_REQUESTS)),
+        };
+        assert!(policy.is_retryable(&rate_limited));
+        assert_eq!(rate_limited.retry_after(), Some(Duration::from_secs(5)));
+        assert!(!policy.is_retryable(&api_error(StatusCode::UNAUTHORIZED)));
+        assert!(!policy.is_retryable(&api_error(StatusCode::FORBIDDEN)));
+    }
+
+    #[test]
+    fn errors_with_a_retryable_message_are_retried() {
+        let policy = RetryPolicy {
+            retry_messages: vec!["try again".into()],
+            ..Default::default()
+        };
+        let error = |message: &str| Error::ApiError {
+            status_code: StatusCode::OK,
+            message: message.into(),
+        };
+        assert!(policy.is_retryable(&error("Temporarily busy, try again")));
+        assert!(!policy.is_retryable(&error("Unknown API key")));
+        assert!(!RetryPolicy::default().is_retryable(&error("try again")));
+    }
+
+    #[test]
+    fn backoff_grows_up_to_max_delay() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            ..Default::default()
+        };
+        assert!(policy.backoff(0) <= Duration::from_secs(1));
+        assert!(policy.backoff(2) >= Duration::from_secs(2));
+        assert!(policy.backoff(2) <= Duration::from_secs(4));
+        assert!(policy.backoff(20) <= Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,90 @@
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+
+/// What to do when a response has fields that aren't in the expected schema
+#[derive(
+    Debug,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum SchemaCheck {
+    /// Log a warning and use the response anyway
+    Warn,
+    /// Fail the request
+    Error,
+}
+
+/// A strict mirror of [`types::AggregateResponse`], which ignores unknown
+/// fields
+///
+/// [`types::AggregateResponse`]: crate::types::AggregateResponse
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct AggregateResponse {
+    ticker: Option<IgnoredAny>,
+    adjusted: Option<IgnoredAny>,
+    #[serde(alias = "queryCount")]
+    query_count: Option<IgnoredAny>,
+    request_id: Option<IgnoredAny>,
+    #[serde(alias = "resultsCount")]
+    results_count: Option<IgnoredAny>,
+    count: Option<IgnoredAny>,
+    status: Option<IgnoredAny>,
+    #[serde(default)]
+    results: Vec<AggregateRecord>,
+    next_url: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct AggregateRecord {
+    #[serde(alias = "t")]
+    timestamp: Option<IgnoredAny>,
+    #[serde(alias = "o")]
+    open: Option<IgnoredAny>,
+    #[serde(alias = "h")]
+    high: Option<IgnoredAny>,
+    #[serde(alias = "l")]
+    low: Option<IgnoredAny>,
+    #[serde(alias = "c")]
+    close: Option<IgnoredAny>,
+    #[serde(alias = "v")]
+    volume: Option<IgnoredAny>,
+    #[serde(alias = "n")]
+    transactions: Option<IgnoredAny>,
+    otc: Option<IgnoredAny>,
+    #[serde(alias = "vw")]
+    vwap: Option<IgnoredAny>,
+}
+
+/// Check that the body of an aggregates response only has known fields
+pub fn check_aggregate_response(body: &str) -> Result<(), serde_json::Error> {
+    serde_json::from_str::<AggregateResponse>(body).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_aggregate_response;
+
+    #[test]
+    fn unexpected_fields_are_rejected() {
+        let known = r#"{"ticker":"SPY","adjusted":true,"queryCount":1,"request_id":"abc","resultsCount":1,"status":"OK","results":[{"t":1,"o":1,"h":1,"l":1,"c":1,"v":1,"n":1,"vw":1}]}"#;
+        assert!(check_aggregate_response(known).is_ok());
+
+        let new_field =
+            known.replace(r#""status""#, r#""delayed":true,"status""#);
+        assert!(check_aggregate_response(&new_field).is_err());
+
+        let new_record_field = known.replace(r#""vw""#, r#""x":4,"vw""#);
+        assert!(check_aggregate_response(&new_record_field).is_err());
+    }
+}
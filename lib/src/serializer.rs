@@ -0,0 +1,1360 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+};
+
+use chrono::{DateTime, SecondsFormat};
+use csv::{QuoteStyle, WriterBuilder};
+use parquet::{
+    basic::{Compression, ZstdLevel},
+    data_type::{
+        BoolType, DataType, FixedLenByteArray, FixedLenByteArrayType, Int64Type,
+    },
+    file::{
+        properties::WriterProperties,
+        writer::{SerializedFileWriter, SerializedRowGroupWriter},
+    },
+    schema::{parser::parse_message_type, types::Type},
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    error::{self, Error},
+    reader,
+    types::{AggregateRecord, CsvQuoting, OutputFormat, TimestampUnit},
+};
+
+impl OutputFormat {
+    /// The built-in serializer for this format
+    pub fn serializer(
+        self,
+        options: SerializeOptions,
+    ) -> Arc<dyn RecordSerializer> {
+        match self {
+            Self::Csv => Arc::new(CsvSerializer { options }),
+            Self::Json => Arc::new(JsonSerializer { options }),
+            Self::JsonArray => Arc::new(JsonArraySerializer { options }),
+            Self::Parquet => Arc::new(ParquetSerializer { options }),
+        }
+    }
+}
+
+impl From<CsvQuoting> for QuoteStyle {
+    fn from(quoting: CsvQuoting) -> Self {
+        match quoting {
+            CsvQuoting::Necessary => Self::Necessary,
+            CsvQuoting::Always => Self::Always,
+            CsvQuoting::Never => Self::Never,
+            CsvQuoting::NonNumeric => Self::NonNumeric,
+        }
+    }
+}
+
+/// The byte order mark CSV files start with for Excel to read them as UTF-8
+pub const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// The Zstandard levels Parquet files can be compressed at
+pub const ZSTD_LEVELS: std::ops::RangeInclusive<u32> = 1..=22;
+
+/// The delimiters CSV files can be written with
+pub const CSV_DELIMITERS: [char; 4] = [',', ';', '\t', '|'];
+
+/// Options for how the built-in serializers write records
+#[derive(Debug, Clone)]
+pub struct SerializeOptions {
+    /// How to write the `timestamp` of each record
+    pub timestamp_unit: TimestampUnit,
+    /// Whether CSV files end with a newline after the last record, which is
+    /// the default
+    pub trailing_newline: bool,
+    /// Add a `datetime` column after the `timestamp`, with the RFC 3339
+    /// datetime in UTC of the timestamp whatever its unit
+    pub with_datetime: bool,
+    /// Add `date` and `time` columns after the `timestamp`, with the UTC
+    /// date and time of day of the timestamp, e.g. `2024-01-02` and
+    /// `14:30:00`
+    pub with_date_and_time: bool,
+    /// Start CSV files with a [`UTF8_BOM`], which Excel needs but other
+    /// parsers can trip over
+    pub bom: bool,
+    /// Which fields of CSV files are quoted, only those that need it by
+    /// default
+    pub quoting: CsvQuoting,
+    /// The delimiter between the fields of CSV files, one of
+    /// [`CSV_DELIMITERS`]
+    pub delimiter: u8,
+    /// Write the decimals of CSV files with a comma instead of a point, as
+    /// spreadsheets in many locales expect. The delimiter can't be a comma
+    pub decimal_comma: bool,
+    /// Compress Parquet files with Zstandard at this level, one of
+    /// [`ZSTD_LEVELS`], instead of with Snappy. Higher levels make smaller files but take
+    /// longer to write
+    pub compression_level: Option<u32>,
+}
+
+impl Default for SerializeOptions {
+    fn default() -> Self {
+        Self {
+            timestamp_unit: TimestampUnit::default(),
+            trailing_newline: true,
+            with_datetime: false,
+            with_date_and_time: false,
+            bom: false,
+            quoting: CsvQuoting::default(),
+            delimiter: b',',
+            decimal_comma: false,
+            compression_level: None,
+        }
+    }
+}
+
+/// A record the way the built-in serializers write it, which can differ
+/// from how Polygon returns it depending on the [`SerializeOptions`]
+#[derive(Serialize)]
+struct OutputRecord<'a> {
+    timestamp: Timestamp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    datetime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<String>,
+    open: Number<'a>,
+    high: Number<'a>,
+    low: Number<'a>,
+    close: Number<'a>,
+    volume: Number<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transactions: &'a Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    otc: &'a Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vwap: Option<Number<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Timestamp {
+    Number(i64),
+    Text(String),
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Number<'a> {
+    Decimal(&'a Decimal),
+    /// A decimal written with a comma, see
+    /// [`SerializeOptions::decimal_comma`]
+    Text(String),
+}
+
+impl Number<'_> {
+    fn with_comma(self) -> Self {
+        match self {
+            Self::Decimal(decimal) => {
+                Self::Text(decimal.to_string().replace('.', ","))
+            }
+            text => text,
+        }
+    }
+}
+
+impl<'a> OutputRecord<'a> {
+    fn new(record: &'a AggregateRecord, options: &SerializeOptions) -> Self {
+        let timestamp = match options.timestamp_unit {
+            TimestampUnit::Millis => Timestamp::Number(record.timestamp),
+            TimestampUnit::Seconds => {
+                Timestamp::Number(record.timestamp.div_euclid(1000))
+            }
+            TimestampUnit::Rfc3339 => match rfc3339(record.timestamp) {
+                Some(datetime) => Timestamp::Text(datetime),
+                None => Timestamp::Number(record.timestamp),
+            },
+        };
+        let date_and_time = options
+            .with_date_and_time
+            .then(|| DateTime::from_timestamp_millis(record.timestamp))
+            .flatten()
+            .map(|datetime| datetime.naive_utc());
+        Self {
+            timestamp,
+            datetime: options
+                .with_datetime
+                .then(|| rfc3339(record.timestamp))
+                .flatten(),
+            date: date_and_time.map(|datetime| datetime.date().to_string()),
+            time: date_and_time
+                .map(|datetime| datetime.time().format("%H:%M:%S").to_string()),
+            open: Number::Decimal(&record.open),
+            high: Number::Decimal(&record.high),
+            low: Number::Decimal(&record.low),
+            close: Number::Decimal(&record.close),
+            volume: Number::Decimal(&record.volume),
+            transactions: &record.transactions,
+            otc: &record.otc,
+            vwap: record.vwap.as_ref().map(Number::Decimal),
+        }
+    }
+
+    /// The record the way the [`CsvSerializer`] writes it, which only
+    /// differs if the decimals are written with a comma
+    fn csv(record: &'a AggregateRecord, options: &SerializeOptions) -> Self {
+        let output = Self::new(record, options);
+        if !options.decimal_comma {
+            return output;
+        }
+        Self {
+            open: output.open.with_comma(),
+            high: output.high.with_comma(),
+            low: output.low.with_comma(),
+            close: output.close.with_comma(),
+            volume: output.volume.with_comma(),
+            vwap: output.vwap.map(Number::with_comma),
+            ..output
+        }
+    }
+}
+
+/// A column of the files the built-in serializers write, as described in the
+/// data dictionary of an output folder
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Column {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// `integer`, `decimal`, `string` or `boolean`. Decimals are written as
+    /// strings in JSON to keep their precision
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<&'static str>,
+    /// Whether the column is left out of the records without a value, or
+    /// empty in CSV files
+    pub optional: bool,
+}
+
+impl SerializeOptions {
+    /// The codec Parquet files are compressed with
+    fn compression(&self) -> Result<Compression, error::Init> {
+        let Some(level) = self.compression_level else {
+            return Ok(Compression::SNAPPY);
+        };
+        let invalid = || error::Init::InvalidCompressionLevel(level);
+        if !ZSTD_LEVELS.contains(&level) {
+            return Err(invalid());
+        }
+        let level = ZstdLevel::try_new(level as i32).map_err(|_| invalid())?;
+        Ok(Compression::ZSTD(level))
+    }
+
+    /// The columns the CSV and JSON serializers write records with, in
+    /// order. Parquet files always have the `timestamp` as a UTC timestamp
+    /// and none of the `datetime`, `date` and `time` columns
+    pub fn columns(&self) -> Vec<Column> {
+        let column = |name, description, kind, unit| Column {
+            name,
+            description,
+            kind,
+            unit,
+            optional: false,
+        };
+        let (kind, unit) = match self.timestamp_unit {
+            TimestampUnit::Millis => {
+                ("integer", "milliseconds since the Unix epoch")
+            }
+            TimestampUnit::Seconds => {
+                ("integer", "seconds since the Unix epoch")
+            }
+            TimestampUnit::Rfc3339 => ("string", "RFC 3339 datetime in UTC"),
+        };
+        let mut columns = vec![column(
+            "timestamp",
+            "The start of the aggregate window",
+            kind,
+            Some(unit),
+        )];
+        if self.with_datetime {
+            columns.push(column(
+                "datetime",
+                "The start of the aggregate window",
+                "string",
+                Some("RFC 3339 datetime in UTC"),
+            ));
+        }
+        if self.with_date_and_time {
+            columns.push(column(
+                "date",
+                "The day the aggregate window starts on",
+                "string",
+                Some("date in UTC, e.g. 2024-01-02"),
+            ));
+            columns.push(column(
+                "time",
+                "The time of day the aggregate window starts at",
+                "string",
+                Some("time in UTC, e.g. 14:30:00"),
+            ));
+        }
+        let price = Some("the currency the ticker is quoted in");
+        columns.extend([
+            column(
+                "open",
+                "The open price for the symbol in the given time period",
+                "decimal",
+                price,
+            ),
+            column(
+                "high",
+                "The highest price for the symbol in the given time period",
+                "decimal",
+                price,
+            ),
+            column(
+                "low",
+                "The lowest price for the symbol in the given time period",
+                "decimal",
+                price,
+            ),
+            column(
+                "close",
+                "The close price for the symbol in the given time period",
+                "decimal",
+                price,
+            ),
+            column(
+                "volume",
+                "The trading volume of the symbol in the given time period",
+                "decimal",
+                Some("shares, contracts or units of the base currency"),
+            ),
+            Column {
+                optional: true,
+                ..column(
+                    "transactions",
+                    "The number of transactions in the aggregate window",
+                    "integer",
+                    None,
+                )
+            },
+            Column {
+                optional: true,
+                ..column(
+                    "otc",
+                    "Whether this aggregate is for an OTC ticker, left out \
+                     if it isn't",
+                    "boolean",
+                    None,
+                )
+            },
+            Column {
+                optional: true,
+                ..column(
+                    "vwap",
+                    "The volume weighted average price",
+                    "decimal",
+                    price,
+                )
+            },
+        ]);
+        columns
+    }
+}
+
+/// The Unix Msec `timestamp` as an RFC 3339 datetime in UTC, e.g.
+/// `2024-01-02T14:30:00Z`
+fn rfc3339(timestamp: i64) -> Option<String> {
+    DateTime::from_timestamp_millis(timestamp)
+        .map(|datetime| datetime.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+/// Turns records into bytes for the files [`Service`](crate::service::Service)
+/// writes, so output formats can be added without touching the service.
+pub trait RecordSerializer: Send + Sync {
+    /// The extension of the files this serializer writes, without the dot
+    fn extension(&self) -> &str;
+
+    /// Write whatever has to come before the first record of a file, like a
+    /// header
+    fn begin(
+        &self,
+        _first: &AggregateRecord,
+        _out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize(
+        &self,
+        record: &AggregateRecord,
+        out: &mut dyn Write,
+    ) -> Result<(), Error>;
+
+    /// Write a batch of records. Override this if serializing many records
+    /// at once is cheaper than one at a time
+    fn serialize_all(
+        &self,
+        records: &[AggregateRecord],
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        for (i, record) in records.iter().enumerate() {
+            if i > 0 {
+                out.write_all(self.separator())
+                    .map_err(error::FileIo::FileWrite)?;
+            }
+            self.serialize(record, out)?;
+        }
+        Ok(())
+    }
+
+    /// What goes between two records, like a comma
+    fn separator(&self) -> &[u8] {
+        b""
+    }
+
+    /// What every file ends with, like a closing bracket. It's written after
+    /// every batch and removed again before the next one, so the file is
+    /// complete even if a run is interrupted
+    fn footer(&self) -> &[u8] {
+        b""
+    }
+
+    /// Write what a file without any records should contain, for formats
+    /// where an empty file isn't valid
+    fn empty(&self, _out: &mut dyn Write) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// An encoder to write files with instead, for formats that can't be
+    /// appended to because they end with an index of everything before it,
+    /// like Parquet. Files in these formats are written in full whenever
+    /// records are added to them, starting with the records already there
+    fn encoder(&self) -> Option<Box<dyn RecordEncoder>> {
+        None
+    }
+}
+
+/// Encodes a whole file batch by batch, see [`RecordSerializer::encoder`]
+pub trait RecordEncoder: Send {
+    /// Start the file in `out`
+    fn begin(&mut self, out: File) -> Result<(), Error>;
+
+    fn write(&mut self, records: &[AggregateRecord]) -> Result<(), Error>;
+
+    /// Write the end of the file
+    fn finish(self: Box<Self>) -> Result<(), Error>;
+}
+
+/// Writes records as CSV, with a header row at the top of each file. Every
+/// row ends with `\n`, including the last one unless
+/// [`SerializeOptions::trailing_newline`] is turned off
+#[derive(Default)]
+pub struct CsvSerializer {
+    pub options: SerializeOptions,
+}
+
+impl RecordSerializer for CsvSerializer {
+    fn extension(&self) -> &str {
+        "csv"
+    }
+
+    fn begin(
+        &self,
+        first: &AggregateRecord,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        // The optional fields of a record are left out when they're empty,
+        // so the header depends on the first record written
+        let mut buffer = WriterBuilder::new()
+            .quote_style(self.options.quoting.into())
+            .delimiter(self.options.delimiter)
+            .from_writer(Vec::new());
+        buffer
+            .serialize(OutputRecord::csv(first, &self.options))
+            .map_err(error::FileIo::Csv)?;
+        let buffer = buffer
+            .into_inner()
+            .map_err(|e| error::FileIo::FileWrite(e.into_error()))?;
+        let header_end = buffer
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(buffer.len(), |end| end + 1);
+        if self.options.bom {
+            out.write_all(UTF8_BOM).map_err(error::FileIo::FileWrite)?;
+        }
+        out.write_all(&buffer[..header_end])
+            .map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+
+    fn serialize(
+        &self,
+        record: &AggregateRecord,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        self.serialize_all(std::slice::from_ref(record), out)
+    }
+
+    fn serialize_all(
+        &self,
+        records: &[AggregateRecord],
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let mut writer = WriterBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .quote_style(self.options.quoting.into())
+            .delimiter(self.options.delimiter)
+            .from_writer(Vec::new());
+        for record in records {
+            writer
+                .serialize(OutputRecord::csv(record, &self.options))
+                .map_err(error::FileIo::Csv)?;
+        }
+        let mut buffer = writer
+            .into_inner()
+            .map_err(|e| error::FileIo::FileWrite(e.into_error()))?;
+        if !self.options.trailing_newline && buffer.ends_with(b"\n") {
+            buffer.pop();
+        }
+        out.write_all(&buffer).map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+
+    fn separator(&self) -> &[u8] {
+        // Without a trailing newline, the last row written so far needs one
+        // before the next batch
+        if self.options.trailing_newline {
+            b""
+        } else {
+            b"\n"
+        }
+    }
+}
+
+/// Writes records as newline delimited JSON, one object per line
+#[derive(Default)]
+pub struct JsonSerializer {
+    pub options: SerializeOptions,
+}
+
+impl RecordSerializer for JsonSerializer {
+    fn extension(&self) -> &str {
+        "ndjson"
+    }
+
+    fn serialize(
+        &self,
+        record: &AggregateRecord,
+        mut out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        serde_json::to_writer(
+            &mut out,
+            &OutputRecord::new(record, &self.options),
+        )?;
+        out.write_all(b"\n").map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+}
+
+/// Writes records as a single JSON array
+#[derive(Default)]
+pub struct JsonArraySerializer {
+    pub options: SerializeOptions,
+}
+
+impl RecordSerializer for JsonArraySerializer {
+    fn extension(&self) -> &str {
+        "json"
+    }
+
+    fn begin(
+        &self,
+        _first: &AggregateRecord,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        out.write_all(b"[\n").map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+
+    fn serialize(
+        &self,
+        record: &AggregateRecord,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        serde_json::to_writer(out, &OutputRecord::new(record, &self.options))?;
+        Ok(())
+    }
+
+    fn separator(&self) -> &[u8] {
+        b",\n"
+    }
+
+    fn footer(&self) -> &[u8] {
+        b"\n]\n"
+    }
+
+    fn empty(&self, out: &mut dyn Write) -> Result<(), Error> {
+        out.write_all(b"[]\n").map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+}
+
+/// The number of decimal places of the decimal columns of Parquet files
+const PARQUET_SCALE: u32 = 10;
+
+/// The columns of Parquet files, in the order they're written
+static PARQUET_SCHEMA: LazyLock<Arc<Type>> = LazyLock::new(|| {
+    let schema = "message aggregate {
+        required int64 timestamp (TIMESTAMP(MILLIS, true));
+        required fixed_len_byte_array(16) open (DECIMAL(38, 10));
+        required fixed_len_byte_array(16) high (DECIMAL(38, 10));
+        required fixed_len_byte_array(16) low (DECIMAL(38, 10));
+        required fixed_len_byte_array(16) close (DECIMAL(38, 10));
+        required fixed_len_byte_array(16) volume (DECIMAL(38, 10));
+        optional int64 transactions;
+        optional boolean otc;
+        optional fixed_len_byte_array(16) vwap (DECIMAL(38, 10));
+    }";
+    Arc::new(parse_message_type(schema).expect("valid schema"))
+});
+
+/// Writes records as Parquet files compressed with Snappy, or Zstandard with
+/// a [`compression_level`](SerializeOptions::compression_level), with a row
+/// group for each batch of records. Prices, volumes and VWAPs are
+/// `DECIMAL(38, 10)`s and timestamps are UTC `TIMESTAMP(MILLIS)`s, which
+/// pandas and polars read as decimals and datetimes.
+///
+/// Written directly, each batch becomes a whole Parquet file of its own
+#[derive(Default)]
+pub struct ParquetSerializer {
+    pub options: SerializeOptions,
+}
+
+impl RecordSerializer for ParquetSerializer {
+    fn extension(&self) -> &str {
+        "parquet"
+    }
+
+    fn serialize(
+        &self,
+        record: &AggregateRecord,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        self.serialize_all(std::slice::from_ref(record), out)
+    }
+
+    fn serialize_all(
+        &self,
+        records: &[AggregateRecord],
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let compression = self.options.compression()?;
+        let mut writer = parquet_writer(Vec::new(), compression)?;
+        write_row_group(&mut writer, records)?;
+        let buffer = writer.into_inner().map_err(error::FileIo::from)?;
+        out.write_all(&buffer).map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+
+    fn encoder(&self) -> Option<Box<dyn RecordEncoder>> {
+        Some(Box::new(ParquetEncoder {
+            options: self.options.clone(),
+            writer: None,
+        }))
+    }
+}
+
+struct ParquetEncoder {
+    options: SerializeOptions,
+    writer: Option<SerializedFileWriter<BufWriter<File>>>,
+}
+
+impl RecordEncoder for ParquetEncoder {
+    fn begin(&mut self, out: File) -> Result<(), Error> {
+        let compression = self.options.compression()?;
+        self.writer = Some(parquet_writer(BufWriter::new(out), compression)?);
+        Ok(())
+    }
+
+    fn write(&mut self, records: &[AggregateRecord]) -> Result<(), Error> {
+        let writer = self.writer.as_mut().expect("begun");
+        write_row_group(writer, records)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        let writer = self.writer.expect("begun");
+        writer
+            .into_inner()
+            .map_err(error::FileIo::from)?
+            .flush()
+            .map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+}
+
+fn parquet_writer<W: Write + Send>(
+    out: W,
+    compression: Compression,
+) -> Result<SerializedFileWriter<W>, error::FileIo> {
+    let properties = WriterProperties::builder()
+        .set_compression(compression)
+        .build();
+    Ok(SerializedFileWriter::new(
+        out,
+        PARQUET_SCHEMA.clone(),
+        Arc::new(properties),
+    )?)
+}
+
+/// Write `records` as a row group of `writer`, with the columns of
+/// [`PARQUET_SCHEMA`]
+fn write_row_group<W: Write + Send>(
+    writer: &mut SerializedFileWriter<W>,
+    records: &[AggregateRecord],
+) -> Result<(), Error> {
+    let decimals = |field: fn(&AggregateRecord) -> &Decimal| {
+        records
+            .iter()
+            .map(|record| to_parquet_decimal(field(record)))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let timestamps: Vec<_> =
+        records.iter().map(|record| record.timestamp).collect();
+    let open = decimals(|record| &record.open)?;
+    let high = decimals(|record| &record.high)?;
+    let low = decimals(|record| &record.low)?;
+    let close = decimals(|record| &record.close)?;
+    let volume = decimals(|record| &record.volume)?;
+    let (transactions, transactions_defined) = optional(records, |record| {
+        record
+            .transactions
+            .map(|n| i64::try_from(n).unwrap_or(i64::MAX))
+    });
+    let (otc, otc_defined) = optional(records, |record| record.otc);
+    let (vwap, vwap_defined) = optional(records, |record| record.vwap);
+    let vwap = vwap
+        .iter()
+        .map(to_parquet_decimal)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut row_group = writer.next_row_group().map_err(error::FileIo::from)?;
+    write_column::<Int64Type, _>(&mut row_group, &timestamps, None)?;
+    for prices in [open, high, low, close, volume] {
+        write_column::<FixedLenByteArrayType, _>(
+            &mut row_group,
+            &prices,
+            None,
+        )?;
+    }
+    write_column::<Int64Type, _>(
+        &mut row_group,
+        &transactions,
+        Some(&transactions_defined),
+    )?;
+    write_column::<BoolType, _>(&mut row_group, &otc, Some(&otc_defined))?;
+    write_column::<FixedLenByteArrayType, _>(
+        &mut row_group,
+        &vwap,
+        Some(&vwap_defined),
+    )?;
+    row_group.close().map_err(error::FileIo::from)?;
+    Ok(())
+}
+
+/// The values of an optional column and its definition levels, 1 for each
+/// record that has a value and 0 for each one that doesn't
+fn optional<T>(
+    records: &[AggregateRecord],
+    field: impl Fn(&AggregateRecord) -> Option<T>,
+) -> (Vec<T>, Vec<i16>) {
+    let values: Vec<_> = records.iter().map(field).collect();
+    let defined = values.iter().map(|value| i16::from(value.is_some()));
+    let defined = defined.collect();
+    (values.into_iter().flatten().collect(), defined)
+}
+
+fn write_column<T: DataType, W: Write + Send>(
+    row_group: &mut SerializedRowGroupWriter<'_, W>,
+    values: &[T::T],
+    defined: Option<&[i16]>,
+) -> Result<(), error::FileIo> {
+    let mut column = row_group.next_column()?.expect("column in the schema");
+    column.typed::<T>().write_batch(values, defined, None)?;
+    column.close()?;
+    Ok(())
+}
+
+/// `value` as the unscaled big-endian bytes of a `DECIMAL(38, 10)`
+fn to_parquet_decimal(
+    value: &Decimal,
+) -> Result<FixedLenByteArray, error::FileIo> {
+    let rounded = value.round_dp(PARQUET_SCALE);
+    rounded
+        .mantissa()
+        .checked_mul(10_i128.pow(PARQUET_SCALE - rounded.scale()))
+        .filter(|unscaled| unscaled.unsigned_abs() < 10_u128.pow(38))
+        .map(|unscaled| unscaled.to_be_bytes().to_vec().into())
+        .ok_or_else(|| error::FileIo::DecimalOutOfRange(value.to_string()))
+}
+
+/// Writes batches of records to a file with a [`RecordSerializer`]
+pub(crate) struct StreamWriter<'a> {
+    out: BufWriter<File>,
+    serializer: &'a dyn RecordSerializer,
+    /// Whether no records have been written to the file yet
+    empty: bool,
+}
+
+/// Writes batches of records to a file with a [`RecordEncoder`]. The file is
+/// written next to the one at `path` and only replaces it when it's finished,
+/// so an interrupted run leaves the file the way it was
+pub(crate) struct EncodedWriter {
+    encoder: Box<dyn RecordEncoder>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+}
+
+/// Writes batches of records to a file, one way or another depending on
+/// the format
+pub(crate) enum RecordWriter<'a> {
+    Stream(StreamWriter<'a>),
+    Encoded(EncodedWriter),
+}
+
+impl<'a> RecordWriter<'a> {
+    /// Append to the file at `path`, creating it if it doesn't exist
+    pub(crate) fn append(
+        path: &Path,
+        serializer: &'a dyn RecordSerializer,
+    ) -> Result<Self, Error> {
+        match serializer.encoder() {
+            Some(encoder) => {
+                Ok(Self::Encoded(EncodedWriter::new(path, encoder, true)?))
+            }
+            None => Ok(Self::Stream(StreamWriter::append(path, serializer)?)),
+        }
+    }
+
+    /// Create the file at `path`, replacing it if it already exists
+    pub(crate) fn create(
+        path: &Path,
+        serializer: &'a dyn RecordSerializer,
+    ) -> Result<Self, Error> {
+        match serializer.encoder() {
+            Some(encoder) => {
+                Ok(Self::Encoded(EncodedWriter::new(path, encoder, false)?))
+            }
+            None => Ok(Self::Stream(StreamWriter::create(path, serializer)?)),
+        }
+    }
+
+    /// Write `records`, the next batch of the file
+    pub(crate) fn write(
+        &mut self,
+        records: &[AggregateRecord],
+    ) -> Result<(), Error> {
+        match self {
+            Self::Stream(writer) => writer.write(records),
+            Self::Encoded(writer) => writer.write(records),
+        }
+    }
+
+    /// Finish the file
+    pub(crate) fn finish(self) -> Result<(), Error> {
+        match self {
+            Self::Stream(writer) => writer.finish(),
+            Self::Encoded(writer) => writer.finish(),
+        }
+    }
+}
+
+impl EncodedWriter {
+    /// The most records to put in a single row group when writing the
+    /// records already in a file
+    const ROW_GROUP_LEN: usize = 100_000;
+
+    /// Start writing the file at `path`. The file is created if it doesn't
+    /// exist yet, so it can be given its permissions straight away
+    fn new(
+        path: &Path,
+        mut encoder: Box<dyn RecordEncoder>,
+        keep_records: bool,
+    ) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(error::FileIo::CreateFile)?;
+        let len = file.metadata().map_err(error::FileIo::CreateFile)?.len();
+        // Everything already in the file has to be written again, there's no
+        // appending to it
+        let records = if keep_records && len > 0 {
+            reader::read_records(path, TimestampUnit::default())?
+        } else {
+            Vec::new()
+        };
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let tmp = File::create(&tmp_path).map_err(error::FileIo::CreateFile)?;
+        encoder.begin(tmp)?;
+        for records in records.chunks(Self::ROW_GROUP_LEN) {
+            encoder.write(records)?;
+        }
+        Ok(Self {
+            encoder,
+            path: path.to_path_buf(),
+            tmp_path,
+        })
+    }
+
+    fn write(&mut self, records: &[AggregateRecord]) -> Result<(), Error> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.encoder.write(records)
+    }
+
+    /// Finish the file and put it in place of the old one, with the same
+    /// permissions
+    fn finish(self) -> Result<(), Error> {
+        self.encoder.finish()?;
+        let permissions = fs::metadata(&self.path)
+            .map_err(error::FileIo::FileWrite)?
+            .permissions();
+        fs::set_permissions(&self.tmp_path, permissions)
+            .map_err(error::FileIo::FileWrite)?;
+        fs::rename(&self.tmp_path, &self.path)
+            .map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+}
+
+impl<'a> StreamWriter<'a> {
+    /// Append to the file at `path`, creating it if it doesn't exist
+    fn append(
+        path: &Path,
+        serializer: &'a dyn RecordSerializer,
+    ) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(path)
+            .map_err(error::FileIo::CreateFile)?;
+        Self::new(file, serializer)
+    }
+
+    /// Create the file at `path`, replacing it if it already exists
+    fn create(
+        path: &Path,
+        serializer: &'a dyn RecordSerializer,
+    ) -> Result<Self, Error> {
+        let file = File::create(path).map_err(error::FileIo::CreateFile)?;
+        Self::new(file, serializer)
+    }
+
+    fn new(
+        mut file: File,
+        serializer: &'a dyn RecordSerializer,
+    ) -> Result<Self, Error> {
+        let len = file.metadata().map_err(error::FileIo::CreateFile)?.len();
+        // A file that only has what `RecordSerializer::empty` wrote doesn't
+        // have any records yet
+        let mut placeholder = Vec::new();
+        serializer.empty(&mut placeholder)?;
+        let empty = len == 0
+            || (len == placeholder.len() as u64 && {
+                let mut contents = Vec::new();
+                file.read_to_end(&mut contents)
+                    .map_err(error::FileIo::ReadFile)?;
+                contents == placeholder
+            });
+        if empty {
+            file.set_len(0).map_err(error::FileIo::FileWrite)?;
+        }
+        Ok(Self {
+            out: BufWriter::new(file),
+            serializer,
+            empty,
+        })
+    }
+
+    /// Write and flush `records`
+    fn write(&mut self, records: &[AggregateRecord]) -> Result<(), Error> {
+        let Some(first) = records.first() else {
+            return Ok(());
+        };
+        let footer = self.serializer.footer();
+        if self.empty {
+            self.serializer.begin(first, &mut self.out)?;
+            self.empty = false;
+        } else {
+            if !footer.is_empty() {
+                let file = self.out.get_ref();
+                let len =
+                    file.metadata().map_err(error::FileIo::FileWrite)?.len();
+                file.set_len(len.saturating_sub(footer.len() as u64))
+                    .map_err(error::FileIo::FileWrite)?;
+            }
+            self.out
+                .write_all(self.serializer.separator())
+                .map_err(error::FileIo::FileWrite)?;
+        }
+        self.serializer.serialize_all(records, &mut self.out)?;
+        self.out
+            .write_all(footer)
+            .map_err(error::FileIo::FileWrite)?;
+        self.out.flush().map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+
+    /// Finish the file, which only matters for files without any records
+    fn finish(mut self) -> Result<(), Error> {
+        if self.empty {
+            self.serializer.empty(&mut self.out)?;
+        }
+        self.out.flush().map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use rust_decimal::Decimal;
+
+    use super::{
+        CsvSerializer, JsonArraySerializer, JsonSerializer, ParquetSerializer,
+        RecordSerializer, RecordWriter, SerializeOptions, UTF8_BOM,
+    };
+    use crate::{
+        error::{self, Error},
+        reader::read_records,
+        test_util::TempOutput,
+        types::{AggregateRecord, CsvQuoting, TimestampUnit},
+    };
+
+    fn records() -> Vec<AggregateRecord> {
+        (1..=2)
+            .map(|i| AggregateRecord {
+                timestamp: 1_704_205_800_000 + i * 60_000,
+                open: Decimal::from(i),
+                high: Decimal::from(i),
+                low: Decimal::from(i),
+                close: Decimal::from(i),
+                volume: Decimal::from(10 * i),
+                transactions: Some(3),
+                otc: None,
+                vwap: None,
+            })
+            .collect()
+    }
+
+    fn serialize(serializer: &dyn RecordSerializer) -> String {
+        let records = records();
+        let mut out = Vec::new();
+        serializer.begin(&records[0], &mut out).unwrap();
+        serializer.serialize_all(&records, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_has_a_single_header() {
+        assert_eq!(
+            serialize(&CsvSerializer::default()),
+            "timestamp,open,high,low,close,volume,transactions\n\
+             1704205860000,1,1,1,1,10,3\n\
+             1704205920000,2,2,2,2,20,3\n"
+        );
+    }
+
+    #[test]
+    fn timestamps_are_converted_to_the_configured_unit() {
+        let serializer = |timestamp_unit| CsvSerializer {
+            options: SerializeOptions {
+                timestamp_unit,
+                ..Default::default()
+            },
+        };
+        let first_field = |output: String| {
+            output
+                .lines()
+                .nth(1)
+                .unwrap()
+                .split(',')
+                .next()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            first_field(serialize(&serializer(TimestampUnit::Seconds))),
+            "1704205860"
+        );
+        assert_eq!(
+            first_field(serialize(&serializer(TimestampUnit::Rfc3339))),
+            "2024-01-02T14:31:00Z"
+        );
+    }
+
+    #[test]
+    fn datetimes_can_be_added_next_to_the_timestamps() {
+        let serializer = CsvSerializer {
+            options: SerializeOptions {
+                with_datetime: true,
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            serialize(&serializer),
+            "timestamp,datetime,open,high,low,close,volume,transactions\n\
+             1704205860000,2024-01-02T14:31:00Z,1,1,1,1,10,3\n\
+             1704205920000,2024-01-02T14:32:00Z,2,2,2,2,20,3\n"
+        );
+    }
+
+    #[test]
+    fn columns_are_the_ones_written() {
+        let options = SerializeOptions {
+            with_datetime: true,
+            with_date_and_time: true,
+            ..Default::default()
+        };
+        let record = AggregateRecord {
+            otc: Some(true),
+            vwap: Some(Decimal::ONE),
+            ..records()[0].clone()
+        };
+        let mut out = Vec::new();
+        let serializer = CsvSerializer {
+            options: options.clone(),
+        };
+        serializer.begin(&record, &mut out).unwrap();
+        let header = String::from_utf8(out).unwrap();
+        let names: Vec<_> =
+            options.columns().iter().map(|column| column.name).collect();
+        assert_eq!(header.trim_end(), names.join(","));
+    }
+
+    #[test]
+    fn dates_and_times_can_be_added_next_to_the_timestamps() {
+        let serializer = CsvSerializer {
+            options: SerializeOptions {
+                with_date_and_time: true,
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            serialize(&serializer),
+            "timestamp,date,time,open,high,low,close,volume,transactions\n\
+             1704205860000,2024-01-02,14:31:00,1,1,1,1,10,3\n\
+             1704205920000,2024-01-02,14:32:00,2,2,2,2,20,3\n"
+        );
+    }
+
+    #[test]
+    fn json_has_one_record_per_line() {
+        let output = serialize(&JsonSerializer::default());
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let record: AggregateRecord = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(record.timestamp, 1_704_205_920_000);
+    }
+
+    #[test]
+    fn custom_serializer() {
+        struct Closes;
+        impl RecordSerializer for Closes {
+            fn extension(&self) -> &str {
+                "txt"
+            }
+
+            fn serialize(
+                &self,
+                record: &AggregateRecord,
+                out: &mut dyn Write,
+            ) -> Result<(), Error> {
+                writeln!(
+                    out,
+                    "{} closed at {}",
+                    record.timestamp, record.close
+                )
+                .map_err(error::FileIo::FileWrite)?;
+                Ok(())
+            }
+        }
+
+        assert_eq!(
+            serialize(&Closes),
+            "1704205860000 closed at 1\n1704205920000 closed at 2\n"
+        );
+    }
+
+    #[test]
+    fn json_array_stays_valid_across_batches() {
+        let dir = TempOutput::new("array");
+        let path = dir.join("array.json");
+        let serializer = JsonArraySerializer::default();
+        let parse = || -> Vec<AggregateRecord> {
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap()
+        };
+
+        RecordWriter::append(&path, &serializer)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert!(parse().is_empty());
+
+        let mut writer = RecordWriter::append(&path, &serializer).unwrap();
+        writer.write(&records()).unwrap();
+        assert_eq!(parse().len(), 2);
+        writer.write(&records()).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(parse().len(), 4);
+
+        // appending in a later run continues the same array
+        let mut writer = RecordWriter::append(&path, &serializer).unwrap();
+        writer.write(&records()[..1]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(parse().len(), 5);
+    }
+
+    #[test]
+    fn trailing_newline_follows_the_options() {
+        let dir = TempOutput::new("newline");
+        for trailing_newline in [true, false] {
+            let path = dir.join(format!("newline-{trailing_newline}.csv"));
+            let serializer = CsvSerializer {
+                options: SerializeOptions {
+                    trailing_newline,
+                    ..Default::default()
+                },
+            };
+            let mut writer = RecordWriter::append(&path, &serializer).unwrap();
+            writer.write(&records()).unwrap();
+            writer.write(&records()).unwrap();
+            writer.finish().unwrap();
+            let contents = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(contents.ends_with('\n'), trailing_newline);
+            assert_eq!(contents.lines().count(), 5);
+            assert!(contents.lines().all(|line| !line.is_empty()));
+        }
+    }
+
+    #[test]
+    fn csv_files_can_start_with_a_bom() {
+        let dir = TempOutput::new("bom");
+        let path = dir.join("bom.csv");
+        let serializer = CsvSerializer {
+            options: SerializeOptions {
+                bom: true,
+                ..Default::default()
+            },
+        };
+        for _ in 0..2 {
+            let mut writer = RecordWriter::append(&path, &serializer).unwrap();
+            writer.write(&records()).unwrap();
+            writer.finish().unwrap();
+        }
+        let contents = std::fs::read(&path).unwrap();
+        assert!(contents.starts_with(b"\xEF\xBB\xBFtimestamp,"));
+        assert_eq!(contents.windows(3).filter(|w| *w == UTF8_BOM).count(), 1);
+        let read = read_records(&path, TimestampUnit::Millis).unwrap();
+        assert_eq!(read.len(), 4);
+    }
+
+    #[test]
+    fn csv_fields_can_all_be_quoted() {
+        let serializer = CsvSerializer {
+            options: SerializeOptions {
+                quoting: CsvQuoting::Always,
+                ..Default::default()
+            },
+        };
+        let csv = serialize(&serializer);
+        assert!(csv.starts_with(
+            "\"timestamp\",\"open\",\"high\",\"low\",\"close\",\"volume\",\"transactions\"\n\
+             \"1704205860000\",\"1\",\"1\",\"1\",\"1\",\"10\",\"3\"\n"
+        ));
+
+        let dir = TempOutput::new("quoted");
+        let path = dir.join("quoted.csv");
+        std::fs::write(&path, csv).unwrap();
+        let read = read_records(&path, TimestampUnit::Millis).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].volume, Decimal::from(20));
+        assert_eq!(read[1].transactions, Some(3));
+    }
+
+    #[test]
+    fn higher_compression_levels_make_smaller_parquet_files() {
+        // A random walk, which compresses but not trivially
+        let mut price = 100_000_i64;
+        let records: Vec<_> = (0..5_000)
+            .map(|i| {
+                price += (i * 7_919 % 13) - 6;
+                AggregateRecord {
+                    timestamp: 1_704_205_800_000 + i * 60_000,
+                    open: Decimal::new(price, 2),
+                    high: Decimal::new(price + i % 5, 2),
+                    low: Decimal::new(price - i % 3, 2),
+                    close: Decimal::new(price + i % 4 - 2, 2),
+                    volume: Decimal::from(i * 31 % 997),
+                    transactions: Some((i % 50) as usize),
+                    otc: None,
+                    vwap: None,
+                }
+            })
+            .collect();
+        let size = |compression_level| {
+            let serializer = ParquetSerializer {
+                options: SerializeOptions {
+                    compression_level,
+                    ..Default::default()
+                },
+            };
+            let mut out = Vec::new();
+            serializer
+                .serialize_all(&records, &mut out)
+                .map(|()| out.len())
+        };
+        let fastest = size(Some(1)).unwrap();
+        let smallest = size(Some(22)).unwrap();
+        assert!(smallest < fastest, "{smallest} isn't below {fastest}");
+        assert!(matches!(
+            size(Some(23)),
+            Err(Error::Init(error::Init::InvalidCompressionLevel(23)))
+        ));
+    }
+
+    #[test]
+    fn parquet_round_trips_across_runs() {
+        let dir = TempOutput::new("round-trip");
+        let path = dir.join("round-trip.parquet");
+        let serializer = ParquetSerializer::default();
+        let mut first = records();
+        first[0].vwap = Some("1.2345678901".parse().unwrap());
+        first[1].open = "-0.5".parse().unwrap();
+        first[1].otc = Some(true);
+
+        let mut writer = RecordWriter::append(&path, &serializer).unwrap();
+        writer.write(&first).unwrap();
+        writer.finish().unwrap();
+        let mut writer = RecordWriter::append(&path, &serializer).unwrap();
+        writer.write(&records()[..1]).unwrap();
+        writer.finish().unwrap();
+        let read = read_records(&path, TimestampUnit::Rfc3339).unwrap();
+
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0].timestamp, first[0].timestamp);
+        assert_eq!(read[0].vwap, first[0].vwap);
+        assert_eq!(read[1].open, first[1].open);
+        assert_eq!(read[1].otc, Some(true));
+        assert_eq!(read[1].volume, Decimal::from(20));
+        assert_eq!(read[2].transactions, Some(3));
+        assert_eq!(read[2].vwap, None);
+    }
+}
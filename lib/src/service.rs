@@ -1,109 +1,1462 @@
 use std::{
-    fs::{File, OpenOptions},
-    path::PathBuf,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::File,
+    future::Future,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, Once,
+    },
     time::Duration,
 };
 
 use crate::{
+    adjust, calendar,
+    checkpoint::Checkpoint,
     client::Client,
     config::Config,
     error::{self, Error},
+    events::{Event, EventWriter},
+    gaps,
+    http_sink::HttpSink,
+    manifest::{Manifest, Status, TickerRun},
+    metadata_cache::MetadataCache,
+    probe::{self, Span, SpanSupport, Support},
+    reader,
+    resample::{self, Resampler},
+    retry::{RetryPolicy, RetryState},
+    serializer::{
+        Column, RecordSerializer, RecordWriter, SerializeOptions,
+        CSV_DELIMITERS, UTF8_BOM, ZSTD_LEVELS,
+    },
+    stats::Summary,
     types::{
-        AggregateRecord, AggregateRequest, AggregateRequestBuilder, Timespan,
+        AggregateRecord, AggregateRequest, AggregateRequestBuilder, Coverage,
+        DateRange, DuplicateCheck, Estimate, FlatFileRecord, Market,
+        MonotonicCheck, PartitionBy, SortOrder, Split, TickerDetails, Timespan,
+        TimestampUnit, WorkItem,
     },
 };
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Datelike, Months, NaiveDate, TimeDelta, Utc};
+use console::Term;
 use csv::WriterBuilder;
-use futures::stream::{self, BoxStream, StreamExt};
-use indicatif::{ProgressBar, ProgressStyle};
-use tokio::{fs, time::sleep};
+use futures::{
+    future,
+    stream::{self, BoxStream, Stream, StreamExt, TryStreamExt},
+};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use serde::Serialize;
+use tokio::{
+    fs,
+    sync::{mpsc, watch, Semaphore, SemaphorePermit},
+    time::sleep,
+};
 use tracing::{debug, error, info, instrument, warn};
 
-const CONCURRENCY_LIMIT: usize = 10;
+const CHECKPOINT_FILE: &str = ".checkpoint.json";
+const DICTIONARY_FILE: &str = "dictionary.json";
+
+/// Changes each record fetched before it's written, see
+/// [`Service::with_transform`]
+pub type Transform = dyn Fn(AggregateRecord) -> AggregateRecord + Send + Sync;
 
 pub struct Service {
     client: Client,
     config: Config,
+    /// The tickers of the run, shuffled and cut down to `max_tickers` once so
+    /// that planning, fetching and checking the success rate go by the same
+    /// ones even without a `seed`
+    tickers: Vec<String>,
+    /// Limits the number of requests in flight at once, across all tickers
+    requests: Arc<Semaphore>,
+    /// Makes sure the permits held back for the ramp-up are only added once
+    ramp_up: Once,
+    checkpoint: tokio::sync::Mutex<Checkpoint>,
+    /// Every ticker is written once with each of these, to its own file
+    serializers: Vec<Arc<dyn RecordSerializer>>,
+    /// What the serializers write records with, for anything else that
+    /// writes records
+    options: SerializeOptions,
+    /// Shared with the client so retries can be shown while they back off
+    progress_bar: ProgressBar,
+    /// Set once a request runs out of retries because of Polygon's rate
+    /// limit, so the advice on what to do about it is only logged once
+    rate_limited: AtomicBool,
+    /// The splits of each ticker fetched so far, to adjust prices as of
+    /// `adjusted_as_of` with
+    splits: Mutex<HashMap<String, Arc<Vec<Split>>>>,
+    /// Whether new requests wait until the fetch is resumed
+    paused: watch::Sender<bool>,
+    /// How many requests have been sent for the pages of each ticker, for
+    /// `max_requests_per_ticker`
+    ticker_requests: Mutex<HashMap<String, Arc<AtomicU32>>>,
+    /// The tickers still saved in folders named after them as is, so the
+    /// warning about renaming them is only logged once
+    unsanitized_dirs: Mutex<HashSet<String>>,
+    /// Where to write the progress of the run as JSON lines, if anywhere
+    events: Option<EventWriter>,
+    /// How each ticker of the current run went
+    manifest: tokio::sync::Mutex<Manifest>,
+    /// The ticker details looked up in this and previous runs, if they're
+    /// kept in `metadata_cache`
+    metadata: Mutex<MetadataCache>,
+    transform: Option<Box<Transform>>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<crate::metrics::MetricsPusher>,
 }
 
 impl Service {
     pub fn new(config: Config, polygon_api_key: &str) -> Result<Self, Error> {
-        let client = Client::new(polygon_api_key)?;
-        Ok(Self { client, config })
+        let client = Client::new(polygon_api_key)?
+            .with_retry_policy(RetryPolicy {
+                retry_server_errors: config.retry_server_errors,
+                max_retries: config.max_retries,
+                retry_messages: config.retry_messages.clone(),
+                ..Default::default()
+            })
+            .with_aggregates_version(&config.aggregates_version)?
+            .with_schema_check(config.schema_check)
+            .with_coalescing(config.coalesce_requests)
+            .with_pinned_certificates(&config.pinned_certificates)?
+            .with_http_version(config.http_version)?;
+        let client = match &config.base_url {
+            Some(base_url) => client.with_base_url(base_url)?,
+            None => client,
+        };
+        let client = match config.requests_per_minute {
+            Some(requests_per_minute) => {
+                client.with_rate_limit(requests_per_minute)
+            }
+            None => client,
+        };
+        Self::new_with_client(config, client)
+    }
+
+    /// Create a service that sends all of its requests with `client`, for
+    /// when the client needs to be configured beyond what [`Client::new`]
+    /// does
+    pub fn new_with_client(
+        mut config: Config,
+        client: Client,
+    ) -> Result<Self, Error> {
+        if config.concurrency == 0 {
+            return Err(error::Init::ZeroConcurrency.into());
+        }
+        if config.write_buffer_size == 0 {
+            return Err(error::Init::ZeroWriteBufferSize.into());
+        }
+        if !CSV_DELIMITERS.contains(&config.csv_delimiter) {
+            return Err(
+                error::Init::InvalidCsvDelimiter(config.csv_delimiter).into()
+            );
+        }
+        if let Some(level) = config.compression_level {
+            if !ZSTD_LEVELS.contains(&level) {
+                return Err(error::Init::InvalidCompressionLevel(level).into());
+            }
+        }
+        if config.csv_decimal_comma && config.csv_delimiter == ',' {
+            return Err(error::Init::AmbiguousDecimalComma.into());
+        }
+        if let Some(rate) = config.min_success_rate {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(error::Init::InvalidSuccessRate(rate).into());
+            }
+        }
+        if !config.full_history {
+            let swap = config.swap_reversed_ranges;
+            check_range(&mut config.from, &mut config.to, swap)?;
+            for (from, to) in &mut config.extra_ranges {
+                check_range(from, to, swap)?;
+            }
+        }
+        // Without `resume` the checkpoint is only added to, so one that
+        // can't be read is started over instead of failing the run
+        let checkpoint = match Checkpoint::load(&checkpoint_path(&config)) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) if !config.resume => {
+                warn!(error = %e, "Can't read the checkpoint, starting a new one");
+                Checkpoint::default()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // A resumed run adds to the manifest of the run it picks up after
+        let manifest = if config.resume {
+            Manifest::load(&manifest_path(&config))?
+        } else {
+            Manifest::default()
+        };
+        let metadata = match &config.metadata_cache {
+            Some(path) => MetadataCache::load(path)?,
+            None => MetadataCache::default(),
+        };
+        let options = SerializeOptions {
+            timestamp_unit: config.timestamp_unit,
+            with_datetime: config.with_datetime,
+            with_date_and_time: config.with_date_and_time,
+            trailing_newline: config.csv_trailing_newline,
+            bom: config.csv_bom,
+            quoting: config.csv_quoting,
+            // Checked to be one of the ASCII delimiters above
+            delimiter: config.csv_delimiter as u8,
+            decimal_comma: config.csv_decimal_comma,
+            compression_level: config.compression_level,
+        };
+        let serializers = config
+            .formats
+            .iter()
+            .map(|format| format.serializer(options.clone()))
+            .collect();
+        if cfg!(not(unix)) && config.permissions.is_some() {
+            warn!("File permissions can only be set on Unix, ignoring them");
+        }
+        if config.warn_on_weekend_range && !config.full_history {
+            warn_on_ranges_without_trading_days(&config);
+        }
+        let events = config
+            .progress_events
+            .as_deref()
+            .map(EventWriter::create)
+            .transpose()?;
+        let mut tickers: Vec<_> =
+            config.tickers.iter().map(String::as_str).collect();
+        if config.shuffle {
+            shuffle(&mut tickers, config.seed);
+        }
+        if let Some(max) = config.max_tickers {
+            tickers.truncate(max);
+        }
+        let tickers = tickers.into_iter().map(String::from).collect();
+        let initial_permits = if config.ramp_up.is_some() {
+            1
+        } else {
+            config.concurrency
+        };
+        #[cfg(feature = "metrics")]
+        let metrics = config
+            .push_metrics
+            .as_deref()
+            .map(crate::metrics::MetricsPusher::new)
+            .transpose()?;
+        #[cfg(not(feature = "metrics"))]
+        if config.push_metrics.is_some() {
+            return Err(error::Init::MetricsDisabled.into());
+        }
+        let progress_bar = ProgressBar::hidden();
+        let client = client.with_on_retry({
+            let progress_bar = progress_bar.clone();
+            move |state| {
+                #[cfg(feature = "metrics")]
+                if matches!(state, RetryState::Backoff { .. }) {
+                    crate::metrics::retried();
+                }
+                show_retry_state(&progress_bar, state)
+            }
+        });
+        Ok(Self {
+            client,
+            config,
+            tickers,
+            requests: Arc::new(Semaphore::new(initial_permits)),
+            ramp_up: Once::new(),
+            checkpoint: tokio::sync::Mutex::new(checkpoint),
+            serializers,
+            options,
+            progress_bar,
+            rate_limited: AtomicBool::new(false),
+            splits: Mutex::default(),
+            paused: watch::Sender::new(false),
+            ticker_requests: Mutex::default(),
+            unsanitized_dirs: Mutex::default(),
+            events,
+            manifest: tokio::sync::Mutex::new(manifest),
+            metadata: Mutex::new(metadata),
+            transform: None,
+            #[cfg(feature = "metrics")]
+            metrics,
+        })
+    }
+
+    /// Reset the progress bar to `length` steps and start drawing it
+    fn start_progress(&self, length: u64) -> ProgressBar {
+        self.start_ramp_up();
+        self.progress_bar.set_style(style());
+        self.progress_bar.set_length(length);
+        self.progress_bar.reset();
+        self.progress_bar
+            .set_draw_target(progress_target(self.config.progress));
+        self.progress_bar.clone()
+    }
+
+    /// Move `progress_bar` on by `chunks`, fetched for `ticker`
+    fn advance(
+        &self,
+        progress_bar: &ProgressBar,
+        ticker: Option<&str>,
+        chunks: u64,
+    ) {
+        progress_bar.inc(chunks);
+        self.emit(Event::Progress {
+            ticker,
+            done: progress_bar.position(),
+            total: progress_bar.length().unwrap_or_default(),
+        });
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.emit(&event);
+        }
+    }
+
+    fn emit_error(
+        &self,
+        ticker: Option<&str>,
+        date: Option<NaiveDate>,
+        e: &Error,
+    ) {
+        self.emit(Event::Error {
+            ticker,
+            date,
+            message: e.to_string(),
+        });
+    }
+
+    fn emit_finished(&self, progress_bar: &ProgressBar) {
+        self.emit(Event::Finished {
+            done: progress_bar.position(),
+            total: progress_bar.length().unwrap_or_default(),
+            aborted: self.aborted(),
+        });
+    }
+
+    /// Save records with `serializer` instead of the built-in serializers of
+    /// the configured formats
+    pub fn with_serializer(
+        mut self,
+        serializer: impl RecordSerializer + 'static,
+    ) -> Self {
+        self.serializers = vec![Arc::new(serializer)];
+        self
+    }
+
+    /// Also save records with `serializer`, next to the files of the
+    /// serializers already configured
+    pub fn add_serializer(
+        mut self,
+        serializer: impl RecordSerializer + 'static,
+    ) -> Self {
+        self.serializers.push(Arc::new(serializer));
+        self
+    }
+
+    /// Pass every record fetched from the aggregates endpoint through
+    /// `transform` before doing anything else with it, e.g. to fill in the
+    /// `vwap` of markets Polygon doesn't have it for. Records are
+    /// transformed after being adjusted with `adjusted_as_of`, and before
+    /// they're written, checked or combined into daily bars, whatever they
+    /// are written to. The grouped daily bars aren't transformed
+    pub fn with_transform(
+        mut self,
+        transform: impl Fn(AggregateRecord) -> AggregateRecord
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.transform = Some(Box::new(transform));
+        self
+    }
+
+    /// The files the records of `request` are written to, one for each
+    /// serializer with a distinct extension. With `derive_daily`, intraday
+    /// requests also get a daily file for each serializer
+    fn sinks(&self, request: &AggregateRequest<'_>) -> Vec<Sink<'_>> {
+        let derive_daily = self.config.derive_daily
+            && matches!(
+                request.timespan,
+                Timespan::Second | Timespan::Minute | Timespan::Hour
+            );
+        let ticker_dir = self.ticker_dir(request.ticker);
+        let bars = request.timespan.with_multiplier(request.multiplier);
+        let mut sinks: Vec<Sink<'_>> = Vec::new();
+        for daily in [false, true] {
+            if daily && !derive_daily {
+                break;
+            }
+            for serializer in &self.serializers {
+                let name = if daily {
+                    format!("day-from-{bars}")
+                } else {
+                    bars.clone()
+                };
+                let key =
+                    format!("{ticker_dir}/{name}.{}", serializer.extension());
+                if sinks.iter().all(|sink| sink.key != key) {
+                    sinks.push(Sink {
+                        path: self.config.output_dir.join(&key),
+                        key,
+                        serializer: serializer.as_ref(),
+                        daily,
+                        partition: self.config.partition_by,
+                    });
+                }
+            }
+        }
+        sinks
+    }
+
+    /// How many records to ask for in each page
+    fn limit(&self) -> u32 {
+        match self.config.last.and_then(|last| u32::try_from(last).ok()) {
+            Some(last) => self.config.limit.min(last.max(1)),
+            None => self.config.limit,
+        }
+    }
+
+    /// The name of the folder `ticker` is saved in. A folder named after
+    /// the ticker as is, from before tickers were sanitized, is kept on
+    /// being used instead of starting over in the sanitized one
+    fn ticker_dir<'a>(&self, ticker: &'a str) -> Cow<'a, str> {
+        if self.config.raw_ticker_paths {
+            return Cow::Borrowed(ticker);
+        }
+        let sanitized = sanitize_path_segment(ticker);
+        let single_folder = matches!(
+            Path::new(ticker).components().collect::<Vec<_>>()[..],
+            [std::path::Component::Normal(_)]
+        );
+        if sanitized != ticker
+            && single_folder
+            && self.config.output_dir.join(ticker).is_dir()
+        {
+            let mut warned =
+                self.unsanitized_dirs.lock().expect("not poisoned");
+            if warned.insert(ticker.to_string()) {
+                warn!(
+                    ticker = %ticker,
+                    folder = %sanitized,
+                    "Using the ticker's folder from before folder names were sanitized, rename it to the sanitized name to move to the new layout"
+                );
+            }
+            return Cow::Borrowed(ticker);
+        }
+        sanitized
+    }
+
+    /// The tickers to fetch, in the order to fetch them in
+    fn tickers(&self) -> Vec<&str> {
+        self.tickers.iter().map(String::as_str).collect()
+    }
+
+    /// Everything [`fetch_data`](Self::fetch_data) would fetch, one work
+    /// item per ticker and range, with the items of each ticker next to each
+    /// other. With `full_history`, this looks up the listing date of every
+    /// ticker.
+    pub async fn plan(&self) -> Vec<WorkItem> {
+        let windows = windows(
+            (self.config.from, self.config.to),
+            &self.config.extra_ranges,
+        );
+        stream::iter(self.tickers())
+            .map(|ticker| {
+                let windows = &windows;
+                async move {
+                    let windows = if self.config.full_history {
+                        vec![(self.listing_date(ticker).await, Utc::now())]
+                    } else {
+                        windows.clone()
+                    };
+                    self.clamp_to_horizons(ticker, windows)
+                        .into_iter()
+                        .map(|(from, to)| WorkItem {
+                            ticker: ticker.to_string(),
+                            timespan: self.config.timespan,
+                            from,
+                            to,
+                        })
+                        .collect::<Vec<_>>()
+                }
+            })
+            .buffered(self.config.concurrency)
+            .concat()
+            .await
+    }
+
+    /// Find out which of `spans` the plan covers, with a request for a
+    /// single bar of the first ticker each, see [`probe::probe_spans`]
+    pub async fn probe_spans(&self, spans: &[Span]) -> Vec<SpanSupport> {
+        let Some(ticker) = self.tickers().first().copied() else {
+            let support = Support::Unknown("No ticker to probe with".into());
+            return spans
+                .iter()
+                .map(|&span| SpanSupport {
+                    span,
+                    support: support.clone(),
+                })
+                .collect();
+        };
+        let to = self.config.to.min(Utc::now());
+        probe::probe_spans(&self.client, ticker, spans, to).await
+    }
+
+    /// Estimate how many requests [`fetch_data`](Self::fetch_data) would
+    /// send for each ticker, without sending any. With `full_history`, the
+    /// listing dates aren't looked up, so every ticker is estimated from
+    /// `from` until now. Ranges already downloaded aren't taken into account
+    pub fn estimate(&self) -> Vec<Estimate> {
+        let windows = if self.config.full_history {
+            vec![(self.config.from, Utc::now())]
+        } else {
+            windows(
+                (self.config.from, self.config.to),
+                &self.config.extra_ranges,
+            )
+        };
+        let by_month =
+            self.config.ticker_parallelism > 1 && self.config.last.is_none();
+        let splits = i64::from(self.config.adjusted_as_of.is_some());
+        self.tickers()
+            .into_iter()
+            .map(|ticker| {
+                let lookups = splits
+                    + i64::from(
+                        self.config.full_history
+                            && self.cached_details(ticker).is_none(),
+                    );
+                let chunks = self
+                    .clamp_to_horizons(ticker, windows.clone())
+                    .into_iter()
+                    .flat_map(|(from, to)| {
+                        if by_month {
+                            monthly_ranges(from, to)
+                        } else {
+                            vec![(from, to)]
+                        }
+                    })
+                    .map(|(from, to)| {
+                        num_chunks(
+                            self.config.timespan,
+                            self.config.multiplier,
+                            from,
+                            to,
+                            self.limit(),
+                        )
+                    })
+                    .sum();
+                Estimate {
+                    ticker: ticker.to_string(),
+                    chunks,
+                    requests: chunks + lookups,
+                }
+            })
+            .collect()
+    }
+
+    /// Drop the parts of `windows` before the horizon of the market of
+    /// `ticker`, if any
+    fn clamp_to_horizons(
+        &self,
+        ticker: &str,
+        windows: Vec<DateRange>,
+    ) -> Vec<DateRange> {
+        match &self.config.horizons {
+            Some(horizons) => windows
+                .into_iter()
+                .filter_map(|window| clamp_to_horizon(horizons, ticker, window))
+                .collect(),
+            None => windows,
+        }
+    }
+
+    /// Fetch every ticker like [`fetch_data`](Self::fetch_data), but POST
+    /// the records to `sink` as they come in instead of writing them to
+    /// files. Nothing is checkpointed, and tickers that fail are logged and
+    /// skipped
+    #[instrument(skip_all)]
+    pub async fn fetch_to_http(&self, sink: &HttpSink) {
+        info!(
+            num_tickers = self.tickers().len(),
+            timespan = %self.config.timespan,
+            url = %sink.url(),
+            from = %self.config.from,
+            to = %self.config.to,
+            "Starting to fetch data..."
+        );
+        self.start_ramp_up();
+        let plan = self.plan().await;
+        let tickers = plan.chunk_by(|a, b| a.ticker == b.ticker);
+        drain(tickers, self.config.concurrency, |items| async move {
+            let ticker = items[0].ticker.as_str();
+            if let Err(e) = self.post_ticker(items, sink).await {
+                if !self.report_rate_limit(&e) {
+                    error!(error = %e, ticker = %ticker, "Encountered an error when processing a ticker");
+                }
+                self.emit_error(Some(ticker), None, &e);
+            }
+        })
+        .await;
+        info!("Finished fetching data!");
+    }
+
+    /// Fetch the items of the plan for a single ticker and POST their
+    /// records to `sink`, holding back records until there's a full batch
+    #[instrument(skip_all, err, fields(ticker = %items[0].ticker))]
+    async fn post_ticker(
+        &self,
+        items: &[WorkItem],
+        sink: &HttpSink,
+    ) -> Result<(), Error> {
+        let ticker = items[0].ticker.as_str();
+        let mut batch = Vec::new();
+        for item in items {
+            let request = self.request(ticker, item.from, item.to)?;
+            let mut pages = take_records(
+                self.get_aggregates(request).await,
+                self.config.last,
+            );
+            while let Some(records) = pages.try_next().await? {
+                batch.extend(records);
+                let full = batch.len() - batch.len() % sink.batch_size();
+                sink.send(ticker, &batch[..full], &self.options).await?;
+                batch.drain(..full);
+            }
+        }
+        sink.send(ticker, &batch, &self.options).await
     }
 
+    /// Fetch every ticker in the plan and save it to disk. Failed tickers
+    /// are logged and recorded in the manifest without stopping the run,
+    /// which only fails if fewer of them succeeded than `min_success_rate`
     #[instrument(skip_all)]
-    pub async fn fetch_data(&self) {
+    pub async fn fetch_data(&self) -> Result<(), Error> {
         info!(
-            num_tickers = self.config.tickers.len(),
+            num_tickers = self.tickers().len(),
             timespan = %self.config.timespan,
             output_dir = ?self.config.output_dir,
             from = %self.config.from,
             to = %self.config.to,
             "Starting to fetch data..."
         );
+        self.start_ramp_up();
+        let plan = self.start_manifest(self.plan().await).await;
+        if self.config.data_dictionary {
+            self.save_dictionary();
+        }
+
+        let num_chunks: i64 = plan
+            .iter()
+            .map(|item| {
+                num_chunks(
+                    item.timespan,
+                    self.config.multiplier,
+                    item.from,
+                    item.to,
+                    self.limit(),
+                )
+            })
+            .sum();
+        let progress_bar =
+            self.start_progress(u64::try_from(num_chunks).unwrap_or_default());
+        self.emit(Event::Started {
+            total: progress_bar.length().unwrap_or_default(),
+        });
+        // Workers take the next ticker off a shared queue as soon as they're
+        // done with their last one, so a few big tickers don't hold up the
+        // rest. All of a ticker's items go to the same worker since they're
+        // appended to the same file
+        let tickers = plan.chunk_by(|a, b| a.ticker == b.ticker);
+        drain(tickers, self.config.concurrency, |items| {
+            self.fetch_ticker(items, progress_bar.clone())
+        })
+        .await;
+
+        progress_bar.finish();
+        self.emit_finished(&progress_bar);
+        let success_rate = {
+            let mut manifest = self.manifest.lock().await;
+            manifest.finished = Some(Utc::now());
+            self.save_manifest(&manifest).await;
+            self.check_success_rate(&manifest)
+        };
+        if self.aborted() {
+            error!("Stopped fetching data because of Polygon's rate limit");
+        } else {
+            info!("Finished fetching data!");
+        }
+        success_rate
+    }
+
+    /// Check that at least `min_success_rate` of the tickers in `manifest`
+    /// succeeded, if set. Tickers fetched by a resumed run count too
+    fn check_success_rate(&self, manifest: &Manifest) -> Result<(), Error> {
+        let Some(min) = self.config.min_success_rate else {
+            return Ok(());
+        };
+        let tickers = self.tickers();
+        let runs: Vec<_> = manifest
+            .tickers
+            .iter()
+            .filter(|(ticker, _)| tickers.contains(&ticker.as_str()))
+            .map(|(_, run)| run)
+            .collect();
+        let succeeded = runs
+            .iter()
+            .filter(|run| run.status == Status::Success)
+            .count();
+        let total = runs.len();
+        if total > 0 && (succeeded as f64) < min * total as f64 {
+            error!(succeeded, total, min, "Too few tickers succeeded");
+            return Err(Error::LowSuccessRate {
+                succeeded,
+                total,
+                min,
+            });
+        }
+        Ok(())
+    }
+
+    /// Fetch the items of the plan for a single ticker, one after another,
+    /// and record how it went in the manifest
+    async fn fetch_ticker(&self, items: &[WorkItem], pb: ProgressBar) {
+        let mut run = TickerRun::new(items, self.config.multiplier);
+        #[cfg(feature = "metrics")]
+        {
+            let started = std::time::Instant::now();
+            let fetch = self.fetch_items(items, pb, &mut run);
+            let ((), retries) = crate::metrics::count_retries(fetch).await;
+            self.push_metrics(
+                &items[0].ticker,
+                started.elapsed(),
+                retries,
+                &run,
+            )
+            .await;
+        }
+        #[cfg(not(feature = "metrics"))]
+        self.fetch_items(items, pb, &mut run).await;
+        let mut manifest = self.manifest.lock().await;
+        manifest.tickers.insert(items[0].ticker.clone(), run);
+        self.save_manifest(&manifest).await;
+    }
+
+    async fn fetch_items(
+        &self,
+        items: &[WorkItem],
+        pb: ProgressBar,
+        run: &mut TickerRun,
+    ) {
+        let ticker = items[0].ticker.as_str();
+        info!(ticker = %ticker, "Fetching data for ticker");
+        for item in items {
+            let request = match self.request(ticker, item.from, item.to) {
+                Ok(request) => request,
+                Err(e) => {
+                    error!(error = %e, ticker = %ticker, "Encountered an error when building a request");
+                    self.emit_error(Some(ticker), None, &e);
+                    run.fail(&e);
+                    return;
+                }
+            };
+            if self.aborted() {
+                debug!(ticker = %ticker, "Skipping ticker, the run was aborted");
+                if run.status == Status::Success {
+                    run.status = Status::Skipped;
+                }
+                return;
+            }
+            // All the items of a ticker go to the same files
+            if self.config.overwrite && std::ptr::eq(item, &items[0]) {
+                if let Err(e) = self.truncate_sinks(&request).await {
+                    error!(error = %e, ticker = %ticker, "Encountered an error when truncating a ticker's files");
+                    run.fail(&e);
+                    self.emit_error(Some(ticker), None, &e.into());
+                    return;
+                }
+            }
+            let result =
+                self.save_aggregates_to_disk(request, pb.clone()).await;
+            match &result {
+                Ok(num_records) => run.records += num_records,
+                Err(e) => {
+                    self.emit_error(Some(ticker), None, e);
+                    run.fail(e);
+                }
+            }
+            match result {
+                Err(Error::TickerRequestLimit(max)) => {
+                    warn!(ticker = %ticker, max_requests = max, "Stopped fetching ticker, it reached its request limit");
+                    break;
+                }
+                Err(e) if !self.report_rate_limit(&e) => {
+                    error!(error = %e, ticker = %ticker, "Encountered an error when processing a ticker");
+                }
+                _ => {}
+            }
+        }
+        if self.config.validate {
+            let _result = self.validate(ticker);
+        }
+        info!(ticker = %ticker, "Finished fetching data for ticker");
+    }
+
+    /// Push the metrics of fetching `ticker` with `run`, if configured.
+    /// Failures are logged rather than failing the ticker
+    #[cfg(feature = "metrics")]
+    async fn push_metrics(
+        &self,
+        ticker: &str,
+        duration: Duration,
+        retries: u32,
+        run: &TickerRun,
+    ) {
+        let Some(metrics) = &self.metrics else { return };
+        let result = metrics
+            .push(&crate::metrics::TickerMetrics {
+                ticker: ticker.to_string(),
+                duration,
+                records: run.records,
+                retries,
+                failed: run.status == Status::Failed,
+            })
+            .await;
+        if let Err(e) = result {
+            warn!(error = %e, ticker = %ticker, "Couldn't push the ticker's metrics");
+        }
+    }
+
+    /// Start the manifest of this run. With `resume`, the tickers that the
+    /// manifest of a previous run has as fully downloaded are taken out of
+    /// `plan`, keeping their entries
+    async fn start_manifest(&self, plan: Vec<WorkItem>) -> Vec<WorkItem> {
+        let mut manifest = self.manifest.lock().await;
+        manifest.started = Utc::now();
+        manifest.finished = None;
+        let plan = if self.config.resume {
+            plan.chunk_by(|a, b| a.ticker == b.ticker)
+                .filter(|items| {
+                    let ticker = &items[0].ticker;
+                    let run = TickerRun::new(items, self.config.multiplier);
+                    let done = manifest.is_done(ticker, &run);
+                    if done {
+                        info!(ticker = %ticker, "Skipping ticker, the manifest has it as downloaded");
+                    }
+                    !done
+                })
+                .flatten()
+                .cloned()
+                .collect()
+        } else {
+            plan
+        };
+        self.save_manifest(&manifest).await;
+        plan
+    }
+
+    /// Save `manifest`, only logging a failure since the data itself is
+    /// still fine
+    async fn save_manifest(&self, manifest: &Manifest) {
+        let path = manifest_path(&self.config);
+        let result = async {
+            tokio::fs::create_dir_all(&self.config.output_dir)
+                .await
+                .map_err(error::FileIo::CreateFile)?;
+            manifest.save(&path).await?;
+            set_permissions(
+                &self.config.output_dir,
+                &path,
+                self.config.permissions,
+            )
+        }
+        .await;
+        if let Err(e) = result {
+            error!(error = %e, "Encountered an error when saving the manifest");
+        }
+    }
+
+    /// Save a description of the columns the files are written with, only
+    /// logging a failure like for the manifest
+    fn save_dictionary(&self) {
+        #[derive(Serialize)]
+        struct Dictionary {
+            columns: Vec<Column>,
+        }
+
+        let path = self.config.output_dir.join(DICTIONARY_FILE);
+        let dictionary = Dictionary {
+            columns: self.options.columns(),
+        };
+        let result = std::fs::create_dir_all(&self.config.output_dir)
+            .map_err(error::FileIo::CreateFile)
+            .and_then(|()| Ok(serde_json::to_string_pretty(&dictionary)?))
+            .and_then(|contents| {
+                std::fs::write(&path, contents)
+                    .map_err(error::FileIo::FileWrite)
+            })
+            .and_then(|()| {
+                set_permissions(
+                    &self.config.output_dir,
+                    &path,
+                    self.config.permissions,
+                )
+            });
+        if let Err(e) = result {
+            error!(error = %e, "Encountered an error when saving the data dictionary");
+        }
+    }
 
-        let num_chunks = num_chunks(
-            self.config.timespan,
-            self.config.from,
-            self.config.to,
-            self.config.limit,
+    /// Read back the records written for `ticker` and log the gaps between
+    /// them. Daily bars are only missing on the days the ticker's market is
+    /// open
+    #[instrument(skip(self), err)]
+    fn validate(&self, ticker: &str) -> Result<Vec<gaps::Gap>, Error> {
+        let coverage = self.downloaded(ticker)?;
+        for gap in &coverage.gaps {
+            warn!(
+                start = %gap.start,
+                end = %gap.end,
+                missing = gap.missing,
+                "Gap in downloaded data"
+            );
+        }
+        info!(
+            num_records = coverage.records,
+            num_gaps = coverage.gaps.len(),
+            "Validated downloaded data"
         );
-        let progress_bar = ProgressBar::new(
-            self.config.tickers.len() as u64 * num_chunks as u64,
+        Ok(coverage.gaps)
+    }
+
+    /// Read back what's already downloaded of every ticker in the config,
+    /// in the files of the first configured format, without fetching
+    /// anything. Gaps are found like with `validate`
+    pub fn coverage(&self) -> Result<Vec<Coverage>, Error> {
+        self.config
+            .tickers
+            .iter()
+            .map(|ticker| self.downloaded(ticker))
+            .collect()
+    }
+
+    /// The range of the records written for `ticker` and the gaps between
+    /// them
+    fn downloaded(&self, ticker: &str) -> Result<Coverage, Error> {
+        let request = self.request(ticker, self.config.from, self.config.to)?;
+        let sinks = self.sinks(&request);
+        let mut coverage = Coverage {
+            ticker: ticker.to_string(),
+            range: None,
+            records: 0,
+            gaps: Vec::new(),
+        };
+        let Some(sink) = sinks.iter().find(|sink| !sink.daily) else {
+            return Ok(coverage);
+        };
+        let mut timestamps = Vec::new();
+        for file in sink.files()?.iter().filter(|file| file.exists()) {
+            let records =
+                reader::read_records(file, self.config.timestamp_unit)?;
+            timestamps.extend(records.iter().map(|record| record.timestamp));
+        }
+        timestamps.sort_unstable();
+        timestamps.dedup();
+        let daily = matches!(request.timespan, Timespan::Day)
+            && request.multiplier == 1;
+        let gaps: Vec<_> = gaps::find_gaps_with_multiplier(
+            request.timespan,
+            request.multiplier,
+            &timestamps,
         )
-        .with_style(style());
-        stream::iter(&self.config.tickers)
-            .for_each_concurrent(CONCURRENCY_LIMIT,|ticker| {
+        .into_iter()
+        .filter_map(|mut gap| {
+            if daily {
+                let days = gaps::missing_trading_days(Market::of(ticker), &gap);
+                gap.missing = i64::try_from(days.len()).ok()?;
+            }
+            (gap.missing > 0).then_some(gap)
+        })
+        .collect();
+        let first = timestamps.first().copied();
+        let last = timestamps.last().copied();
+        coverage.range = first.zip(last).and_then(|(first, last)| {
+            Some((
+                DateTime::from_timestamp_millis(first)?,
+                DateTime::from_timestamp_millis(last)?,
+            ))
+        });
+        coverage.records = timestamps.len();
+        coverage.gaps = gaps;
+        Ok(coverage)
+    }
+
+    /// The request for the records of `ticker` from `from` to `to`, going by
+    /// the config
+    fn request<'a>(
+        &self,
+        ticker: &'a str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<AggregateRequest<'a>, Error> {
+        Ok(AggregateRequestBuilder::default()
+            .timespan(self.config.timespan)
+            .multiplier(self.config.multiplier)
+            .ticker(ticker)
+            .from(from)
+            .to(to)
+            .limit(self.limit())
+            .adjusted(self.config.adjusted)
+            .sort(if self.config.last.is_some() {
+                SortOrder::Desc
+            } else {
+                self.config.sort
+            })
+            .build()?)
+    }
+
+    /// Fetch the records of `ticker` from `from` to `to` in the config and
+    /// write them to `out` instead of to files, in the first of the
+    /// configured formats. Records are written batch by batch as they come
+    /// in, except in formats like Parquet that have to be written in one go
+    #[instrument(skip(self, out), err)]
+    pub async fn fetch_to_writer(
+        &self,
+        ticker: &str,
+        mut out: impl Write,
+    ) -> Result<(), Error> {
+        let serializer =
+            self.serializers.first().ok_or(Error::NoOutputFormat)?;
+        let request = self.request(ticker, self.config.from, self.config.to)?;
+        // Like the files, records fetched newest first are written oldest
+        // first if asked to, which means waiting for all of them
+        let reverse = (self.config.reverse_descending
+            || self.config.last.is_some())
+            && request.sort == SortOrder::Desc;
+        let buffer_all = reverse || serializer.encoder().is_some();
+        let mut buffer = Vec::new();
+        let mut empty = true;
+        let mut stream =
+            take_records(self.get_aggregates(request).await, self.config.last);
+        while let Some(records) = stream.try_next().await? {
+            if buffer_all {
+                buffer.extend(records);
+            } else {
+                write_records(serializer.as_ref(), &mut out, &records, empty)?;
+                empty &= records.is_empty();
+            }
+        }
+        if buffer_all {
+            if reverse {
+                buffer.reverse();
+            }
+            write_records(serializer.as_ref(), &mut out, &buffer, true)?;
+            empty = buffer.is_empty();
+        }
+        if empty {
+            serializer.empty(&mut out)?;
+        } else {
+            out.write_all(serializer.footer())
+                .map_err(error::FileIo::FileWrite)?;
+        }
+        out.flush().map_err(error::FileIo::FileWrite)?;
+        Ok(())
+    }
+
+    /// Empty the files `request` is written to and forget what the
+    /// checkpoint has for them
+    async fn truncate_sinks(
+        &self,
+        request: &AggregateRequest<'_>,
+    ) -> Result<(), error::FileIo> {
+        let sinks = self.sinks(request);
+        for sink in &sinks {
+            if sink.partition != PartitionBy::None {
+                for file in sink.files()? {
+                    std::fs::remove_file(file)
+                        .map_err(error::FileIo::FileWrite)?;
+                }
+                continue;
+            }
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .open(&sink.path)
+            {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(error::FileIo::FileWrite(e)),
+            }
+        }
+        let mut checkpoint = self.checkpoint.lock().await;
+        for sink in &sinks {
+            checkpoint.clear(&sink.key);
+        }
+        let path = checkpoint_path(&self.config);
+        if path.exists() {
+            checkpoint.save(&path).await?;
+        }
+        Ok(())
+    }
+
+    /// If `error` is Polygon's rate limit, which the client already retried
+    /// for as long as it could, log what to do about it the first time and
+    /// return `true`
+    fn report_rate_limit(&self, error: &Error) -> bool {
+        if error.status() != Some(reqwest::StatusCode::TOO_MANY_REQUESTS) {
+            return false;
+        }
+        if !self.rate_limited.swap(true, Ordering::Relaxed) {
+            error!(
+                error = %error,
+                abort = self.config.abort_on_rate_limit,
+                "Polygon's rate limit was still exceeded after retrying. Lower \
+                 --concurrency, set --rate-limit to the number of \
+                 requests a minute your plan allows (5 on the free tier) or \
+                 upgrade your Polygon plan"
+            );
+        } else {
+            debug!(error = %error, "Rate limited");
+        }
+        true
+    }
+
+    /// Whether to stop fetching, because of the rate limit
+    fn aborted(&self) -> bool {
+        self.config.abort_on_rate_limit
+            && self.rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// The details of `ticker` in the metadata cache, if it's configured
+    /// and they haven't expired
+    fn cached_details(&self, ticker: &str) -> Option<TickerDetails> {
+        self.config.metadata_cache.as_ref()?;
+        let metadata = self.metadata.lock().expect("not poisoned");
+        metadata
+            .get(ticker, self.config.metadata_ttl, Utc::now())
+            .cloned()
+    }
+
+    /// The details of `ticker`, from the metadata cache if they're in it,
+    /// otherwise looked up on Polygon and added to it
+    async fn ticker_details(
+        &self,
+        ticker: &str,
+    ) -> Result<TickerDetails, Error> {
+        if let Some(details) = self.cached_details(ticker) {
+            debug!(ticker = %ticker, "Using cached ticker details");
+            return Ok(details);
+        }
+        let permit = self.acquire().await;
+        let details = self.client.get_ticker_details(ticker).await?;
+        drop(permit);
+        if let Some(path) = &self.config.metadata_cache {
+            let mut metadata = self.metadata.lock().expect("not poisoned");
+            metadata.insert(ticker, details.clone(), Utc::now());
+            if let Err(e) = metadata.save(path) {
+                warn!(error = %e, path = %path.display(), "Couldn't save the metadata cache");
+            }
+        }
+        Ok(details)
+    }
+
+    /// The start of the day `ticker` was listed, falling back to `from` if
+    /// Polygon doesn't know when that was
+    async fn listing_date(&self, ticker: &str) -> DateTime<Utc> {
+        let details = self.ticker_details(ticker).await;
+        match details.map(|details| details.list_date) {
+            Ok(Some(date)) => date
+                .and_hms_opt(0, 0, 0)
+                .map_or(self.config.from, |date| date.and_utc()),
+            Ok(None) => {
+                warn!(ticker = %ticker, "Ticker has no listing date");
+                self.config.from
+            }
+            Err(e) => {
+                warn!(error = %e, ticker = %ticker, "Couldn't get the listing date of a ticker");
+                self.config.from
+            }
+        }
+    }
+
+    /// Start adding the permits held back when ramping up, if configured
+    fn start_ramp_up(&self) {
+        if let Some(duration) = self.config.ramp_up {
+            self.ramp_up.call_once(|| {
+                tokio::spawn(ramp_up(
+                    self.requests.clone(),
+                    self.config.concurrency - 1,
+                    duration,
+                ));
+            });
+        }
+    }
+
+    /// Stop sending new requests until [`resume`](Self::resume) is called.
+    /// Requests already in flight finish, and their records are written
+    pub fn pause(&self) {
+        if !self.paused.send_replace(true) {
+            info!("Paused, no new requests are sent until resumed");
+        }
+    }
+
+    /// Carry on sending requests after [`pause`](Self::pause)
+    pub fn resume(&self) {
+        if self.paused.send_replace(false) {
+            info!("Resumed");
+        }
+    }
+
+    /// Whether the fetch is paused, see [`pause`](Self::pause)
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Wait until the fetch isn't paused and there is room for another
+    /// request in flight
+    async fn acquire(&self) -> SemaphorePermit<'_> {
+        wait_until_resumed(&mut self.paused.subscribe()).await;
+        self.requests
+            .acquire()
+            .await
+            .expect("the semaphore is never closed")
+    }
+
+    /// Fetch the daily bars of the whole US stock market for every day from
+    /// `from` to `to` with the grouped daily endpoint. Each day is saved to
+    /// `$output_dir/$date.csv`, containing every ticker traded that day, or
+    /// to the files of each ticker with `grouped_by_ticker`.
+    #[instrument(skip_all)]
+    pub async fn fetch_grouped_daily(&self) {
+        let days = days(self.config.from, self.config.to);
+        info!(
+            num_days = days.len(),
+            output_dir = ?self.config.output_dir,
+            from = %self.config.from,
+            to = %self.config.to,
+            "Starting to fetch grouped daily data..."
+        );
+
+        let progress_bar = self.start_progress(days.len() as u64);
+        self.emit(Event::Started {
+            total: days.len() as u64,
+        });
+        if self.config.grouped_by_ticker {
+            // Every day goes to the same files, so the days are fetched
+            // concurrently but saved one after another
+            stream::iter(days)
+                .map(|date| async move {
+                    let permit = self.acquire().await;
+                    let bars = self
+                        .client
+                        .get_grouped_daily_by_ticker(date, self.config.adjusted)
+                        .await;
+                    drop(permit);
+                    (date, bars)
+                })
+                .buffered(self.config.concurrency)
+                .for_each(|(date, bars)| {
+                    let _result = bars
+                        .and_then(|bars| self.save_grouped_daily_by_ticker(bars))
+                        .inspect_err(|e| {
+                            error!(error = %e, date = %date, "Encountered an error when processing a day");
+                            self.emit_error(None, Some(date), e);
+                        });
+                    self.advance(&progress_bar, None, 1);
+                    future::ready(())
+                })
+                .await;
+            progress_bar.finish();
+            self.emit_finished(&progress_bar);
+            info!("Finished fetching data!");
+            return;
+        }
+        stream::iter(days)
+            .for_each_concurrent(self.config.concurrency, |date| {
                 let pb = progress_bar.clone();
                 async move {
-                    let request = match AggregateRequestBuilder::default()
-                        .timespan(self.config.timespan)
-                        .ticker(ticker)
-                        .from(self.config.from)
-                        .to(self.config.to)
-                        .limit(self.config.limit)
-                        .build() {
-                            Ok(request) => request,
-                            Err(e) => {
-                                error!(error = %e, ticker = %ticker, "Encountered an error when building a request");
-                                return;
-                            }
-                        };
-                    tracing::info!(ticker = %ticker, "Fetching data for ticker");
-                    let _result = self.save_aggregates_to_disk(request, pb).await.inspect_err(|e| {
-                        error!(error = %e, ticker = %ticker, "Encountered an error when processing a ticker");
-                    });
-                    tracing::info!(ticker = %ticker, "Finished fetching data for ticker");
+                    let _result = self
+                        .save_grouped_daily_to_disk(date)
+                        .await
+                        .inspect_err(|e| {
+                            error!(error = %e, date = %date, "Encountered an error when processing a day");
+                            self.emit_error(None, Some(date), e);
+                        });
+                    self.advance(&pb, None, 1);
                 }
             })
             .await;
 
         progress_bar.finish();
+        self.emit_finished(&progress_bar);
         info!("Finished fetching data!");
     }
 
+    #[instrument(skip(self), err)]
+    pub async fn save_grouped_daily_to_disk(
+        &self,
+        date: NaiveDate,
+    ) -> Result<(), Error> {
+        let permit = self.acquire().await;
+        let response = self
+            .client
+            .get_grouped_daily(date, self.config.adjusted)
+            .await?;
+        drop(permit);
+        if response.results.is_empty() {
+            // the market was most likely closed
+            warn!("Got no results");
+            return Ok(());
+        }
+
+        let path = if self.config.flat_file_layout {
+            self.config.output_dir.join(flat_file_path(date))
+        } else {
+            self.config.output_dir.join(format!("{date}.csv"))
+        };
+        if let Some(parent_dir) = path.parent() {
+            fs::create_dir_all(parent_dir)
+                .await
+                .map_err(error::FileIo::CreateFile)?;
+        }
+        let mut file =
+            File::create(&path).map_err(error::FileIo::CreateFile)?;
+        set_permissions(
+            &self.config.output_dir,
+            &path,
+            self.config.permissions,
+        )?;
+        if self.config.csv_bom {
+            file.write_all(UTF8_BOM).map_err(error::FileIo::FileWrite)?;
+        }
+        let mut writer = WriterBuilder::new()
+            .flexible(true)
+            .quote_style(self.config.csv_quoting.into())
+            .delimiter(self.options.delimiter)
+            .from_writer(file);
+        debug!(num_records = %response.results.len(), "Processing batch of records");
+        for record in response.results {
+            if self.config.flat_file_layout {
+                writer.serialize(FlatFileRecord::from(record))
+            } else {
+                writer.serialize(record)
+            }
+            .map_err(error::FileIo::Csv)?;
+        }
+        let file = writer
+            .into_inner()
+            .map_err(|e| error::FileIo::FileWrite(e.into_error()))?;
+        if !self.config.csv_trailing_newline {
+            let len = file.metadata().map_err(error::FileIo::FileWrite)?.len();
+            file.set_len(len.saturating_sub(1))
+                .map_err(error::FileIo::FileWrite)?;
+        }
+        Ok(())
+    }
+
+    /// Merge the daily bar of each ticker in `bars` into the ticker's files,
+    /// skipping the tickers that aren't configured if any are
+    fn save_grouped_daily_by_ticker(
+        &self,
+        bars: BTreeMap<String, AggregateRecord>,
+    ) -> Result<(), Error> {
+        if bars.is_empty() {
+            // the market was most likely closed
+            warn!("Got no results");
+            return Ok(());
+        }
+        let newest_first = self.config.sort == SortOrder::Desc
+            && !self.config.reverse_descending;
+        for (ticker, bar) in bars {
+            if !self.config.tickers.is_empty()
+                && !self.config.tickers.contains(&ticker)
+            {
+                continue;
+            }
+            let request = AggregateRequest {
+                timespan: Timespan::Day,
+                multiplier: 1,
+                ..self.request(&ticker, self.config.from, self.config.to)?
+            };
+            self.merge_into_sinks(
+                &self.sinks(&request),
+                vec![bar],
+                newest_first,
+            )?;
+        }
+        Ok(())
+    }
+
     #[instrument(skip_all, fields(ticker = %request.ticker))]
     async fn get_aggregates<'a>(
         &'a self,
-        request: AggregateRequest<'a>,
-    ) -> BoxStream<Result<Vec<AggregateRecord>, Error>> {
+        mut request: AggregateRequest<'a>,
+    ) -> BoxStream<'a, Result<Vec<AggregateRecord>, Error>> {
+        let splits = match self.config.adjusted_as_of {
+            Some(as_of) => match self.splits(request.ticker).await {
+                Ok(splits) => Some((as_of, splits)),
+                Err(e) => return stream::once(async { Err(e) }).boxed(),
+            },
+            None => None,
+        };
+        if splits.is_some() {
+            request.adjusted = false;
+        }
         let client = self.client.clone();
+        let requests = self.requests.clone();
+        let save_raw = self
+            .config
+            .save_raw
+            .as_ref()
+            .map(|dir| dir.join(&*self.ticker_dir(request.ticker)));
+        let permissions = self.config.permissions;
+        let paused = self.paused.subscribe();
+        let max_requests = self.config.max_requests_per_ticker;
+        let sent = self.ticker_requests(request.ticker);
         let stream = stream::unfold(
-            (request, None, false),
-            move |(mut request, next_url, final_page)| {
+            (request, None, false, 0),
+            move |(mut request, next_url, final_page, page)| {
                 let client = client.clone();
+                let requests = requests.clone();
+                let save_raw = save_raw.clone();
+                let mut paused = paused.clone();
+                let sent = sent.clone();
                 async move {
                     if final_page {
                         return None;
                     } else if let Some(url) = next_url {
                         request.next_url = Some(url);
                     }
+                    if let Some(max) = max_requests {
+                        if sent.fetch_add(1, Ordering::Relaxed) >= max.get() {
+                            let e = Error::TickerRequestLimit(max);
+                            return Some((Err(e), (request, None, true, page)));
+                        }
+                    }
 
-                    match client.get_aggregate(&request).await {
+                    wait_until_resumed(&mut paused).await;
+                    let permit = requests
+                        .acquire()
+                        .await
+                        .expect("the semaphore is never closed");
+                    let response = client.get_aggregate_raw(&request).await;
+                    drop(permit);
+                    let response = match (response, save_raw) {
+                        (Ok((response, body)), Some(dir)) => save_raw_response(
+                            &dir,
+                            &request,
+                            page,
+                            &body,
+                            permissions,
+                        )
+                        .await
+                        .map(|_| response),
+                        (response, _) => response.map(|(response, _)| response),
+                    };
+                    match response {
                         Ok(response) if response.next_url.is_some() => Some((
                             Ok(response.results),
-                            (request, response.next_url, false),
+                            (request, response.next_url, false, page + 1),
                         )),
                         Ok(response) => {
                             debug!(
@@ -112,105 +1465,1515 @@ impl Service {
                             );
                             Some((
                                 Ok(response.results),
-                                (request, response.next_url, true),
+                                (request, response.next_url, true, page + 1),
                             ))
                         }
-                        Err(e) => Some((Err(e), (request, None, false))),
+                        Err(e) => Some((Err(e), (request, None, false, page))),
                     }
                 }
             },
         );
 
-        stream.boxed()
+        let stream = match self.config.page_duplicates {
+            Some(check) => stream
+                .map(move |records| {
+                    let mut records = records?;
+                    check_page_duplicates(check, &mut records)?;
+                    Ok(records)
+                })
+                .boxed(),
+            None => stream.boxed(),
+        };
+        let stream = match splits {
+            Some((as_of, splits)) => stream
+                .map(move |records| {
+                    let mut records = records?;
+                    adjust::adjust_as_of(&mut records, &splits, as_of)?;
+                    Ok(records)
+                })
+                .boxed(),
+            None => stream,
+        };
+        match self.transform.as_deref() {
+            Some(transform) => stream
+                .map_ok(move |records| {
+                    records.into_iter().map(transform).collect()
+                })
+                .boxed(),
+            None => stream,
+        }
     }
 
-    #[instrument(skip_all, err, fields(ticker = %request.ticker))]
-    pub async fn save_aggregates_to_disk<'a>(
+    /// The number of requests sent for the pages of `ticker` so far
+    fn ticker_requests(&self, ticker: &str) -> Arc<AtomicU32> {
+        let mut sent = self.ticker_requests.lock().expect("not poisoned");
+        sent.entry(ticker.to_owned()).or_default().clone()
+    }
+
+    /// The splits of `ticker`, only fetched the first time they're needed
+    async fn splits(&self, ticker: &str) -> Result<Arc<Vec<Split>>, Error> {
+        if let Some(splits) =
+            self.splits.lock().expect("not poisoned").get(ticker)
+        {
+            return Ok(splits.clone());
+        }
+        let permit = self.acquire().await;
+        let splits = Arc::new(self.client.get_splits(ticker).await?);
+        drop(permit);
+        self.splits
+            .lock()
+            .expect("not poisoned")
+            .insert(ticker.to_string(), splits.clone());
+        Ok(splits)
+    }
+
+    /// Fetch every page of `request` and yield its records one at a time,
+    /// without writing them anywhere. Pages are fetched as the stream is
+    /// polled, sharing the limit on requests in flight with everything else
+    /// the service fetches. Raw responses are still saved with `save_raw`
+    pub fn stream_aggregates<'a>(
         &'a self,
         request: AggregateRequest<'a>,
-        progress_bar: ProgressBar,
-    ) -> Result<(), Error> {
-        let ticker = &request.ticker;
-        let timespan = &request.timespan;
-        let file_path = self
-            .config
-            .output_dir
-            .join(format!("{ticker}/{timespan}.csv"));
-        let parent_dir = file_path
-            .parent()
-            .ok_or_else(|| {
-                std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    "Output directory must have at least one parent",
-                )
+    ) -> impl Stream<Item = Result<AggregateRecord, Error>> + 'a {
+        stream::once(self.get_aggregates(request))
+            .flatten()
+            .map_ok(|records| stream::iter(records.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    /// Fetch each of `ranges` of `request` concurrently, yielding one batch
+    /// of records per range. Batches are yielded in the same order as
+    /// `ranges`, no matter which range finishes first
+    async fn get_aggregates_by_range<'a>(
+        &'a self,
+        request: AggregateRequest<'a>,
+        ranges: Vec<DateRange>,
+    ) -> BoxStream<'a, (DateRange, Result<Vec<AggregateRecord>, Error>)> {
+        stream::iter(ranges)
+            .map(move |(from, to)| {
+                let request = AggregateRequest {
+                    from,
+                    to,
+                    next_url: None,
+                    ..request.clone()
+                };
+                async move {
+                    let records = self
+                        .get_aggregates(request)
+                        .await
+                        .try_concat()
+                        .await
+                        .map(|mut records: Vec<_>| {
+                            records.sort_by_key(|record| record.timestamp);
+                            records
+                        });
+                    ((from, to), records)
+                }
             })
-            .map_err(error::FileIo::CreateFile)?;
-        fs::create_dir_all(parent_dir)
-            .await
-            .map_err(error::FileIo::CreateFile)?;
-        let file = create_or_open_file(file_path)?;
-        let mut writer = WriterBuilder::new().flexible(true).from_writer(file);
-        let mut stream = self.get_aggregates(request).await;
-        while let Some(result) = stream.next().await {
-            match result {
-                Ok(records) if records.is_empty() => {
-                    warn!("Got no results");
+            .buffered(self.config.ticker_parallelism)
+            .boxed()
+    }
+
+    #[instrument(skip_all, err, fields(ticker = %request.ticker))]
+    pub async fn save_aggregates_to_disk<'a>(
+        &'a self,
+        mut request: AggregateRequest<'a>,
+        progress_bar: ProgressBar,
+    ) -> Result<usize, Error> {
+        let sinks = self.sinks(&request);
+        let ticker = request.ticker;
+        // The most recent bars have to be fetched one page after another,
+        // newest first, to know when to stop
+        let by_month =
+            self.config.ticker_parallelism > 1 && self.config.last.is_none();
+        // With a file per day, the checkpoint has which days are complete,
+        // so only the days around them are fetched
+        let by_day = self.config.resume
+            && self.config.last.is_none()
+            && sinks.iter().all(|sink| sink.partition == PartitionBy::Day);
+        let by_range = by_month || by_day;
+        // Months are sorted as they come in, so only a single range fetched
+        // newest first needs buffering to be written oldest first
+        let reverse = (self.config.reverse_descending
+            || self.config.last.is_some())
+            && request.sort == SortOrder::Desc
+            && !by_range;
+        let newest_first =
+            request.sort == SortOrder::Desc && !by_range && !reverse;
+        let estimated_pages = u64::try_from(num_chunks(
+            request.timespan,
+            request.multiplier,
+            request.from,
+            request.to,
+            request.limit,
+        ))
+        .unwrap_or_default();
+        // What's fetched now completes the whole range in the files
+        let (from, to) = (request.from, request.to);
+        let last_downloaded = if by_day {
+            None
+        } else {
+            self.skip_downloaded(&mut request, &sinks, newest_first)?
+        };
+        let resumed = last_downloaded.is_some();
+        if request.from > request.to {
+            info!("Already downloaded, skipping");
+            self.advance(&progress_bar, Some(ticker), estimated_pages);
+            return Ok(0);
+        }
+
+        // Fetching by month lets us checkpoint each month separately,
+        // otherwise the whole range is a single unit of work
+        let ranges = if by_range {
+            monthly_ranges(request.from, request.to)
+        } else {
+            vec![(request.from, request.to)]
+        };
+        let num_ranges = ranges.len();
+        let mut started = false;
+        let ranges: Vec<_> = if by_day {
+            let (ranges, any_started) = self.missing_days(&sinks, ranges).await;
+            started = any_started;
+            ranges
+        } else if self.config.resume {
+            let checkpoint = self.checkpoint.lock().await;
+            ranges
+                .into_iter()
+                .filter(|(from, to)| {
+                    !sinks.iter().all(|sink| {
+                        checkpoint.is_complete(&sink.key, *from, *to)
+                    })
+                })
+                .collect()
+        } else {
+            ranges
+        };
+        if ranges.is_empty() {
+            info!("Already downloaded, skipping");
+            self.advance(&progress_bar, Some(ticker), estimated_pages);
+            return Ok(0);
+        }
+        // The ranges that were skipped are already in the file, so anything
+        // fetched now has to be merged into it to keep the file in order
+        // The same goes for daily bars when the file ended partway through a
+        // day, since that day's bar has to be derived again. Days in files
+        // of their own only need merging if a run before left them unfinished
+        let mut merge_buffer = (ranges.len() < num_ranges && !by_day
+            || started
+            || resumed && sinks.iter().any(|sink| sink.daily))
+        .then(Vec::new);
+        let mut reverse_buffer = reverse.then(Vec::new);
+        // Records fetched again to catch revisions are held back like any
+        // that come in out of order, and merged over the ones in the files
+        let revisions = self.config.revision_window.is_some() && !newest_first;
+        let mut reorder = (self.config.strict_order || revisions).then(|| {
+            let mut reorder = Reorder::new(newest_first);
+            if revisions {
+                reorder.last = last_downloaded;
+            }
+            reorder
+        });
+
+        let mut writers = open_writers(&sinks, self.config.permissions).await?;
+        let mut daily = sinks
+            .iter()
+            .any(|sink| sink.daily)
+            .then(|| DailyResampler::new(resample::new_york_day));
+        let gap_request = self.config.fill_gaps.then(|| request.clone());
+        let mut timestamps = Vec::new();
+        let mut last_timestamp = None;
+        let mut summary = self.config.summary_stats.then(Summary::default);
+        let mut pages = 0;
+        let mut num_records = 0;
+        let mut completed = Vec::new();
+        let mut num_failed = 0;
+        let stream = if by_range {
+            self.get_aggregates_by_range(request, ranges)
+                .await
+                .map(|(range, result)| (Some(range), result))
+                .boxed()
+        } else {
+            take_records(self.get_aggregates(request).await, self.config.last)
+                .map(|result| (None, result))
+                .boxed()
+        };
+        // Pages are fetched ahead while the ones before are written, holding
+        // up to `write_buffer_size` of them in memory
+        let (fetch, mut pages_fetched) =
+            fetch_ahead(stream, self.config.write_buffer_size);
+        let write = async {
+            while let Some((range, result)) = pages_fetched.recv().await {
+                pages += 1;
+                if pages > estimated_pages {
+                    progress_bar.inc_length(1);
                 }
-                Ok(records) => {
-                    debug!(num_records = %records.len(), "Processing batch of recrods");
-                    for record in records {
-                        writer.serialize(record).map_err(error::FileIo::Csv)?;
+                self.advance(&progress_bar, Some(ticker), 1);
+
+                match result {
+                    Ok(records) if records.is_empty() => {
+                        warn!("Got no results");
+                    }
+                    Ok(mut records) => {
+                        debug!(num_records = %records.len(), "Processing batch of recrods");
+                        num_records += records.len();
+                        if gap_request.is_some() {
+                            timestamps
+                                .extend(records.iter().map(|r| r.timestamp));
+                        }
+                        if let Some(Err(e)) = summary.as_mut().map(|summary| {
+                            records
+                                .iter()
+                                .try_for_each(|record| summary.add(record))
+                        }) {
+                            // The summary is only informational, so the records
+                            // are still saved
+                            warn!(error = %e, "Can't summarize the records, leaving out the summary");
+                            summary = None;
+                        }
+                        if let Some(buffer) = &mut merge_buffer {
+                            // Merging sorts and dedups, so the file always ends
+                            // up monotonic
+                            buffer.extend(records);
+                        } else if let Some(buffer) = &mut reverse_buffer {
+                            buffer.extend(records);
+                        } else {
+                            if let Some(reorder) = &mut reorder {
+                                records = reorder.order(records);
+                            }
+                            if let Some(check) = self.config.monotonic {
+                                check_monotonic(
+                                    check,
+                                    &mut last_timestamp,
+                                    &records,
+                                )?;
+                            }
+                            write_batch(
+                                &mut writers,
+                                &sinks,
+                                daily.as_mut(),
+                                &records,
+                                self.config.write_concurrency,
+                            )?;
+                        }
+                    }
+                    Err(e) if range.is_some() => {
+                        // Keep going with the other months, the checkpoint lets
+                        // a later run fetch just the ones that failed
+                        if !self.report_rate_limit(&e) {
+                            error!(error = %e, "Error when fetching a month of data");
+                        }
+                        num_failed += 1;
+                        if self.aborted() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Error when getting next item from stream");
+                        // Once we are more intelligent about appending data
+                        // we could potentially remove this return
+                        return Err(e);
                     }
-                    writer.flush().map_err(error::FileIo::FileWrite)?;
                 }
-                Err(e) => {
-                    error!("Error when getting next item from stream");
-                    // Once we are more intelligent about appending data
-                    // we could potentially remove this return
-                    return Err(e);
+
+                if let Some((from, to)) = range {
+                    if merge_buffer.is_some() {
+                        completed.push((from, to));
+                    } else {
+                        self.complete(&sinks, from, to).await?;
+                    }
+                }
+                // A crude stand-in for rate limiting, not needed with the real
+                // thing
+                if !self.client.is_rate_limited() {
+                    sleep(Duration::from_millis(20)).await
+                }
+            }
+            // Dropping the receiver stops the fetching if the loop ended
+            // early
+            drop(pages_fetched);
+            Ok(())
+        };
+        let ((), result) = future::join(fetch, write).await;
+        result?;
+
+        // `num_chunks` is only an estimate, so give back the part of the
+        // progress bar this ticker didn't use, e.g. for sparse tickers that
+        // only have a single page of data
+        if pages < estimated_pages {
+            let length = progress_bar.length().unwrap_or_default();
+            progress_bar
+                .set_length(length.saturating_sub(estimated_pages - pages));
+        }
+
+        if let Some(mut records) = reverse_buffer {
+            records.reverse();
+            if let Some(reorder) = &mut reorder {
+                records = reorder.order(records);
+            }
+            if let Some(check) = self.config.monotonic {
+                check_monotonic(check, &mut last_timestamp, &records)?;
+            }
+            write_batch(
+                &mut writers,
+                &sinks,
+                daily.as_mut(),
+                &records,
+                self.config.write_concurrency,
+            )?;
+        }
+        if let Some(bar) = daily.map(Resampler::finish).transpose()?.flatten() {
+            for (writer, _) in writers
+                .iter_mut()
+                .zip(&sinks)
+                .filter(|(_, sink)| sink.daily)
+            {
+                writer.write(&[daily_bar(bar.clone())])?;
+            }
+        }
+        for writer in writers {
+            writer.finish()?;
+        }
+        let late = reorder.map(Reorder::into_late).unwrap_or_default();
+        if !late.is_empty() {
+            debug!(
+                num_records = late.len(),
+                "Merging records that came in out of order"
+            );
+            self.merge_into_sinks(&sinks, late, newest_first)?;
+        }
+        if let Some(records) = merge_buffer {
+            self.merge_into_sinks(&sinks, records, newest_first)?;
+            for (from, to) in completed {
+                self.complete(&sinks, from, to).await?;
+            }
+        }
+        if !by_range {
+            self.complete(&sinks, from, to).await?;
+        }
+
+        if let Some(request) = gap_request {
+            self.fill_gaps(&request, &sinks, timestamps, newest_first)
+                .await?;
+        }
+        if let Some(summary) = summary {
+            info!(
+                num_records = summary.count,
+                min_close = ?summary.min_close,
+                max_close = ?summary.max_close,
+                average_close = ?summary.average_close(),
+                total_volume = %summary.total_volume,
+                "Summary of fetched data"
+            );
+        }
+
+        if num_failed > 0 {
+            return Err(Error::IncompleteRanges(num_failed));
+        }
+        Ok(num_records)
+    }
+
+    /// Split `ranges` up into days and leave out the ones the checkpoint has
+    /// as complete for all `sinks`, joining the days left back up within
+    /// each range. Also returns whether any of the days left already have a
+    /// file, from a run that stopped partway through them
+    async fn missing_days(
+        &self,
+        sinks: &[Sink<'_>],
+        ranges: Vec<DateRange>,
+    ) -> (Vec<DateRange>, bool) {
+        let checkpoint = self.checkpoint.lock().await;
+        let mut missing: Vec<DateRange> = Vec::new();
+        let mut started = false;
+        for (from, to) in ranges {
+            let mut joined = false;
+            for (from, to) in daily_ranges(from, to) {
+                if sinks
+                    .iter()
+                    .all(|sink| checkpoint.is_complete(&sink.key, from, to))
+                {
+                    joined = false;
+                    continue;
+                }
+                started |= sinks
+                    .iter()
+                    .any(|sink| sink.file(from.timestamp_millis()).exists());
+                match missing.last_mut() {
+                    Some(last) if joined => last.1 = to,
+                    _ => missing.push((from, to)),
+                }
+                joined = true;
+            }
+        }
+        (missing, started)
+    }
+
+    /// Narrow `request` down to what isn't in its files yet: whatever comes
+    /// after the last record, or before it for files written `newest_first`.
+    /// With `revision_window`, files written oldest first are fetched again
+    /// from that long before the last record. Returns the timestamp of the
+    /// last record, if anything was already there. Leaves `from` after `to`
+    /// if there's nothing left to fetch
+    fn skip_downloaded(
+        &self,
+        request: &mut AggregateRequest<'_>,
+        sinks: &[Sink<'_>],
+        newest_first: bool,
+    ) -> Result<Option<i64>, error::FileIo> {
+        let mut last = None;
+        for sink in sinks.iter().filter(|sink| !sink.daily) {
+            let timestamp = match sink
+                .last_timestamp(self.config.timestamp_unit, newest_first)
+            {
+                Ok(Some(timestamp)) => timestamp,
+                // Start over when any file is empty, so none of them
+                // miss out
+                Ok(None) | Err(error::FileIo::UnreadableFormat(_)) => {
+                    return Ok(None)
                 }
+                Err(e) => return Err(e),
+            };
+            // Go by the file that's furthest behind
+            last = Some(match last {
+                Some(last) if newest_first => timestamp.max(last),
+                Some(last) => timestamp.min(last),
+                None => timestamp,
+            });
+        }
+        let Some((timestamp, last)) = last.and_then(|timestamp| {
+            Some((timestamp, DateTime::from_timestamp_millis(timestamp)?))
+        }) else {
+            return Ok(None);
+        };
+        debug!(last = %last, "Picking up after the records already downloaded");
+        if newest_first {
+            request.to = request.to.min(last - TimeDelta::milliseconds(1));
+        } else {
+            let overlap = self
+                .config
+                .revision_window
+                .and_then(|window| TimeDelta::from_std(window).ok())
+                .unwrap_or_default();
+            request.from = request
+                .from
+                .max(last + TimeDelta::milliseconds(1) - overlap);
+        }
+        Ok(Some(timestamp))
+    }
+
+    /// Merge `records` into the file of every sink, keeping files written
+    /// `newest_first` that way
+    fn merge_into_sinks(
+        &self,
+        sinks: &[Sink<'_>],
+        records: Vec<AggregateRecord>,
+        newest_first: bool,
+    ) -> Result<(), Error> {
+        let mut merged = Vec::new();
+        let mut source = None;
+        for sink in sinks.iter().filter(|sink| !sink.daily) {
+            merged =
+                self.merge_into_file(sink, records.clone(), newest_first)?;
+            source = Some(sink);
+        }
+        if let Some(sink) = source {
+            merged = self.whole_days(sink, merged)?;
+        }
+        // The days records were merged into have changed, so the daily
+        // files are derived again from all the records of those days
+        let mut days: Vec<_> =
+            resample::resample(&merged, resample::new_york_day)?
+                .into_iter()
+                .map(daily_bar)
+                .collect();
+        if newest_first {
+            days.reverse();
+        }
+        for sink in sinks.iter().filter(|sink| sink.daily) {
+            self.rewrite(sink, &days)?;
+        }
+        Ok(())
+    }
+
+    /// All the records of `sink` in the New York days of `merged`, the
+    /// records of the files just merged into, oldest first. Partitions go by
+    /// UTC dates, so a day in New York can have records in the partition
+    /// after, e.g. winter after-hours bars past midnight UTC
+    fn whole_days(
+        &self,
+        sink: &Sink<'_>,
+        mut merged: Vec<AggregateRecord>,
+    ) -> Result<Vec<AggregateRecord>, Error> {
+        if sink.partition == PartitionBy::None {
+            return Ok(merged);
+        }
+        // A day in New York is at most 25 hours long
+        const DAY: i64 = 25 * 60 * 60 * 1000 - 1;
+        let days: BTreeSet<_> =
+            merged.iter().map(resample::new_york_day).collect();
+        let merged_files: BTreeSet<_> =
+            merged.iter().map(|r| sink.file(r.timestamp)).collect();
+        let files: BTreeSet<_> = days
+            .iter()
+            .flat_map(|day| [sink.file(*day), sink.file(day + DAY)])
+            .filter(|file| !merged_files.contains(file) && file.exists())
+            .collect();
+        for file in files {
+            merged.extend(
+                reader::read_records(&file, self.config.timestamp_unit)?
+                    .into_iter()
+                    .filter(|r| days.contains(&resample::new_york_day(r))),
+            );
+        }
+        merged.sort_by_key(|record| record.timestamp);
+        merged.dedup_by_key(|record| record.timestamp);
+        Ok(merged)
+    }
+
+    /// Merge `records` into the file of `sink`, keeping the file sorted by
+    /// timestamp, newest first if it's written `newest_first`, and free of
+    /// duplicates. With partitions, only the files `records` go in are
+    /// touched. Returns all the records in those files, oldest first
+    fn merge_into_file(
+        &self,
+        sink: &Sink<'_>,
+        records: Vec<AggregateRecord>,
+        newest_first: bool,
+    ) -> Result<Vec<AggregateRecord>, Error> {
+        let files: BTreeSet<_> = match sink.partition {
+            PartitionBy::None => BTreeSet::from([sink.path.clone()]),
+            _ => records.iter().map(|r| sink.file(r.timestamp)).collect(),
+        };
+        let mut merged = records;
+        for file in files {
+            if file.exists() {
+                merged.extend(reader::read_records(
+                    &file,
+                    self.config.timestamp_unit,
+                )?);
+            }
+        }
+        merged.sort_by_key(|record| record.timestamp);
+        merged.dedup_by_key(|record| record.timestamp);
+        if newest_first {
+            merged.reverse();
+        }
+        self.rewrite(sink, &merged)?;
+        if newest_first {
+            merged.reverse();
+        }
+        Ok(merged)
+    }
+
+    /// Replace the file of `sink` with `records`, or the file of each
+    /// partition they go in
+    fn rewrite(
+        &self,
+        sink: &Sink<'_>,
+        records: &[AggregateRecord],
+    ) -> Result<(), Error> {
+        let batches: Vec<_> = match sink.partition {
+            PartitionBy::None => vec![records],
+            partition => records
+                .chunk_by(|a, b| {
+                    partition.partition(a.timestamp)
+                        == partition.partition(b.timestamp)
+                })
+                .collect(),
+        };
+        for batch in batches {
+            let path = batch
+                .first()
+                .map_or_else(|| sink.path.clone(), |r| sink.file(r.timestamp));
+            create_parent_dir(&path)?;
+            let mut writer = RecordWriter::create(&path, sink.serializer)?;
+            writer.write(batch)?;
+            writer.finish()?;
+            set_permissions(sink.root(), &path, self.config.permissions)?;
+        }
+        Ok(())
+    }
+
+    /// Record in the checkpoint that `from..=to` has been written to all of
+    /// `sinks`
+    async fn complete(
+        &self,
+        sinks: &[Sink<'_>],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<(), error::FileIo> {
+        let mut checkpoint = self.checkpoint.lock().await;
+        for sink in sinks {
+            checkpoint.complete(&sink.key, from, to);
+        }
+        let path = checkpoint_path(&self.config);
+        checkpoint.save(&path).await?;
+        set_permissions(&self.config.output_dir, &path, self.config.permissions)
+    }
+
+    /// Re-request the periods missing from `timestamps`, the timestamps of
+    /// the records fetched for `request`, and merge whatever Polygon returns
+    /// into the files of `sinks`
+    #[instrument(skip_all, err, fields(ticker = %request.ticker))]
+    async fn fill_gaps(
+        &self,
+        request: &AggregateRequest<'_>,
+        sinks: &[Sink<'_>],
+        mut timestamps: Vec<i64>,
+        newest_first: bool,
+    ) -> Result<(), Error> {
+        let mut filled = Vec::new();
+        for attempt in 1..=self.config.max_fill_attempts {
+            timestamps.sort_unstable();
+            timestamps.dedup();
+            let gaps = gaps::find_gaps_with_multiplier(
+                request.timespan,
+                request.multiplier,
+                &timestamps,
+            );
+            if gaps.is_empty() {
+                break;
+            }
+            info!(attempt, num_gaps = gaps.len(), "Filling gaps");
+
+            let mut num_found = 0;
+            for gap in gaps {
+                let request = AggregateRequest {
+                    from: gap.start + TimeDelta::milliseconds(1),
+                    to: gap.end - TimeDelta::milliseconds(1),
+                    next_url: None,
+                    ..request.clone()
+                };
+                let records: Vec<_> =
+                    self.get_aggregates(request).await.try_concat().await?;
+                num_found += records.len();
+                timestamps.extend(records.iter().map(|r| r.timestamp));
+                filled.extend(records);
+            }
+            if num_found == 0 {
+                // the remaining gaps are most likely holidays or periods
+                // without any trades, re-requesting them won't help
+                debug!("Found no data in any of the gaps");
+                break;
+            }
+        }
+        if matches!(request.timespan, Timespan::Day) && request.multiplier == 1
+        {
+            timestamps.sort_unstable();
+            timestamps.dedup();
+            let days: Vec<_> = gaps::find_gaps(Timespan::Day, &timestamps)
+                .iter()
+                .flat_map(gaps::missing_weekdays)
+                .map(|day| day.to_string())
+                .collect();
+            if !days.is_empty() {
+                info!(
+                    days = %days.join(","),
+                    "Weekdays without any data, likely market holidays"
+                );
             }
-            progress_bar.inc(1);
-            sleep(Duration::from_millis(20)).await
         }
 
+        if !filled.is_empty() {
+            info!(num_records = filled.len(), "Merging records into file");
+            self.merge_into_sinks(sinks, filled, newest_first)?;
+        }
+        Ok(())
+    }
+}
+
+/// Wait until `paused` is false, see [`Service::pause`]
+async fn wait_until_resumed(paused: &mut watch::Receiver<bool>) {
+    // The sender lives as long as the service, so this can't fail while
+    // there's anything left to fetch
+    let _ = paused.wait_for(|paused| !paused).await;
+}
+
+/// Open the file of every sink for appending, creating the directories up to
+/// it. Symlinked directories anywhere in the path, including the output
+/// directory itself, are followed, so files land wherever they point to.
+/// The files of partitions are only opened once there's something to write
+/// to them
+async fn open_writers<'a>(
+    sinks: &[Sink<'a>],
+    permissions: Option<u32>,
+) -> Result<Vec<SinkWriter<'a>>, Error> {
+    let mut writers = Vec::with_capacity(sinks.len());
+    for sink in sinks {
+        let mut writer = SinkWriter {
+            sink: sink.clone(),
+            permissions,
+            current: None,
+        };
+        if sink.partition == PartitionBy::None {
+            fs::create_dir_all(parent_dir(&sink.path)?)
+                .await
+                .map_err(error::FileIo::CreateFile)?;
+            writer.open(sink.path.clone())?;
+        }
+        writers.push(writer);
+    }
+    Ok(writers)
+}
+
+/// The directory `path` is in
+fn parent_dir(path: &Path) -> Result<&Path, error::FileIo> {
+    path.parent()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Output directory must have at least one parent",
+            )
+        })
+        .map_err(error::FileIo::CreateFile)
+}
+
+/// Create the directories up to `path`
+fn create_parent_dir(path: &Path) -> Result<(), error::FileIo> {
+    std::fs::create_dir_all(parent_dir(path)?)
+        .map_err(error::FileIo::CreateFile)
+}
+
+/// Where the records of a ticker are written with one of the serializers
+#[derive(Clone)]
+struct Sink<'a> {
+    /// The path of the file relative to the output directory, also its key
+    /// in the checkpoint. With partitions, the files are in a folder named
+    /// after the file instead, see [`Sink::file`]
+    key: String,
+    path: PathBuf,
+    serializer: &'a dyn RecordSerializer,
+    /// Whether the file gets daily bars derived from the records instead of
+    /// the records themselves
+    daily: bool,
+    partition: PartitionBy,
+}
+
+/// Appends to the file of a sink, or to the file of the partition of each
+/// record, one partition after another
+struct SinkWriter<'a> {
+    sink: Sink<'a>,
+    permissions: Option<u32>,
+    /// The file being written to, with its writer
+    current: Option<(PathBuf, RecordWriter<'a>)>,
+}
+
+impl<'a> SinkWriter<'a> {
+    /// Switch to appending to `path`, finishing the file before
+    fn open(&mut self, path: PathBuf) -> Result<(), Error> {
+        if let Some((_, writer)) = self.current.take() {
+            writer.finish()?;
+        }
+        let writer = RecordWriter::append(&path, self.sink.serializer)?;
+        set_permissions(self.sink.root(), &path, self.permissions)?;
+        self.current = Some((path, writer));
+        Ok(())
+    }
+
+    fn write(&mut self, records: &[AggregateRecord]) -> Result<(), Error> {
+        let partition = self.sink.partition;
+        let batches = records.chunk_by(|a, b| {
+            partition.partition(a.timestamp) == partition.partition(b.timestamp)
+        });
+        for batch in batches {
+            let path = self.sink.file(batch[0].timestamp);
+            if self.current.as_ref().is_none_or(|(open, _)| *open != path) {
+                create_parent_dir(&path)?;
+                self.open(path)?;
+            }
+            let (_, writer) = self.current.as_mut().expect("opened");
+            writer.write(batch)?;
+        }
         Ok(())
     }
+
+    fn finish(self) -> Result<(), Error> {
+        match self.current {
+            Some((_, writer)) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+type DailyResampler = Resampler<i64, fn(&AggregateRecord) -> i64>;
+
+/// Write a batch of `records` to `out` with `serializer`, starting with what
+/// comes before the first record if nothing has been written yet
+fn write_records(
+    serializer: &dyn RecordSerializer,
+    out: &mut dyn Write,
+    records: &[AggregateRecord],
+    first: bool,
+) -> Result<(), Error> {
+    let Some(record) = records.first() else {
+        return Ok(());
+    };
+    if first {
+        serializer.begin(record, out)?;
+    } else {
+        out.write_all(serializer.separator())
+            .map_err(error::FileIo::FileWrite)?;
+    }
+    serializer.serialize_all(records, out)
+}
+
+/// Keeps the records appended to a file strictly ordered by timestamp, in
+/// the order the file is written in, whatever order they're fetched in.
+/// Records that come in too late to be appended are held back, to be merged
+/// into the file once it's written
+struct Reorder {
+    newest_first: bool,
+    /// The timestamp of the last record let through
+    last: Option<i64>,
+    late: Vec<AggregateRecord>,
+}
+
+impl Reorder {
+    fn new(newest_first: bool) -> Self {
+        Self {
+            newest_first,
+            last: None,
+            late: Vec::new(),
+        }
+    }
+
+    /// Sort a batch of `records`, returning the ones that can be appended
+    /// after the records let through so far and holding back the rest
+    fn order(
+        &mut self,
+        mut records: Vec<AggregateRecord>,
+    ) -> Vec<AggregateRecord> {
+        records.sort_by_key(|record| record.timestamp);
+        if self.newest_first {
+            records.reverse();
+        }
+        records.dedup_by_key(|record| record.timestamp);
+        let (in_order, late): (Vec<_>, _) =
+            records.into_iter().partition(|record| match self.last {
+                Some(last) if self.newest_first => record.timestamp < last,
+                Some(last) => record.timestamp > last,
+                None => true,
+            });
+        self.late.extend(late);
+        if let Some(record) = in_order.last() {
+            self.last = Some(record.timestamp);
+        }
+        in_order
+    }
+
+    /// The records held back
+    fn into_late(self) -> Vec<AggregateRecord> {
+        self.late
+    }
+}
+
+/// Write `records` to the writer of every sink, and the daily bars they
+/// complete to the writers of the daily sinks. Up to `concurrency` sinks
+/// are written at the same time
+fn write_batch(
+    writers: &mut [SinkWriter<'_>],
+    sinks: &[Sink<'_>],
+    daily: Option<&mut DailyResampler>,
+    records: &[AggregateRecord],
+    concurrency: usize,
+) -> Result<(), Error> {
+    let days: Vec<_> = daily
+        .map(|resampler| resampler.push(records))
+        .transpose()?
+        .unwrap_or_default()
+        .into_iter()
+        .map(daily_bar)
+        .collect();
+    let write = |writer: &mut SinkWriter<'_>, sink: &Sink<'_>| {
+        if !sink.daily {
+            writer.write(records)
+        } else if !days.is_empty() {
+            writer.write(&days)
+        } else {
+            Ok(())
+        }
+    };
+    if concurrency <= 1 || writers.len() <= 1 {
+        return writers
+            .iter_mut()
+            .zip(sinks)
+            .try_for_each(|(writer, sink)| write(writer, sink));
+    }
+    // Writing is blocking IO, so each sink gets a thread of its own for the
+    // batch, for a slow one not to hold up the others
+    for (writers, sinks) in writers
+        .chunks_mut(concurrency)
+        .zip(sinks.chunks(concurrency))
+    {
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = writers
+                .iter_mut()
+                .zip(sinks)
+                .map(|(writer, sink)| scope.spawn(|| write(writer, sink)))
+                .collect();
+            // The scope waits for the rest if one of them fails
+            threads.into_iter().try_for_each(|thread| {
+                thread.join().expect("writing doesn't panic")
+            })
+        })?;
+    }
+    Ok(())
+}
+
+/// A bar combined from all the bars of a day, starting at midnight in New
+/// York
+fn daily_bar(mut bar: AggregateRecord) -> AggregateRecord {
+    bar.timestamp = resample::new_york_day(&bar);
+    bar
+}
+
+impl Sink<'_> {
+    /// The output directory the file is in
+    fn root(&self) -> &Path {
+        let depth = Path::new(&self.key).components().count();
+        self.path.ancestors().nth(depth).unwrap_or(&self.path)
+    }
+
+    /// The file the record at `timestamp` goes in, e.g.
+    /// `$ticker/day/2024/01/02.csv` for the daily partitions of
+    /// `$ticker/day.csv`
+    fn file(&self, timestamp: i64) -> PathBuf {
+        let Some(date) = self.partition.partition(timestamp) else {
+            return self.path.clone();
+        };
+        let extension = self.serializer.extension();
+        let (year, month, day) = (date.year(), date.month(), date.day());
+        let name = match self.partition {
+            PartitionBy::Month => format!("{year:04}/{month:02}.{extension}"),
+            _ => format!("{year:04}/{month:02}/{day:02}.{extension}"),
+        };
+        self.path.with_extension("").join(name)
+    }
+
+    /// Every file of the sink there is so far, oldest first
+    fn files(&self) -> Result<Vec<PathBuf>, error::FileIo> {
+        if self.partition == PartitionBy::None {
+            return Ok(vec![self.path.clone()]);
+        }
+        let mut files = Vec::new();
+        let mut dirs = vec![self.path.with_extension("")];
+        while let Some(dir) = dirs.pop() {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(error::FileIo::ReadFile(e)),
+            };
+            for entry in entries {
+                let path = entry.map_err(error::FileIo::ReadFile)?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().and_then(|e| e.to_str())
+                    == Some(self.serializer.extension())
+                {
+                    files.push(path);
+                }
+            }
+        }
+        // Years, months and days are zero padded, so sorting by name sorts
+        // by date
+        files.sort();
+        Ok(files)
+    }
+
+    /// The timestamp of the last record written, which is in the last file
+    /// or in the first one for files written `newest_first`
+    fn last_timestamp(
+        &self,
+        unit: TimestampUnit,
+        newest_first: bool,
+    ) -> Result<Option<i64>, error::FileIo> {
+        let files = self.files()?;
+        let file = if newest_first {
+            files.first()
+        } else {
+            files.last()
+        };
+        match file {
+            Some(file) => reader::last_timestamp(file, unit),
+            None => Ok(None),
+        }
+    }
 }
 
-// According to Polygon docs, it should work
-/// Estimate the number of chunks for the given `timespan` and the time interval
+/// Estimate the number of chunks, pages of up to `limit` bars, that the bars
+/// of `multiplier` `timespan`s from `from` to `to` come back in. This is the
+/// most there can be, markets aren't open around the clock. Even a range
+/// without any bars takes a request, so it's at least 1 unless `to` is
+/// before `from`
 fn num_chunks(
     timespan: Timespan,
+    multiplier: u32,
     from: DateTime<Utc>,
     to: DateTime<Utc>,
     limit: u32,
 ) -> i64 {
+    if to < from {
+        return 0;
+    }
     let duration = to - from;
-    // according to https://polygon.io/blog/aggs-api-updates
-    let num_intervals = match timespan {
-        // tbh I'm not sure Polygon's behavior for seconds, beware this is untested
+    let months = i64::from(to.year() - from.year()) * 12
+        + i64::from(to.month())
+        - i64::from(from.month());
+    // according to https://polygon.io/blog/aggs-api-updates, the bars
+    // starting at `from` and at `to` are both included
+    let num_intervals = 1 + match timespan {
         Timespan::Second => duration.num_seconds(),
         Timespan::Minute => duration.num_minutes(),
         Timespan::Hour => duration.num_hours(),
         Timespan::Day => duration.num_days(),
         Timespan::Week => duration.num_weeks(),
-        // the following branches are obviously technically incorrect,
-        // but this is just used for an estimate so it's fine
-        Timespan::Month => duration.num_weeks() / 4,
-        Timespan::Quarter => duration.num_weeks() / 12,
-        Timespan::Year => duration.num_days() / 365,
+        Timespan::Month => months,
+        Timespan::Quarter => months / 3,
+        Timespan::Year => i64::from(to.year() - from.year()),
     };
+    let ceil_div = |n: i64, d: i64| (n + d - 1) / d;
+    let num_bars = ceil_div(num_intervals, i64::from(multiplier.max(1)));
+    ceil_div(num_bars, i64::from(limit.max(1))).max(1)
+}
 
-    num_intervals / i64::from(limit)
+/// Shuffle `tickers`, deterministically if a `seed` is given
+fn shuffle(tickers: &mut [&str], seed: Option<u64>) {
+    match seed {
+        Some(seed) => tickers.shuffle(&mut StdRng::seed_from_u64(seed)),
+        None => tickers.shuffle(&mut rand::thread_rng()),
+    }
 }
 
-fn create_or_open_file(file_path: PathBuf) -> Result<File, error::FileIo> {
-    OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(file_path)
-        .map_err(error::FileIo::CreateFile)
+/// Every day from `from` to `to`, inclusive
+fn days(from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<NaiveDate> {
+    from.date_naive()
+        .iter_days()
+        .take_while(|day| *day <= to.date_naive())
+        .collect()
+}
+
+/// Split `from..=to` into consecutive ranges that each fall within a single
+/// calendar month
+fn monthly_ranges(from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<DateRange> {
+    let mut ranges = Vec::new();
+    let mut start = from;
+    while start <= to {
+        let next_month = start
+            .date_naive()
+            .with_day(1)
+            .and_then(|date| date.checked_add_months(Months::new(1)))
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|date| date.and_utc());
+        let Some(next_month) = next_month else {
+            ranges.push((start, to));
+            break;
+        };
+        ranges.push((start, to.min(next_month - TimeDelta::milliseconds(1))));
+        start = next_month;
+    }
+    ranges
+}
+
+/// Split `from..=to` up into UTC days, the days of [`PartitionBy::Day`]
+fn daily_ranges(from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<DateRange> {
+    let mut ranges = Vec::new();
+    let mut start = from;
+    while start <= to {
+        let next_day = start
+            .date_naive()
+            .succ_opt()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|date| date.and_utc());
+        let Some(next_day) = next_day else {
+            ranges.push((start, to));
+            break;
+        };
+        ranges.push((start, to.min(next_day - TimeDelta::milliseconds(1))));
+        start = next_day;
+    }
+    ranges
+}
+
+/// Write the raw body of a response to `$dir/$timespan/$from-$to/$page.json`,
+/// where `dir` is the ticker's folder and `from` and `to` are the request's
+/// range in milliseconds, like in its URL
+async fn save_raw_response(
+    dir: &Path,
+    request: &AggregateRequest<'_>,
+    page: usize,
+    body: &str,
+    permissions: Option<u32>,
+) -> Result<(), Error> {
+    let parent_dir = dir
+        .join(request.timespan.with_multiplier(request.multiplier))
+        .join(format!(
+            "{}-{}",
+            request.from.timestamp_millis(),
+            request.to.timestamp_millis()
+        ));
+    fs::create_dir_all(&parent_dir)
+        .await
+        .map_err(error::FileIo::CreateFile)?;
+    let path = parent_dir.join(format!("{page:05}.json"));
+    fs::write(&path, body)
+        .await
+        .map_err(error::FileIo::FileWrite)?;
+    set_permissions(dir.parent().unwrap_or(dir), &path, permissions)?;
+    Ok(())
+}
+
+/// Set the Unix mode of the file at `path` and of the directories between it
+/// and `root` to `mode`. Directories also get the execute bit wherever
+/// they're readable, so that they can still be listed
+fn set_permissions(
+    root: &Path,
+    path: &Path,
+    mode: Option<u32>,
+) -> Result<(), error::FileIo> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir_mode = mode | ((mode & 0o444) >> 2);
+        let dirs = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(root) && *dir != root);
+        for (path, mode) in
+            std::iter::once((path, mode)).chain(dirs.map(|dir| (dir, dir_mode)))
+        {
+            std::fs::set_permissions(
+                path,
+                std::fs::Permissions::from_mode(mode),
+            )
+            .map_err(error::FileIo::FileWrite)?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (root, path, mode);
+    Ok(())
+}
+
+/// Where the manifest of a run is saved, `$output_dir/manifest-$span.json`,
+/// so runs of other spans into the same folder keep theirs
+fn manifest_path(config: &Config) -> PathBuf {
+    let span = config.timespan.with_multiplier(config.multiplier);
+    config.output_dir.join(format!("manifest-{span}.json"))
+}
+
+/// Where the checkpoint is loaded from and saved to
+fn checkpoint_path(config: &Config) -> PathBuf {
+    match &config.checkpoint {
+        Some(path) => path.clone(),
+        None => config.output_dir.join(CHECKPOINT_FILE),
+    }
+}
+
+/// Names on Windows that refer to devices rather than files, with or without
+/// an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6",
+    "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+    "LPT7", "LPT8", "LPT9",
+];
+
+/// The longest a ticker's folder name can be, in bytes. Most filesystems
+/// allow 255, this leaves room for anything appended to it
+const MAX_SEGMENT_LEN: usize = 200;
+
+/// Turn `ticker` into a folder name that's valid on every OS. Characters
+/// Windows doesn't allow in paths, like the `:` in `X:BTCUSD`, and control
+/// characters are replaced with `_`, as are trailing dots and spaces. Names
+/// reserved for devices on Windows start with an extra `_`, and long tickers are
+/// cut short.
+fn sanitize_path_segment(ticker: &str) -> Cow<'_, str> {
+    let is_hostile = |c: char| {
+        c.is_control()
+            || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')
+    };
+    let stem = ticker.split('.').next().unwrap_or_default();
+    let reserved = RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem.trim_end()));
+    if !ticker.is_empty()
+        && ticker.len() <= MAX_SEGMENT_LEN
+        && !ticker.contains(is_hostile)
+        && !ticker.ends_with(['.', ' '])
+        && !reserved
+    {
+        return Cow::Borrowed(ticker);
+    }
+
+    let mut end = ticker.len().min(MAX_SEGMENT_LEN);
+    while !ticker.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut segment: String = ticker[..end]
+        .chars()
+        .map(|c| if is_hostile(c) { '_' } else { c })
+        .collect();
+    let kept = segment.trim_end_matches(['.', ' ']).len();
+    let trailing = segment.len() - kept;
+    segment.truncate(kept);
+    segment.extend(std::iter::repeat_n('_', trailing));
+    if segment.is_empty() || reserved {
+        segment.insert(0, '_');
+    }
+    Cow::Owned(segment)
+}
+
+/// Where Polygon's flat files keep the daily aggregates of `date`, relative
+/// to the root of the bucket. Polygon gzips its files, these are left as is.
+fn flat_file_path(date: NaiveDate) -> PathBuf {
+    PathBuf::from(format!(
+        "us_stocks_sip/day_aggs_v1/{:04}/{:02}/{date}.csv",
+        date.year(),
+        date.month()
+    ))
+}
+
+/// Run `work` on every one of `items` with a pool of `workers`, each taking
+/// the next item off a shared queue whenever it finishes the one before
+async fn drain<T, F, Fut>(
+    items: impl IntoIterator<Item = T>,
+    workers: usize,
+    work: F,
+) where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let queue = Mutex::new(items.into_iter());
+    let worker = || async {
+        loop {
+            let next = queue.lock().expect("not poisoned").next();
+            let Some(item) = next else { break };
+            work(item).await;
+        }
+    };
+    future::join_all((0..workers.max(1)).map(|_| worker())).await;
+}
+
+/// The first `last` records of `pages`, if set. The stream ends as soon as
+/// there are that many, without asking for another page
+fn take_records<'a>(
+    pages: BoxStream<'a, Result<Vec<AggregateRecord>, Error>>,
+    last: Option<usize>,
+) -> BoxStream<'a, Result<Vec<AggregateRecord>, Error>> {
+    let Some(last) = last else { return pages };
+    stream::unfold((pages, last), |(mut pages, remaining)| async move {
+        if remaining == 0 {
+            debug!("Got the most recent bars, stopping early");
+            return None;
+        }
+        let mut result = pages.next().await?;
+        let remaining = match &mut result {
+            Ok(records) => {
+                records.truncate(remaining);
+                remaining - records.len()
+            }
+            Err(_) => remaining,
+        };
+        Some((result, (pages, remaining)))
+    })
+    .boxed()
+}
+
+/// Feed `stream` into a channel that holds up to `capacity` items, so what
+/// comes next can be fetched while the items before are being written. The
+/// future has to be polled alongside the receiver, and stops once the
+/// receiver is dropped
+fn fetch_ahead<'a, T: Send + 'a>(
+    mut stream: BoxStream<'a, T>,
+    capacity: usize,
+) -> (impl Future<Output = ()> + 'a, mpsc::Receiver<T>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let fetch = async move {
+        while let Some(item) = stream.next().await {
+            if tx.send(item).await.is_err() {
+                break;
+            }
+        }
+    };
+    (fetch, rx)
+}
+
+/// Add `permits` to `semaphore` one at a time, evenly spread over `duration`
+async fn ramp_up(
+    semaphore: Arc<Semaphore>,
+    permits: usize,
+    duration: Duration,
+) {
+    let step = duration / u32::try_from(permits.max(1)).unwrap_or(u32::MAX);
+    for _ in 0..permits {
+        sleep(step).await;
+        semaphore.add_permits(1);
+    }
+}
+
+/// Check that the timestamps of `records` strictly increase, starting after
+/// `last`, and leave `last` at the final timestamp
+fn check_monotonic(
+    check: MonotonicCheck,
+    last: &mut Option<i64>,
+    records: &[AggregateRecord],
+) -> Result<(), Error> {
+    for record in records {
+        let timestamp = record.timestamp;
+        match *last {
+            Some(previous) if timestamp <= previous => match check {
+                MonotonicCheck::Warn => {
+                    warn!(previous, timestamp, "Timestamps out of order");
+                }
+                MonotonicCheck::Error => {
+                    return Err(Error::NonMonotonic {
+                        previous,
+                        timestamp,
+                    });
+                }
+            },
+            _ => {}
+        }
+        *last = Some(last.map_or(timestamp, |last| last.max(timestamp)));
+    }
+    Ok(())
+}
+
+/// Check that no two of `records`, from a single page, have the same
+/// timestamp, dropping all but the first of each with
+/// [`DuplicateCheck::Drop`]
+fn check_page_duplicates(
+    check: DuplicateCheck,
+    records: &mut Vec<AggregateRecord>,
+) -> Result<(), Error> {
+    let mut seen = HashSet::new();
+    let duplicates: Vec<_> = records
+        .iter()
+        .map(|record| record.timestamp)
+        .filter(|timestamp| !seen.insert(*timestamp))
+        .collect();
+    let Some(&first) = duplicates.first() else {
+        return Ok(());
+    };
+    match check {
+        DuplicateCheck::Warn => {
+            warn!(
+                duplicates = duplicates.len(),
+                first, "Duplicate timestamps in a page"
+            );
+        }
+        DuplicateCheck::Drop => {
+            warn!(
+                duplicates = duplicates.len(),
+                first, "Dropping duplicate timestamps in a page"
+            );
+            let mut seen = HashSet::new();
+            records.retain(|record| seen.insert(record.timestamp));
+        }
+        DuplicateCheck::Error => return Err(Error::DuplicateTimestamp(first)),
+    }
+    Ok(())
+}
+
+/// Move the start of `range` up to the horizon of the market `ticker` trades
+/// in, or skip the range entirely if it ends before the horizon
+fn clamp_to_horizon(
+    horizons: &BTreeMap<Market, NaiveDate>,
+    ticker: &str,
+    (from, to): DateRange,
+) -> Option<DateRange> {
+    let market = Market::of(ticker);
+    let Some(horizon) = horizons
+        .get(&market)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|datetime| datetime.and_utc())
+    else {
+        return Some((from, to));
+    };
+    if to < horizon {
+        warn!(%ticker, %market, %horizon, "Skipping range before the market's horizon");
+        None
+    } else if from < horizon {
+        warn!(%ticker, %market, %horizon, "Moving the start of range up to the market's horizon");
+        Some((horizon, to))
+    } else {
+        Some((from, to))
+    }
+}
+
+/// Make sure `from` isn't after `to`, swapping them with a warning if they're
+/// reversed and `swap` is set
+fn check_range(
+    from: &mut DateTime<Utc>,
+    to: &mut DateTime<Utc>,
+    swap: bool,
+) -> Result<(), error::Init> {
+    if from <= to {
+        return Ok(());
+    } else if !swap {
+        return Err(error::Init::ReversedRange(*from, *to));
+    }
+    warn!(from = %from, to = %to, "Swapping the ends of a reversed range");
+    std::mem::swap(from, to);
+    Ok(())
+}
+
+/// Warn about each range of `config` that the market of none of the tickers
+/// is open on
+fn warn_on_ranges_without_trading_days(config: &Config) {
+    let markets: BTreeSet<_> = config
+        .tickers
+        .iter()
+        .map(|ticker| Market::of(ticker))
+        .collect();
+    for (from, to) in windows((config.from, config.to), &config.extra_ranges) {
+        for market in &markets {
+            let (from, to) = (from.date_naive(), to.date_naive());
+            if calendar::trading_days(*market, from, to).next().is_none() {
+                warn!(
+                    %from,
+                    %to,
+                    %market,
+                    "No trading days in range, so there's nothing to download. \
+                     Check --from and --to aren't on a weekend or holiday"
+                );
+            }
+        }
+    }
+}
+
+/// All the windows to fetch for each ticker, in order so that appending each
+/// one to the output keeps the output sorted
+fn windows(range: DateRange, extra_ranges: &[DateRange]) -> Vec<DateRange> {
+    let mut windows: Vec<_> = std::iter::once(range)
+        .chain(extra_ranges.iter().copied())
+        .collect();
+    windows.sort();
+    windows
+}
+
+/// Where to draw the progress bar, see [`Config::progress`]
+fn progress_target(progress: Option<bool>) -> ProgressDrawTarget {
+    match progress {
+        // Unlike `ProgressDrawTarget::stderr`, draws whether or not stderr
+        // is a terminal
+        Some(true) => ProgressDrawTarget::term_like_with_hz(
+            Box::new(Term::buffered_stderr()),
+            20,
+        ),
+        Some(false) => ProgressDrawTarget::hidden(),
+        None => ProgressDrawTarget::stderr(),
+    }
+}
+
+/// Show in the progress bar that a request is backing off, since the bar
+/// would otherwise look frozen
+fn show_retry_state(progress_bar: &ProgressBar, state: RetryState) {
+    match state {
+        RetryState::Backoff { delay, .. } => {
+            progress_bar.set_message(format!("retrying (backoff {delay:.0?})"))
+        }
+        RetryState::Retrying => progress_bar.set_message(""),
+    }
 }
 
 fn style() -> ProgressStyle {
@@ -222,10 +2985,1705 @@ fn style() -> ProgressStyle {
 
 #[cfg(test)]
 mod tests {
-    use super::style;
+    use std::{
+        io::IsTerminal,
+        num::{NonZeroU32, NonZeroUsize},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
 
-    #[test]
+    use chrono::{DateTime, Datelike, NaiveDate, TimeDelta, TimeZone, Utc};
+    use futures::{stream, StreamExt, TryStreamExt};
+    use indicatif::ProgressBar;
+    use reqwest::StatusCode;
+    use rust_decimal::Decimal;
+    use tokio::sync::Semaphore;
+    use wiremock::{
+        matchers::{method, path, path_regex, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{
+        check_monotonic, check_page_duplicates, clamp_to_horizon, daily_bar,
+        drain, fetch_ahead, flat_file_path, monthly_ranges, num_chunks,
+        open_writers, progress_target, ramp_up, sanitize_path_segment,
+        show_retry_state, shuffle, style, windows, write_batch, write_records,
+        DailyResampler, Reorder, Service, Sink, MAX_SEGMENT_LEN,
+    };
+    use crate::{
+        checkpoint::Checkpoint,
+        client::{Client, DEFAULT_AGGREGATES_VERSION},
+        config::{default_horizons, Config},
+        error::Error,
+        http_sink::HttpSink,
+        reader, resample,
+        retry::RetryState,
+        serializer::{RecordSerializer, RecordWriter},
+        test_util::TempOutput,
+        types::{
+            AggregateRecord, AggregateRequestBuilder, CsvQuoting,
+            DuplicateCheck, FlatFileRecord, GroupedDailyRecord, MonotonicCheck,
+            OutputFormat, PartitionBy, SortOrder, Timespan, TimestampUnit,
+        },
+    };
+
+    fn config(output_dir: &Path) -> Config {
+        Config {
+            tickers: vec!["SPY".to_string()],
+            max_tickers: None,
+            shuffle: false,
+            seed: None,
+            timespan: Timespan::Day,
+            multiplier: 1,
+            output_dir: output_dir.to_path_buf(),
+            partition_by: PartitionBy::None,
+            formats: vec![OutputFormat::Csv],
+            data_dictionary: false,
+            timestamp_unit: TimestampUnit::Millis,
+            with_datetime: false,
+            with_date_and_time: false,
+            from: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            to: Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap(),
+            horizons: None,
+            extra_ranges: Vec::new(),
+            swap_reversed_ranges: false,
+            full_history: false,
+            metadata_cache: None,
+            metadata_ttl: Duration::ZERO,
+            warn_on_weekend_range: false,
+            aggregates_version: DEFAULT_AGGREGATES_VERSION.to_string(),
+            base_url: None,
+            pinned_certificates: Vec::new(),
+            http_version: None,
+            limit: 5_000,
+            save_raw: None,
+            retry_server_errors: false,
+            max_retries: 3,
+            retry_messages: Vec::new(),
+            concurrency: 10,
+            write_buffer_size: 4,
+            write_concurrency: 1,
+            ticker_parallelism: 1,
+            ramp_up: None,
+            fill_gaps: false,
+            max_fill_attempts: 3,
+            summary_stats: false,
+            validate: false,
+            resume: false,
+            overwrite: false,
+            revision_window: None,
+            monotonic: None,
+            page_duplicates: None,
+            strict_order: true,
+            schema_check: None,
+            flat_file_layout: false,
+            grouped_by_ticker: false,
+            raw_ticker_paths: false,
+            checkpoint: None,
+            sort: SortOrder::Asc,
+            adjusted: true,
+            adjusted_as_of: None,
+            reverse_descending: false,
+            coalesce_requests: false,
+            permissions: None,
+            derive_daily: false,
+            last: None,
+            requests_per_minute: None,
+            max_requests_per_ticker: None,
+            push_metrics: None,
+            progress_events: None,
+            progress: None,
+            csv_trailing_newline: true,
+            csv_bom: false,
+            csv_quoting: CsvQuoting::Necessary,
+            csv_delimiter: ',',
+            csv_decimal_comma: false,
+            compression_level: None,
+            abort_on_rate_limit: false,
+            min_success_rate: None,
+        }
+    }
+
+    fn record(timestamp: i64) -> AggregateRecord {
+        AggregateRecord {
+            timestamp,
+            open: Decimal::ONE,
+            high: Decimal::ONE,
+            low: Decimal::ONE,
+            close: Decimal::ONE,
+            volume: Decimal::ONE,
+            transactions: None,
+            otc: None,
+            vwap: None,
+        }
+    }
+
+    #[test]
     fn style_is_valid() {
         let _ = style();
     }
+
+    #[test]
+    fn backoff_is_shown_in_the_progress_bar() {
+        let progress_bar = ProgressBar::hidden();
+        show_retry_state(
+            &progress_bar,
+            RetryState::Backoff {
+                attempt: 2,
+                delay: Duration::from_millis(4200),
+            },
+        );
+        assert_eq!(progress_bar.message(), "retrying (backoff 4s)");
+        show_retry_state(&progress_bar, RetryState::Retrying);
+        assert_eq!(progress_bar.message(), "");
+    }
+
+    #[test]
+    fn progress_bar_can_be_forced_on_or_off() {
+        assert!(!progress_target(Some(true)).is_hidden());
+        assert!(progress_target(Some(false)).is_hidden());
+        assert_eq!(
+            progress_target(None).is_hidden(),
+            !std::io::stderr().is_terminal()
+        );
+    }
+
+    #[test]
+    fn shuffle_with_seed_is_deterministic() {
+        let tickers = ["AAPL", "AMZN", "GOOG", "META", "MSFT", "NVDA", "TSLA"];
+        let mut first = tickers;
+        let mut second = tickers;
+        shuffle(&mut first, Some(42));
+        shuffle(&mut second, Some(42));
+        assert_eq!(first, second);
+        assert_ne!(first, tickers);
+    }
+
+    #[test]
+    fn monthly_ranges_split_on_month_boundaries() {
+        let from = Utc.with_ymd_and_hms(2023, 12, 15, 0, 0, 0).unwrap();
+        let to = Utc.with_ymd_and_hms(2024, 2, 10, 0, 0, 0).unwrap();
+        let ranges = monthly_ranges(from, to);
+        let january = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let february = Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap();
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].0, from);
+        assert_eq!(ranges[1].0, january);
+        assert_eq!(ranges[2], (february, to));
+        assert!(ranges[0].1 < january);
+        assert!(ranges[1].1 < february);
+    }
+
+    #[test]
+    fn windows_are_fetched_in_order() {
+        let day = |d| Utc.with_ymd_and_hms(2024, 3, d, 0, 0, 0).unwrap();
+        let windows = windows((day(20), day(22)), &[(day(1), day(3))]);
+        assert_eq!(windows, [(day(1), day(3)), (day(20), day(22))]);
+    }
+
+    #[tokio::test]
+    async fn ramp_up_adds_permits_gradually() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let ramp_up = tokio::spawn(ramp_up(
+            semaphore.clone(),
+            9,
+            Duration::from_millis(450),
+        ));
+        assert_eq!(semaphore.available_permits(), 1);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let midway = semaphore.available_permits();
+        assert!(1 < midway && midway < 10, "{midway} permits midway");
+        ramp_up.await.unwrap();
+        assert_eq!(semaphore.available_permits(), 10);
+    }
+
+    /// Mount a single page with a single bar at timestamp 1 on `server` for
+    /// the tickers matching `ticker`, a regex
+    async fn one_page(server: &MockServer, ticker: &str) {
+        Mock::given(path_regex(format!("^/v2/aggs/ticker/{ticker}/")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                serde_json::json!({
+                    "ticker": ticker,
+                    "adjusted": true,
+                    "queryCount": 1,
+                    "request_id": "1",
+                    "resultsCount": 1,
+                    "status": "OK",
+                    "results": [{"t": 1, "o": 1, "h": 1, "l": 1, "c": 1, "v": 10}],
+                }),
+            ))
+            .mount(server)
+            .await;
+    }
+
+    /// A service with `config` pointed at a mock server that has two pages
+    /// of SPY, with a single bar each at timestamps 1 and 2
+    async fn two_pages(config: Config) -> (MockServer, Service) {
+        let server = MockServer::start().await;
+        let page = |cursor: Option<&str>, timestamp: i64| {
+            serde_json::json!({
+                "ticker": "SPY",
+                "adjusted": true,
+                "queryCount": 1,
+                "request_id": "1",
+                "resultsCount": 1,
+                "status": "OK",
+                "results": [{"t": timestamp, "o": 1, "h": 1, "l": 1, "c": 1, "v": 10}],
+                "next_url": cursor.map(|cursor| {
+                    format!("https://api.polygon.io/v2/next?cursor={cursor}")
+                }),
+            })
+        };
+        Mock::given(path_regex("^/v2/aggs/ticker/SPY/range/"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(page(Some("2"), 1)),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/v2/next"))
+            .and(query_param("cursor", "2"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(page(None, 2)),
+            )
+            .mount(&server)
+            .await;
+        let service = Service::new(
+            Config {
+                base_url: Some(server.uri()),
+                ..config
+            },
+            "key",
+        )
+        .unwrap();
+        (server, service)
+    }
+
+    #[tokio::test]
+    async fn a_ticker_can_be_fetched_into_memory() {
+        let (_server, service) = two_pages(config(Path::new("data"))).await;
+        let mut out = Vec::new();
+        service.fetch_to_writer("SPY", &mut out).await.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "timestamp,open,high,low,close,volume\n1,1,1,1,1,10\n2,1,1,1,1,10\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_responses_of_each_window_are_kept_apart() {
+        let output_dir = TempOutput::new("raw");
+        let march = |d| Utc.with_ymd_and_hms(2024, 3, d, 0, 0, 0).unwrap();
+        let (_server, service) = two_pages(Config {
+            extra_ranges: vec![(march(1), march(10))],
+            save_raw: Some(output_dir.join("raw")),
+            ..config(&output_dir)
+        })
+        .await;
+        service.fetch_data().await.unwrap();
+
+        let windows = output_dir.join("raw/SPY/day");
+        let mut saved = Vec::new();
+        for window in std::fs::read_dir(&windows).unwrap() {
+            for page in std::fs::read_dir(window.unwrap().path()).unwrap() {
+                let page = page.unwrap().path();
+                saved.push(page.strip_prefix(&windows).unwrap().to_owned());
+            }
+        }
+        saved.sort();
+        let range = |from: DateTime<Utc>, to: DateTime<Utc>| {
+            format!("{}-{}", from.timestamp_millis(), to.timestamp_millis())
+        };
+        let (from, to) = (service.config.from, service.config.to);
+        let mut expected: Vec<PathBuf> =
+            [range(from, to), range(march(1), march(10))]
+                .iter()
+                .flat_map(|dir| {
+                    ["00000.json", "00001.json"]
+                        .map(|page| Path::new(dir).join(page))
+                })
+                .collect();
+        expected.sort();
+        assert_eq!(saved, expected);
+    }
+
+    #[tokio::test]
+    async fn prices_can_be_adjusted_as_of_a_date() {
+        let (server, service) = two_pages(Config {
+            adjusted_as_of: NaiveDate::from_ymd_opt(1970, 1, 5),
+            ..config(Path::new("data"))
+        })
+        .await;
+        Mock::given(path("/v3/reference/splits"))
+            .and(query_param("ticker", "SPY"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                serde_json::json!({
+                    "status": "OK",
+                    "results": [
+                        {"ticker": "SPY", "execution_date": "1970-01-02", "split_from": 1, "split_to": 2},
+                        {"ticker": "SPY", "execution_date": "1970-01-06", "split_from": 1, "split_to": 10},
+                    ],
+                }),
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut out = Vec::new();
+        service.fetch_to_writer("SPY", &mut out).await.unwrap();
+        // Only the split before the as-of date is adjusted for
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "timestamp,open,high,low,close,volume\n\
+             1,0.5,0.5,0.5,0.5,20\n2,0.5,0.5,0.5,0.5,20\n"
+        );
+        let requests = server.received_requests().await.unwrap();
+        assert!(requests
+            .iter()
+            .filter(|request| request.url.path().starts_with("/v2/aggs"))
+            .all(|request| request
+                .url
+                .query_pairs()
+                .any(|(key, value)| key == "adjusted" && value == "false")));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn metrics_of_each_ticker_are_pushed() {
+        let gateway = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/metrics/job/polygon-data/ticker/SPY"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&gateway)
+            .await;
+        let output_dir = TempOutput::new("metrics");
+        let (server, service) = two_pages(Config {
+            push_metrics: Some(gateway.uri()),
+            ..config(&output_dir)
+        })
+        .await;
+        Mock::given(path_regex("^/v2/aggs/"))
+            .respond_with(
+                ResponseTemplate::new(429).insert_header("retry-after", "0"),
+            )
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        service.fetch_data().await.unwrap();
+
+        let requests = gateway.received_requests().await.unwrap();
+        let body = String::from_utf8(requests[0].body.clone()).unwrap();
+        assert!(body.contains("\npolygon_data_records 2\n"));
+        assert!(body.contains("\npolygon_data_retries 1\n"));
+        assert!(body.contains("\npolygon_data_failed 0\n"));
+    }
+
+    #[tokio::test]
+    async fn runs_with_too_few_successful_tickers_fail() {
+        let server = MockServer::start().await;
+        Mock::given(path_regex("^/v2/aggs/ticker/E/"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        one_page(&server, ".+").await;
+        let output_dir = TempOutput::new("success-rate");
+        let run = |min_success_rate| {
+            let _ = std::fs::remove_dir_all(&output_dir);
+            let service = Service::new(
+                Config {
+                    tickers: ["A", "B", "C", "D", "E"].map(String::from).into(),
+                    base_url: Some(server.uri()),
+                    min_success_rate: Some(min_success_rate),
+                    ..config(&output_dir)
+                },
+                "key",
+            )
+            .unwrap();
+            async move { service.fetch_data().await }
+        };
+        assert!(matches!(
+            run(0.95).await,
+            Err(Error::LowSuccessRate {
+                succeeded: 4,
+                total: 5,
+                ..
+            })
+        ));
+        assert!(run(0.8).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn runs_of_several_spans_are_resumed_together() {
+        let server = MockServer::start().await;
+        one_page(&server, "SPY").await;
+        let output_dir = TempOutput::new("spans");
+        let service = |timespan, resume| {
+            Service::new(
+                Config {
+                    timespan,
+                    base_url: Some(server.uri()),
+                    resume,
+                    ..config(&output_dir)
+                },
+                "key",
+            )
+            .unwrap()
+        };
+        for timespan in [Timespan::Day, Timespan::Hour] {
+            service(timespan, false).fetch_data().await.unwrap();
+        }
+        let requests = server.received_requests().await.unwrap().len();
+        assert_eq!(requests, 2);
+
+        for timespan in [Timespan::Day, Timespan::Hour] {
+            let resumed = service(timespan, true);
+            assert!(resumed
+                .start_manifest(resumed.plan().await)
+                .await
+                .is_empty());
+            resumed.fetch_data().await.unwrap();
+        }
+        assert_eq!(server.received_requests().await.unwrap().len(), requests);
+        assert!(output_dir.join("manifest-day.json").exists());
+        assert!(output_dir.join("manifest-hour.json").exists());
+    }
+
+    #[tokio::test]
+    async fn cached_ticker_details_are_reused_by_later_runs() {
+        let server = MockServer::start().await;
+        Mock::given(path("/v3/reference/tickers/SPY"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                serde_json::json!({
+                    "status": "OK",
+                    "request_id": "1",
+                    "results": {
+                        "ticker": "SPY",
+                        "name": "SPDR S&P 500 ETF Trust",
+                        "market": "stocks",
+                        "list_date": "1993-01-29",
+                    },
+                }),
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let output_dir = TempOutput::new("metadata-cache");
+        let cache = output_dir.join("metadata.json");
+        let service = || {
+            Service::new(
+                Config {
+                    base_url: Some(server.uri()),
+                    full_history: true,
+                    metadata_cache: Some(cache.clone()),
+                    metadata_ttl: Duration::from_secs(60),
+                    ..config(&output_dir)
+                },
+                "key",
+            )
+            .unwrap()
+        };
+        let listed = Utc.with_ymd_and_hms(1993, 1, 29, 0, 0, 0).unwrap();
+        assert_eq!(service().plan().await[0].from, listed);
+        // The second run only reads the cache
+        let service = service();
+        assert_eq!(service.estimate()[0].requests, 1);
+        assert_eq!(service.plan().await[0].from, listed);
+    }
+
+    #[tokio::test]
+    async fn records_are_transformed_before_being_written() {
+        let (_server, service) = two_pages(Config {
+            formats: vec![OutputFormat::JsonArray],
+            ..config(Path::new("data"))
+        })
+        .await;
+        let service = service.with_transform(|mut record| {
+            record.vwap = Some((record.high + record.low) / Decimal::TWO);
+            record.timestamp *= 10;
+            record
+        });
+        let mut out = Vec::new();
+        service.fetch_to_writer("SPY", &mut out).await.unwrap();
+        let records: Vec<serde_json::Value> =
+            serde_json::from_slice(&out).unwrap();
+        assert_eq!(records[1]["timestamp"], 20);
+        assert_eq!(records[1]["vwap"], "1");
+    }
+
+    #[test]
+    fn chunks_are_estimated_from_the_number_of_bars() {
+        let date = |y, m, d| Utc.with_ymd_and_hms(y, m, d, 0, 0, 0).unwrap();
+        let chunks = |timespan, multiplier, from, to, limit| {
+            num_chunks(timespan, multiplier, from, to, limit)
+        };
+        let (jan_2, jan_3) = (date(2024, 1, 2), date(2024, 1, 3));
+        // 1441 minutes including both ends
+        assert_eq!(chunks(Timespan::Minute, 1, jan_2, jan_3, 5_000), 1);
+        assert_eq!(chunks(Timespan::Minute, 1, jan_2, jan_3, 1_000), 2);
+        assert_eq!(chunks(Timespan::Minute, 5, jan_2, jan_3, 100), 3);
+        let (from, to) = (date(2020, 1, 1), date(2024, 12, 1));
+        assert_eq!(chunks(Timespan::Day, 1, from, to, 5_000), 1);
+        assert_eq!(chunks(Timespan::Month, 1, from, to, 12), 5);
+        assert_eq!(chunks(Timespan::Quarter, 1, from, to, 4), 5);
+        assert_eq!(chunks(Timespan::Year, 1, from, to, 1), 5);
+        // A range without any bars still takes a request
+        assert_eq!(chunks(Timespan::Year, 1, jan_2, jan_2, 5_000), 1);
+        assert_eq!(chunks(Timespan::Day, 1, jan_3, jan_2, 5_000), 0);
+    }
+
+    #[test]
+    fn requests_are_estimated_per_ticker_and_month() {
+        let service = Service::new(
+            Config {
+                tickers: vec!["SPY".to_string(), "QQQ".to_string()],
+                ticker_parallelism: 2,
+                adjusted_as_of: NaiveDate::from_ymd_opt(2024, 1, 1),
+                ..config(Path::new("data"))
+            },
+            "key",
+        )
+        .unwrap();
+        let estimates = service.estimate();
+        assert_eq!(estimates.len(), 2);
+        // January and the first day of February, plus the splits
+        assert_eq!(
+            (estimates[1].ticker.as_str(), estimates[1].chunks),
+            ("QQQ", 2)
+        );
+        assert_eq!(estimates[1].requests, 3);
+    }
+
+    #[test]
+    fn validating_reports_missing_trading_days() {
+        let output_dir = TempOutput::new("validate");
+        std::fs::create_dir_all(output_dir.join("SPY")).unwrap();
+        // Friday, then Tuesday after Martin Luther King Jr. Day, then
+        // Thursday, so only Wednesday is missing
+        let day = |d| Utc.with_ymd_and_hms(2024, 1, d, 5, 0, 0).unwrap();
+        let rows: String = [day(12), day(16), day(18)]
+            .iter()
+            .map(|day| format!("{},1,1,1,1,1\n", day.timestamp_millis()))
+            .collect();
+        std::fs::write(
+            output_dir.join("SPY/day.csv"),
+            format!("timestamp,open,high,low,close,volume\n{rows}"),
+        )
+        .unwrap();
+        let service = Service::new(
+            Config {
+                tickers: vec!["SPY".to_string(), "QQQ".to_string()],
+                ..config(&output_dir)
+            },
+            "key",
+        )
+        .unwrap();
+        let gaps = service.validate("SPY").unwrap();
+        assert_eq!(gaps.len(), 1);
+        assert_eq!((gaps[0].start, gaps[0].end), (day(16), day(18)));
+        assert_eq!(gaps[0].missing, 1);
+
+        let coverage = service.coverage().unwrap();
+        assert_eq!(coverage[0].range, Some((day(12), day(18))));
+        assert_eq!(coverage[0].records, 3);
+        assert_eq!(coverage[0].gaps, gaps);
+        // Nothing is downloaded of QQQ
+        assert_eq!((coverage[1].range, coverage[1].records), (None, 0));
+    }
+
+    #[tokio::test]
+    async fn no_more_pages_are_fetched_once_there_are_enough_bars() {
+        let output_dir = TempOutput::new("last");
+        let (server, service) = two_pages(Config {
+            last: Some(1),
+            ..config(&output_dir)
+        })
+        .await;
+        let request = service
+            .request("SPY", service.config.from, service.config.to)
+            .unwrap();
+        service
+            .save_aggregates_to_disk(request, ProgressBar::hidden())
+            .await
+            .unwrap();
+        // The next page would have been fetched ahead of writing the first
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(output_dir.join("SPY/day.csv")).unwrap(),
+            "timestamp,open,high,low,close,volume\n1,1,1,1,1,10\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn records_are_streamed_one_by_one_across_pages() {
+        let (_server, service) = two_pages(config(Path::new("data"))).await;
+        let request = service
+            .request("SPY", service.config.from, service.config.to)
+            .unwrap();
+        let records: Vec<_> = service
+            .stream_aggregates(request)
+            .map_ok(|record| record.timestamp)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(records, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn records_can_be_posted_instead_of_saved() {
+        let (server, service) = two_pages(config(Path::new("data"))).await;
+        Mock::given(method("POST"))
+            .and(path("/ingest"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        let sink = HttpSink::new(&format!("{}/ingest", server.uri()))
+            .unwrap()
+            .with_batch_size(NonZeroUsize::new(1).unwrap());
+        service.fetch_to_http(&sink).await;
+        let posted: Vec<_> = server
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|request| request.method == "POST")
+            .map(|request| String::from_utf8(request.body).unwrap())
+            .collect();
+        assert_eq!(posted.len(), 2);
+        assert!(posted[1].contains(r#""timestamp":2"#), "{}", posted[1]);
+    }
+
+    #[tokio::test]
+    async fn a_ticker_stops_at_its_request_limit() {
+        let (server, service) = two_pages(Config {
+            max_requests_per_ticker: NonZeroU32::new(1),
+            ..config(Path::new("data"))
+        })
+        .await;
+        let request = service
+            .request("SPY", service.config.from, service.config.to)
+            .unwrap();
+        let records: Vec<_> =
+            service.stream_aggregates(request).collect().await;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].as_ref().unwrap().timestamp, 1);
+        assert!(matches!(records[1], Err(Error::TickerRequestLimit(_))));
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+        // Other tickers have their own budget
+        assert_eq!(service.ticker_requests("QQQ").load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn newest_first_holds_across_pages() {
+        let server = MockServer::start().await;
+        let page = |cursor: Option<&str>, timestamps: [i64; 2]| {
+            serde_json::json!({
+                "ticker": "SPY",
+                "adjusted": true,
+                "queryCount": 2,
+                "request_id": "1",
+                "resultsCount": 2,
+                "status": "OK",
+                "results": timestamps.map(|t| {
+                    serde_json::json!({"t": t, "o": 1, "h": 1, "l": 1, "c": 1, "v": 10})
+                }),
+                "next_url": cursor.map(|cursor| {
+                    format!("https://api.polygon.io/v2/next?cursor={cursor}&sort=desc")
+                }),
+            })
+        };
+        Mock::given(path_regex("^/v2/aggs/ticker/SPY/range/"))
+            .and(query_param("sort", "desc"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(page(Some("2"), [4, 3])),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/v2/next"))
+            .and(query_param("cursor", "2"))
+            .and(query_param("sort", "desc"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(page(None, [2, 1])),
+            )
+            .mount(&server)
+            .await;
+        let service = Service::new(
+            Config {
+                base_url: Some(server.uri()),
+                sort: SortOrder::Desc,
+                ..config(Path::new("data"))
+            },
+            "key",
+        )
+        .unwrap();
+        let request = service
+            .request("SPY", service.config.from, service.config.to)
+            .unwrap();
+        let records: Vec<_> = service
+            .stream_aggregates(request)
+            .map_ok(|record| record.timestamp)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(records, [4, 3, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn grouped_daily_bars_can_be_saved_by_ticker() {
+        let output_dir = TempOutput::new("by-ticker");
+        let server = MockServer::start().await;
+        for (day, timestamp) in [(2, 1704171600000_i64), (3, 1704258000000)] {
+            Mock::given(path(format!(
+                "/v2/aggs/grouped/locale/us/market/stocks/2024-01-0{day}"
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                serde_json::json!({
+                    "adjusted": true,
+                    "queryCount": 2,
+                    "resultsCount": 2,
+                    "status": "OK",
+                    "results": [
+                        {"T": "SPY", "t": timestamp, "o": day, "h": day, "l": day, "c": day, "v": 1},
+                        {"T": "QQQ", "t": timestamp, "o": 1, "h": 1, "l": 1, "c": 1, "v": 1},
+                    ],
+                }),
+            ))
+            .mount(&server)
+            .await;
+        }
+        let service = Service::new(
+            Config {
+                base_url: Some(server.uri()),
+                grouped_by_ticker: true,
+                from: Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap(),
+                to: Utc.with_ymd_and_hms(2024, 1, 3, 0, 0, 0).unwrap(),
+                ..config(&output_dir)
+            },
+            "key",
+        )
+        .unwrap();
+        service.fetch_grouped_daily().await;
+        assert_eq!(
+            std::fs::read_to_string(output_dir.join("SPY/day.csv")).unwrap(),
+            "timestamp,open,high,low,close,volume\n\
+             1704171600000,2,2,2,2,1\n1704258000000,3,3,3,3,1\n"
+        );
+        // Only the configured tickers are saved
+        assert!(!output_dir.join("QQQ").exists());
+    }
+
+    #[tokio::test]
+    async fn no_requests_are_sent_while_paused() {
+        let (server, service) = two_pages(config(Path::new("data"))).await;
+        let request = service
+            .request("SPY", service.config.from, service.config.to)
+            .unwrap();
+        service.pause();
+        assert!(service.is_paused());
+        let fetch = service.stream_aggregates(request).try_collect::<Vec<_>>();
+        let resume = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(server.received_requests().await.unwrap().is_empty());
+            service.resume();
+        };
+        let (records, ()) = tokio::join!(fetch, resume);
+        assert_eq!(records.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn batches_written_to_a_writer_make_a_single_document() {
+        let serializer = OutputFormat::JsonArray.serializer(Default::default());
+        let mut out = Vec::new();
+        write_records(serializer.as_ref(), &mut out, &[record(1)], true)
+            .unwrap();
+        write_records(serializer.as_ref(), &mut out, &[], false).unwrap();
+        write_records(serializer.as_ref(), &mut out, &[record(2)], false)
+            .unwrap();
+        out.extend(serializer.footer());
+        let records: Vec<AggregateRecord> =
+            serde_json::from_slice(&out).unwrap();
+        assert_eq!(records.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn sinks_are_written_concurrently() {
+        /// Takes a while to write, noting how many writes overlap
+        struct Slow {
+            extension: &'static str,
+            writing: Arc<AtomicU32>,
+            most: Arc<AtomicU32>,
+        }
+        impl RecordSerializer for Slow {
+            fn extension(&self) -> &str {
+                self.extension
+            }
+
+            fn serialize(
+                &self,
+                _record: &AggregateRecord,
+                _out: &mut dyn std::io::Write,
+            ) -> Result<(), Error> {
+                let writing = self.writing.fetch_add(1, Ordering::SeqCst) + 1;
+                self.most.fetch_max(writing, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(50));
+                self.writing.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        let output_dir = TempOutput::new("concurrent");
+        let (writing, most) = (Arc::default(), Arc::<AtomicU32>::default());
+        let slow = |extension| Slow {
+            extension,
+            writing: Arc::clone(&writing),
+            most: Arc::clone(&most),
+        };
+        let (_server, service) = two_pages(Config {
+            write_concurrency: 2,
+            ..config(&output_dir)
+        })
+        .await;
+        let service =
+            service.with_serializer(slow("a")).add_serializer(slow("b"));
+        let request = service
+            .request("SPY", service.config.from, service.config.to)
+            .unwrap();
+        service
+            .save_aggregates_to_disk(request, ProgressBar::hidden())
+            .await
+            .unwrap();
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn resuming_daily_partitions_fetches_only_the_missing_days() {
+        let server = MockServer::start().await;
+        one_page(&server, "SPY").await;
+        let output_dir = TempOutput::new("complete-days");
+        let day = |d| Utc.with_ymd_and_hms(2024, 1, d, 0, 0, 0).unwrap();
+        let before = |d| day(d) - TimeDelta::milliseconds(1);
+        for (from, to) in [(1, 3), (5, 6), (1, 6)] {
+            Service::new(
+                Config {
+                    from: day(from),
+                    to: before(to),
+                    partition_by: PartitionBy::Day,
+                    base_url: Some(server.uri()),
+                    resume: true,
+                    ..config(&output_dir)
+                },
+                "key",
+            )
+            .unwrap()
+            .fetch_data()
+            .await
+            .unwrap();
+        }
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 3);
+        let missing = format!(
+            "/{}/{}",
+            day(3).timestamp_millis(),
+            before(5).timestamp_millis()
+        );
+        assert!(requests[2].url.path().ends_with(&missing));
+    }
+
+    #[tokio::test]
+    async fn records_are_partitioned_by_day() {
+        let output_dir = TempOutput::new("partitions");
+        let service = Service::new_with_client(
+            Config {
+                partition_by: PartitionBy::Day,
+                ..config(&output_dir)
+            },
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        let request = service
+            .request("SPY", service.config.from, service.config.to)
+            .unwrap();
+        let sinks = service.sinks(&request);
+        let at = |d, h| {
+            Utc.with_ymd_and_hms(2024, 1, d, h, 0, 0)
+                .unwrap()
+                .timestamp_millis()
+        };
+        let mut writers = open_writers(&sinks, None).await.unwrap();
+        // A day's bars can come in over more than one batch
+        for batch in [&[at(2, 14), at(2, 15)][..], &[at(2, 16), at(3, 14)]] {
+            let records: Vec<_> = batch.iter().copied().map(record).collect();
+            write_batch(&mut writers, &sinks, None, &records, 1).unwrap();
+        }
+        for writer in writers {
+            writer.finish().unwrap();
+        }
+        service
+            .merge_into_sinks(&sinks, vec![record(at(3, 10))], false)
+            .unwrap();
+
+        let timestamps = |day: &str| {
+            let path = output_dir.join("SPY/day/2024/01").join(day);
+            reader::read_records(&path, TimestampUnit::Millis)
+                .unwrap()
+                .iter()
+                .map(|record| record.timestamp)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timestamps("02.csv"), [at(2, 14), at(2, 15), at(2, 16)]);
+        assert_eq!(timestamps("03.csv"), [at(3, 10), at(3, 14)]);
+        assert_eq!(
+            sinks[0]
+                .last_timestamp(TimestampUnit::Millis, false)
+                .unwrap(),
+            Some(at(3, 14))
+        );
+        assert!(!output_dir.join("SPY/day.csv").exists());
+
+        let month = Sink {
+            partition: PartitionBy::Month,
+            ..sinks[0].clone()
+        };
+        assert!(month.file(at(3, 14)).ends_with("SPY/day/2024/01.csv"));
+    }
+
+    #[test]
+    fn late_records_are_held_back() {
+        let timestamps = |records: &[AggregateRecord]| {
+            records.iter().map(|r| r.timestamp).collect::<Vec<_>>()
+        };
+        let mut reorder = Reorder::new(false);
+        let batch = reorder.order(vec![record(3), record(1), record(2)]);
+        assert_eq!(timestamps(&batch), [1, 2, 3]);
+        let batch = reorder.order(vec![record(5), record(3), record(0)]);
+        assert_eq!(timestamps(&batch), [5]);
+        assert_eq!(timestamps(&reorder.into_late()), [0, 3]);
+
+        let mut reorder = Reorder::new(true);
+        let batch = reorder.order(vec![record(1), record(3), record(2)]);
+        assert_eq!(timestamps(&batch), [3, 2, 1]);
+        let batch = reorder.order(vec![record(0), record(4)]);
+        assert_eq!(timestamps(&batch), [0]);
+        assert_eq!(timestamps(&reorder.into_late()), [4]);
+    }
+
+    #[test]
+    fn out_of_order_timestamps_are_flagged() {
+        let records: Vec<_> = [1, 2, 4, 3].map(record).into();
+        let mut last = None;
+        assert!(check_monotonic(
+            MonotonicCheck::Error,
+            &mut last,
+            &records[..3]
+        )
+        .is_ok());
+        assert!(matches!(
+            check_monotonic(MonotonicCheck::Error, &mut last, &records[3..]),
+            Err(Error::NonMonotonic {
+                previous: 4,
+                timestamp: 3
+            })
+        ));
+        let mut last = None;
+        assert!(
+            check_monotonic(MonotonicCheck::Warn, &mut last, &records).is_ok()
+        );
+    }
+
+    #[test]
+    fn duplicate_timestamps_in_a_page_are_handled_per_policy() {
+        let page: Vec<_> = [1, 2, 2, 3, 1].map(record).into();
+        let mut records = page.clone();
+        check_page_duplicates(DuplicateCheck::Warn, &mut records).unwrap();
+        assert_eq!(records.len(), 5);
+        check_page_duplicates(DuplicateCheck::Drop, &mut records).unwrap();
+        let timestamps: Vec<_> =
+            records.iter().map(|record| record.timestamp).collect();
+        assert_eq!(timestamps, [1, 2, 3]);
+        check_page_duplicates(DuplicateCheck::Error, &mut records).unwrap();
+        let mut records = page;
+        assert!(matches!(
+            check_page_duplicates(DuplicateCheck::Error, &mut records),
+            Err(Error::DuplicateTimestamp(2))
+        ));
+    }
+
+    #[test]
+    fn every_sink_gets_the_same_records() {
+        let output_dir = TempOutput::new("sinks");
+        let config = Config {
+            formats: vec![OutputFormat::Csv, OutputFormat::Json],
+            ..config(&output_dir)
+        };
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(config.from)
+            .to(config.to)
+            .limit(config.limit)
+            .build()
+            .unwrap();
+        let service =
+            Service::new_with_client(config, Client::new("key").unwrap())
+                .unwrap();
+        let sinks = service.sinks(&request);
+        assert_eq!(sinks.len(), 2);
+
+        std::fs::create_dir_all(output_dir.join("SPY")).unwrap();
+        for sink in &sinks {
+            RecordWriter::append(&sink.path, sink.serializer)
+                .unwrap()
+                .write(&[record(1), record(3)])
+                .unwrap();
+        }
+        service
+            .merge_into_sinks(&sinks, vec![record(2)], false)
+            .unwrap();
+
+        let timestamps: Vec<Vec<_>> = sinks
+            .iter()
+            .map(|sink| {
+                reader::read_records(&sink.path, TimestampUnit::Millis)
+                    .unwrap()
+                    .iter()
+                    .map(|record| record.timestamp)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(timestamps[0], [1, 2, 3]);
+        assert_eq!(timestamps[0], timestamps[1]);
+    }
+
+    #[test]
+    fn ranges_before_the_horizon_are_clamped() {
+        let horizons = default_horizons();
+        let date = |y, m, d| Utc.with_ymd_and_hms(y, m, d, 0, 0, 0).unwrap();
+        let range = (date(2000, 1, 1), date(2024, 1, 1));
+        assert_eq!(
+            clamp_to_horizon(&horizons, "SPY", range),
+            Some((date(2003, 9, 10), date(2024, 1, 1)))
+        );
+        assert_eq!(
+            clamp_to_horizon(&horizons, "X:BTCUSD", range),
+            Some((date(2013, 1, 1), date(2024, 1, 1)))
+        );
+        assert_eq!(clamp_to_horizon(&horizons, "I:SPX", range), Some(range));
+        assert_eq!(
+            clamp_to_horizon(
+                &horizons,
+                "SPY",
+                (date(1990, 1, 1), date(1991, 1, 1))
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn shuffled_tickers_are_picked_once_per_run() {
+        let output_dir = TempOutput::new("shuffled");
+        let service = Service::new_with_client(
+            Config {
+                tickers: (0..50).map(|i| format!("T{i}")).collect(),
+                max_tickers: Some(3),
+                shuffle: true,
+                ..config(&output_dir)
+            },
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        let tickers = service.tickers();
+        assert_eq!(tickers.len(), 3);
+        for _ in 0..5 {
+            assert_eq!(service.tickers(), tickers);
+            let plan = service.plan().await;
+            let planned: Vec<_> =
+                plan.iter().map(|item| item.ticker.as_str()).collect();
+            assert_eq!(planned, tickers);
+        }
+    }
+
+    #[tokio::test]
+    async fn plan_has_a_work_item_per_ticker_and_window() {
+        let output_dir = TempOutput::new("plan");
+        let mut config = config(&output_dir);
+        config.tickers = vec!["SPY".to_string(), "QQQ".to_string()];
+        config.extra_ranges = vec![(
+            Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 4, 1, 0, 0, 0).unwrap(),
+        )];
+        let service =
+            Service::new_with_client(config, Client::new("key").unwrap())
+                .unwrap();
+        let plan = service.plan().await;
+
+        let tickers: Vec<_> = plan.iter().map(|i| i.ticker.as_str()).collect();
+        assert_eq!(tickers, ["SPY", "SPY", "QQQ", "QQQ"]);
+        assert!(plan[0].to < plan[1].from);
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json[0]["timespan"], "day");
+        assert_eq!(json[0]["from"], "2024-01-01T00:00:00Z");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinked_output_directories_are_followed() {
+        let root = TempOutput::new("symlinks");
+        let volume = root.join("volume");
+        let output_dir = root.join("output");
+        std::fs::create_dir_all(&volume).unwrap();
+        std::os::unix::fs::symlink(&volume, &output_dir).unwrap();
+
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap())
+            .limit(5_000)
+            .build()
+            .unwrap();
+        let service = Service::new_with_client(
+            config(&output_dir),
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        let sinks = service.sinks(&request);
+        for mut writer in open_writers(&sinks, None).await.unwrap() {
+            writer.write(&[record(1)]).unwrap();
+            writer.finish().unwrap();
+        }
+        service
+            .complete(&sinks, request.from, request.to)
+            .await
+            .unwrap();
+
+        let file = volume.join("SPY").join("minute.csv");
+        let records =
+            reader::read_records(&file, TimestampUnit::Millis).unwrap();
+        let checkpoint_written = volume.join(".checkpoint.json").exists();
+        let still_a_symlink = output_dir
+            .symlink_metadata()
+            .unwrap()
+            .file_type()
+            .is_symlink();
+        assert_eq!(records.len(), 1);
+        assert!(checkpoint_written);
+        assert!(still_a_symlink);
+    }
+
+    #[tokio::test]
+    async fn idle_workers_take_the_next_item() {
+        let finished = std::sync::Mutex::new(Vec::new());
+        let items = [200, 20, 20, 20];
+        drain(items.into_iter().enumerate(), 2, |(i, millis)| {
+            let finished = &finished;
+            async move {
+                tokio::time::sleep(Duration::from_millis(millis)).await;
+                finished.lock().unwrap().push(i);
+            }
+        })
+        .await;
+        // The short items all go to the worker that isn't busy with the
+        // long one, instead of being split evenly between the two
+        assert_eq!(finished.into_inner().unwrap(), [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn bars_with_a_multiplier_get_their_own_file() {
+        let service = Service::new_with_client(
+            config(Path::new("data")),
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        let request = |multiplier| {
+            AggregateRequestBuilder::default()
+                .ticker("SPY")
+                .multiplier(multiplier)
+                .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+                .to(Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap())
+                .limit(5_000)
+                .build()
+                .unwrap()
+        };
+        assert_eq!(service.sinks(&request(1))[0].key, "SPY/minute.csv");
+        assert_eq!(service.sinks(&request(5))[0].key, "SPY/5minute.csv");
+    }
+
+    #[test]
+    fn requests_pick_up_after_the_last_record_on_disk() {
+        let root = TempOutput::new("skip-downloaded");
+        std::fs::create_dir_all(root.join("SPY")).unwrap();
+        let service = Service::new_with_client(
+            config(&root),
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        let jan = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let feb = Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap();
+        let mut request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(jan)
+            .to(feb)
+            .limit(5_000)
+            .build()
+            .unwrap();
+        let sinks = service.sinks(&request);
+
+        // Without a file, the whole range is fetched
+        assert_eq!(
+            service
+                .skip_downloaded(&mut request, &sinks, false)
+                .unwrap(),
+            None
+        );
+        assert_eq!(request.from, jan);
+
+        let last = Utc.with_ymd_and_hms(2024, 1, 15, 20, 59, 0).unwrap();
+        std::fs::write(
+            &sinks[0].path,
+            format!(
+                "timestamp,open,high,low,close,volume\n\
+                 1,1,1,1,1,1\n\
+                 {},1,1,1,1,1\n",
+                last.timestamp_millis()
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            service
+                .skip_downloaded(&mut request, &sinks, false)
+                .unwrap(),
+            Some(last.timestamp_millis())
+        );
+        assert_eq!(request.from, last + TimeDelta::milliseconds(1));
+        assert_eq!(request.to, feb);
+
+        // Files written newest first end with the oldest record
+        request.from = jan;
+        assert!(service
+            .skip_downloaded(&mut request, &sinks, true)
+            .unwrap()
+            .is_some());
+        assert_eq!(request.from, jan);
+        assert_eq!(request.to, last - TimeDelta::milliseconds(1));
+    }
+
+    #[tokio::test]
+    async fn revised_records_are_replaced_when_picking_up() {
+        let output_dir = TempOutput::new("revisions");
+        std::fs::create_dir_all(output_dir.join("SPY")).unwrap();
+        // Polygon has since revised the close of the first bar to 1
+        std::fs::write(
+            output_dir.join("SPY/day.csv"),
+            "timestamp,open,high,low,close,volume\n1,1,1,1,5,10\n",
+        )
+        .unwrap();
+        let (_server, service) = two_pages(Config {
+            revision_window: Some(Duration::from_secs(24 * 60 * 60)),
+            ..config(&output_dir)
+        })
+        .await;
+        let request = service
+            .request("SPY", service.config.from, service.config.to)
+            .unwrap();
+        service
+            .save_aggregates_to_disk(request, ProgressBar::hidden())
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output_dir.join("SPY/day.csv")).unwrap(),
+            "timestamp,open,high,low,close,volume\n1,1,1,1,1,10\n2,1,1,1,1,10\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn pages_are_fetched_up_to_the_write_buffer_size_ahead() {
+        let pages = || stream::iter(0..10).boxed();
+        let (fetch, mut pages_fetched) = fetch_ahead(pages(), 3);
+        let mut fetch = std::pin::pin!(fetch);
+        // Nothing's written yet, so fetching stops once the buffer is full
+        assert!(futures::poll!(&mut fetch).is_pending());
+        assert_eq!(pages_fetched.max_capacity(), 3);
+        assert_eq!(pages_fetched.len(), 3);
+        assert_eq!(pages_fetched.recv().await, Some(0));
+        assert!(futures::poll!(&mut fetch).is_pending());
+        assert_eq!(pages_fetched.len(), 3);
+
+        let (fetch, pages_fetched) = fetch_ahead(pages(), 8);
+        let mut fetch = std::pin::pin!(fetch);
+        assert!(futures::poll!(&mut fetch).is_pending());
+        assert_eq!(pages_fetched.len(), 8);
+        // Once the receiver's gone, the rest of the pages aren't fetched
+        drop(pages_fetched);
+        assert!(futures::poll!(&mut fetch).is_ready());
+    }
+
+    #[test]
+    fn reversed_ranges_are_an_error_unless_swapped() {
+        let reversed = |swap_reversed_ranges| Config {
+            from: Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap(),
+            to: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            swap_reversed_ranges,
+            ..config(Path::new("data"))
+        };
+        assert!(matches!(
+            Service::new(reversed(false), "key"),
+            Err(Error::Init(crate::error::Init::ReversedRange(..)))
+        ));
+        let service = Service::new(reversed(true), "key").unwrap();
+        assert!(service.config.from < service.config.to);
+        assert_eq!(service.config.to.month(), 2);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    #[test]
+    fn pushing_metrics_needs_the_metrics_feature() {
+        let service = Service::new_with_client(
+            Config {
+                push_metrics: Some("http://localhost:9091".into()),
+                ..config(Path::new("data"))
+            },
+            Client::new("key").unwrap(),
+        );
+        assert!(matches!(
+            service,
+            Err(Error::Init(crate::error::Init::MetricsDisabled))
+        ));
+    }
+
+    #[test]
+    fn concurrency_must_be_at_least_one() {
+        let service = Service::new_with_client(
+            Config {
+                concurrency: 0,
+                ..config(Path::new("data"))
+            },
+            Client::new("key").unwrap(),
+        );
+        assert!(matches!(
+            service,
+            Err(Error::Init(crate::error::Init::ZeroConcurrency))
+        ));
+    }
+
+    #[test]
+    fn decimal_commas_need_another_csv_delimiter() {
+        let service = |csv_delimiter, csv_decimal_comma| {
+            Service::new_with_client(
+                Config {
+                    csv_delimiter,
+                    csv_decimal_comma,
+                    ..config(Path::new("data"))
+                },
+                Client::new("key").unwrap(),
+            )
+        };
+        assert!(matches!(
+            service(',', true),
+            Err(Error::Init(crate::error::Init::AmbiguousDecimalComma))
+        ));
+        assert!(matches!(
+            service('.', false),
+            Err(Error::Init(crate::error::Init::InvalidCsvDelimiter('.')))
+        ));
+        assert!(service(';', true).is_ok());
+        assert!(service('\t', false).is_ok());
+    }
+
+    #[test]
+    fn running_out_of_retries_on_the_rate_limit_aborts_the_run() {
+        let service = Service::new_with_client(
+            Config {
+                abort_on_rate_limit: true,
+                ..config(Path::new("data"))
+            },
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        let error = |status_code| Error::ApiError {
+            status_code,
+            message: String::new(),
+        };
+        assert!(!service.report_rate_limit(&error(StatusCode::NOT_FOUND)));
+        assert!(!service.aborted());
+        let rate_limited = Error::RateLimited {
+            retry_after: None,
+            source: Box::new(error(StatusCode::TOO_MANY_REQUESTS)),
+        };
+        assert!(service.report_rate_limit(&rate_limited));
+        assert!(service.report_rate_limit(&rate_limited));
+        assert!(service.aborted());
+    }
+
+    #[test]
+    fn only_the_last_bars_are_requested() {
+        let service = |last| {
+            Service::new_with_client(
+                Config {
+                    last,
+                    ..config(Path::new("data"))
+                },
+                Client::new("key").unwrap(),
+            )
+            .unwrap()
+        };
+        assert_eq!(service(None).limit(), 5_000);
+        assert_eq!(service(Some(100)).limit(), 100);
+        assert_eq!(service(Some(100_000)).limit(), 5_000);
+    }
+
+    #[test]
+    fn unreadable_checkpoints_only_stop_resumed_runs() {
+        let output_dir = TempOutput::new("unreadable-checkpoint");
+        std::fs::write(output_dir.join(".checkpoint.json"), "{").unwrap();
+        let service = |resume| {
+            Service::new_with_client(
+                Config {
+                    resume,
+                    ..config(&output_dir)
+                },
+                Client::new("key").unwrap(),
+            )
+        };
+        assert!(service(false).is_ok());
+        assert!(service(true).is_err());
+    }
+
+    #[tokio::test]
+    async fn checkpoints_can_live_outside_the_output_dir() {
+        let root = TempOutput::new("checkpoint");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = root.join("shared.json");
+        let jan = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let feb = Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap();
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.complete("SPY/day.csv", jan, feb);
+        checkpoint.save(&path).await.unwrap();
+
+        let output_dir = root.join("output");
+        let service = Service::new_with_client(
+            Config {
+                checkpoint: Some(path.clone()),
+                ..config(&output_dir)
+            },
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        let skipped = service.checkpoint.lock().await.is_complete(
+            "SPY/day.csv",
+            jan,
+            feb,
+        );
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let request = AggregateRequestBuilder::default()
+            .ticker("QQQ")
+            .from(jan)
+            .to(feb)
+            .limit(5_000)
+            .build()
+            .unwrap();
+        service
+            .complete(&service.sinks(&request), jan, feb)
+            .await
+            .unwrap();
+        let saved = Checkpoint::load(&path).unwrap();
+        let in_output_dir = output_dir.join(".checkpoint.json").exists();
+        assert!(skipped);
+        assert!(saved.is_complete("QQQ/minute.csv", jan, feb));
+        assert!(!in_output_dir);
+    }
+
+    #[test]
+    fn path_hostile_tickers_are_sanitized() {
+        assert_eq!(sanitize_path_segment("SPY"), "SPY");
+        assert_eq!(sanitize_path_segment("BRK.A"), "BRK.A");
+        assert_eq!(sanitize_path_segment("X:BTCUSD"), "X_BTCUSD");
+        assert_eq!(sanitize_path_segment("A/B\\C*?\n"), "A_B_C___");
+        assert_eq!(sanitize_path_segment("con"), "_con");
+        assert_eq!(sanitize_path_segment("LPT1.X"), "_LPT1.X");
+        assert_eq!(sanitize_path_segment("ABC. "), "ABC__");
+        assert_eq!(sanitize_path_segment(".."), "__");
+        assert_eq!(sanitize_path_segment(""), "_");
+        let long = "É".repeat(MAX_SEGMENT_LEN);
+        let segment = sanitize_path_segment(&long);
+        assert!(segment.len() <= MAX_SEGMENT_LEN);
+        assert!(segment.chars().all(|c| c == 'É'));
+    }
+
+    #[tokio::test]
+    async fn merged_daily_bars_keep_the_partition_after_midnight_utc() {
+        let output_dir = TempOutput::new("derive-daily-merge");
+        let service = Service::new_with_client(
+            Config {
+                timespan: Timespan::Minute,
+                derive_daily: true,
+                partition_by: PartitionBy::Day,
+                ..config(&output_dir)
+            },
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        let request = service
+            .request("SPY", service.config.from, service.config.to)
+            .unwrap();
+        let sinks = service.sinks(&request);
+        let at = |d, h| {
+            Utc.with_ymd_and_hms(2024, 1, d, h, 0, 0)
+                .unwrap()
+                .timestamp_millis()
+        };
+        // 18:00 on January 2nd in New York and 19:00, past midnight UTC
+        service
+            .merge_into_sinks(
+                &sinks,
+                vec![record(at(2, 23)), record(at(3, 0))],
+                false,
+            )
+            .unwrap();
+        // Only the partition of January 3rd is merged into
+        service
+            .merge_into_sinks(&sinks, vec![record(at(3, 1))], false)
+            .unwrap();
+
+        let days: Vec<_> = sinks[1]
+            .files()
+            .unwrap()
+            .iter()
+            .flat_map(|file| {
+                reader::read_records(file, TimestampUnit::Millis).unwrap()
+            })
+            .map(|day| (day.timestamp, day.volume))
+            .collect();
+        assert_eq!(days, [(at(2, 5), Decimal::from(3))]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn folders_of_unsanitized_tickers_are_kept_on() {
+        let output_dir = TempOutput::new("unsanitized");
+        let service = Service::new_with_client(
+            config(&output_dir),
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(service.ticker_dir("X:BTCUSD"), "X_BTCUSD");
+        std::fs::create_dir(output_dir.join("X:BTCUSD")).unwrap();
+        assert_eq!(service.ticker_dir("X:BTCUSD"), "X:BTCUSD");
+        assert_eq!(service.ticker_dir("X:ETHUSD"), "X_ETHUSD");
+    }
+
+    #[tokio::test]
+    async fn minute_bars_are_combined_into_daily_bars() {
+        let output_dir = TempOutput::new("derive-daily");
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap())
+            .limit(5_000)
+            .build()
+            .unwrap();
+        let service = Service::new_with_client(
+            Config {
+                derive_daily: true,
+                ..config(&output_dir)
+            },
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        let sinks = service.sinks(&request);
+        let mut writers = open_writers(&sinks, None).await.unwrap();
+        let mut daily = DailyResampler::new(resample::new_york_day);
+        // Midnight in New York in the winter
+        let day = (24 + 5) * 60 * 60 * 1000;
+        let minute = 60 * 1000;
+        let next_day = day + 24 * 60 * 60 * 1000;
+        let batches = [
+            vec![record(day), record(day + minute)],
+            vec![record(day + 2 * minute), record(next_day + minute)],
+        ];
+        for batch in &batches {
+            write_batch(&mut writers, &sinks, Some(&mut daily), batch, 1)
+                .unwrap();
+        }
+        let last = daily_bar(daily.finish().unwrap().unwrap());
+        writers[1].write(&[last]).unwrap();
+        for writer in writers {
+            writer.finish().unwrap();
+        }
+
+        let read = |sink: usize| {
+            reader::read_records(&sinks[sink].path, TimestampUnit::Millis)
+                .unwrap()
+        };
+        let (minutes, days) = (read(0), read(1));
+        assert!(sinks[1].path.ends_with("SPY/day-from-minute.csv"));
+        assert_eq!(minutes.len(), 4);
+        let days: Vec<_> =
+            days.iter().map(|d| (d.timestamp, d.volume)).collect();
+        assert_eq!(days, [(day, Decimal::from(3)), (next_day, Decimal::ONE)]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn created_files_get_the_configured_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let output_dir = TempOutput::new("permissions");
+        let request = AggregateRequestBuilder::default()
+            .ticker("SPY")
+            .from(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .to(Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap())
+            .limit(5_000)
+            .build()
+            .unwrap();
+        let service = Service::new_with_client(
+            config(&output_dir),
+            Client::new("key").unwrap(),
+        )
+        .unwrap();
+        let sinks = service.sinks(&request);
+        for writer in open_writers(&sinks, Some(0o640)).await.unwrap() {
+            writer.finish().unwrap();
+        }
+
+        let mode = |path: &Path| {
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+        };
+        let file_mode = mode(&sinks[0].path);
+        let dir_mode = mode(&output_dir.join("SPY"));
+        assert_eq!(file_mode, 0o640);
+        assert_eq!(dir_mode, 0o750);
+    }
+
+    #[test]
+    fn flat_files_match_polygons_layout() {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 7).unwrap();
+        assert_eq!(
+            flat_file_path(date),
+            Path::new("us_stocks_sip/day_aggs_v1/2024/03/2024-03-07.csv")
+        );
+
+        let record = GroupedDailyRecord {
+            ticker: "SPY".to_string(),
+            timestamp: 1_709_841_600_000,
+            open: Decimal::from(1),
+            high: Decimal::from(4),
+            low: Decimal::from(1),
+            close: Decimal::from(3),
+            volume: Decimal::from(100),
+            transactions: Some(7),
+            otc: None,
+            vwap: Some(Decimal::from(2)),
+        };
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.serialize(FlatFileRecord::from(record)).unwrap();
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            csv,
+            "ticker,volume,open,close,high,low,window_start,transactions\n\
+             SPY,100,1,3,4,1,1709841600000000000,7\n"
+        );
+    }
 }
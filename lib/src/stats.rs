@@ -0,0 +1,110 @@
+use rust_decimal::Decimal;
+
+use crate::{error::Error, types::AggregateRecord};
+
+/// Summary statistics over a ticker's records, handy for spotting obviously
+/// wrong data at a glance
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Summary {
+    /// The number of records summarized
+    pub count: usize,
+    /// The lowest close price
+    pub min_close: Option<Decimal>,
+    /// The highest close price
+    pub max_close: Option<Decimal>,
+    /// The sum of all close prices, used for the average
+    pub total_close: Decimal,
+    /// The sum of the volume of every record
+    pub total_volume: Decimal,
+}
+
+impl Summary {
+    /// Add `record` to the summary. Fails with [`Error::Overflow`] instead of
+    /// panicking if the totals get too large, leaving the summary as it was
+    pub fn add(&mut self, record: &AggregateRecord) -> Result<(), Error> {
+        let total_close = self
+            .total_close
+            .checked_add(record.close)
+            .ok_or(Error::Overflow("close price"))?;
+        let total_volume = self
+            .total_volume
+            .checked_add(record.volume)
+            .ok_or(Error::Overflow("volume"))?;
+        self.count += 1;
+        self.min_close = Some(
+            self.min_close
+                .map_or(record.close, |min| min.min(record.close)),
+        );
+        self.max_close = Some(
+            self.max_close
+                .map_or(record.close, |max| max.max(record.close)),
+        );
+        self.total_close = total_close;
+        self.total_volume = total_volume;
+        Ok(())
+    }
+
+    /// The average close price
+    pub fn average_close(&self) -> Option<Decimal> {
+        self.total_close.checked_div(Decimal::from(self.count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::Summary;
+    use crate::{error::Error, types::AggregateRecord};
+
+    fn record(close: i64, volume: i64) -> AggregateRecord {
+        AggregateRecord {
+            timestamp: 0,
+            open: Decimal::from(close),
+            high: Decimal::from(close),
+            low: Decimal::from(close),
+            close: Decimal::from(close),
+            volume: Decimal::from(volume),
+            transactions: None,
+            otc: None,
+            vwap: None,
+        }
+    }
+
+    #[test]
+    fn summary_of_records() {
+        let mut summary = Summary::default();
+        for record in [record(3, 100), record(1, 50), record(5, 10)] {
+            summary.add(&record).unwrap();
+        }
+        assert_eq!(summary.count, 3);
+        assert_eq!(summary.min_close, Some(Decimal::from(1)));
+        assert_eq!(summary.max_close, Some(Decimal::from(5)));
+        assert_eq!(summary.average_close(), Some(Decimal::from(3)));
+        assert_eq!(summary.total_volume, Decimal::from(160));
+    }
+
+    #[test]
+    fn overflowing_totals_are_an_error() {
+        let mut summary = Summary::default();
+        let mut huge = record(1, 0);
+        huge.volume = Decimal::MAX;
+        summary.add(&huge).unwrap();
+        let before = summary.clone();
+        assert!(matches!(summary.add(&huge), Err(Error::Overflow("volume"))));
+        assert_eq!(summary, before);
+
+        // Large volumes that fit are still added up exactly
+        let mut summary = Summary::default();
+        huge.volume = Decimal::MAX / Decimal::from(4);
+        for _ in 0..3 {
+            summary.add(&huge).unwrap();
+        }
+        assert_eq!(summary.total_volume, huge.volume * Decimal::from(3));
+    }
+
+    #[test]
+    fn empty_summary_has_no_average() {
+        assert_eq!(Summary::default().average_close(), None);
+    }
+}
@@ -1,4 +1,6 @@
-use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
 use derive_builder::Builder;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
@@ -10,6 +12,8 @@ use serde::{Deserialize, Serialize};
     Serialize,
     Clone,
     Copy,
+    PartialEq,
+    Eq,
     strum::Display,
     strum::EnumString,
 )]
@@ -27,20 +31,328 @@ pub enum Timespan {
     Year,
 }
 
-#[derive(Builder)]
+/// The format of the files records are saved in
+#[derive(
+    Debug,
+    Default,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// Newline delimited JSON, one record per line
+    Json,
+    /// A single JSON array with all the records of a ticker
+    #[serde(rename = "json-array")]
+    #[strum(serialize = "json-array")]
+    JsonArray,
+    /// Compressed columnar files, with prices and volumes as decimals rounded
+    /// to 10 places and timestamps as UTC timestamps whatever the timestamp
+    /// unit
+    Parquet,
+}
+
+/// How timestamps are written to the output
+#[derive(
+    Debug,
+    Default,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum TimestampUnit {
+    /// Unix Msec, as returned by Polygon
+    #[default]
+    Millis,
+    /// Unix seconds
+    Seconds,
+    /// RFC 3339 datetimes in UTC, e.g. `2024-01-02T14:30:00Z`
+    Rfc3339,
+}
+
+/// Which fields of CSV files are quoted
+#[derive(
+    Debug,
+    Default,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum CsvQuoting {
+    /// Only the fields that need it, e.g. because they contain a comma
+    #[default]
+    Necessary,
+    /// Every field, including the header
+    Always,
+    /// No field, even the ones that need it
+    Never,
+    /// Every field that isn't a number
+    NonNumeric,
+}
+
+/// How the records of a ticker are split up into files, by the UTC date of
+/// their timestamps
+#[derive(
+    Debug,
+    Default,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum PartitionBy {
+    /// A single file, `$ticker/$timespan.csv`
+    None,
+    /// A file per day, `$ticker/$timespan/$year/$month/$day.csv`
+    #[default]
+    Day,
+    /// A file per month, `$ticker/$timespan/$year/$month.csv`
+    Month,
+}
+
+/// The market a ticker trades in, going by the prefix Polygon gives tickers
+/// outside of the stock market, e.g. `X:BTCUSD`
+#[derive(
+    Debug,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Market {
+    Stocks,
+    Options,
+    Indices,
+    Forex,
+    Crypto,
+}
+
+impl Timespan {
+    /// The name of bars of `multiplier` of this timespan, e.g. `5minute`,
+    /// or just the timespan for a multiplier of 1
+    pub fn with_multiplier(self, multiplier: u32) -> String {
+        if multiplier == 1 {
+            self.to_string()
+        } else {
+            format!("{multiplier}{self}")
+        }
+    }
+}
+
+impl PartitionBy {
+    /// The first day of the partition the record at `timestamp` goes in,
+    /// `None` without partitions
+    pub fn partition(self, timestamp: i64) -> Option<NaiveDate> {
+        let date = DateTime::from_timestamp_millis(timestamp)?.date_naive();
+        match self {
+            Self::None => None,
+            Self::Day => Some(date),
+            Self::Month => date.with_day(1),
+        }
+    }
+}
+
+impl Market {
+    /// The market `ticker` trades in
+    pub fn of(ticker: &str) -> Self {
+        match ticker.split_once(':').map(|(prefix, _)| prefix) {
+            Some("O") => Self::Options,
+            Some("I") => Self::Indices,
+            Some("C") => Self::Forex,
+            Some("X") => Self::Crypto,
+            _ => Self::Stocks,
+        }
+    }
+}
+
+/// The order Polygon returns aggregates in, by timestamp
+#[derive(
+    Debug,
+    Default,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum SortOrder {
+    /// Oldest first
+    #[default]
+    Asc,
+    /// Newest first
+    Desc,
+}
+
+/// The version of HTTP to talk to Polygon in
+#[derive(
+    Debug,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+)]
+pub enum HttpVersion {
+    /// Only HTTP/1.1, e.g. for proxies that don't support HTTP/2
+    #[serde(rename = "1.1")]
+    #[strum(serialize = "1.1")]
+    Http1,
+    /// Only HTTP/2, without falling back to HTTP/1.1. Over plain HTTP the
+    /// server has to support HTTP/2 without an upgrade
+    #[serde(rename = "2")]
+    #[strum(serialize = "2")]
+    Http2,
+}
+
+/// What to do when the timestamps of a ticker don't strictly increase
+#[derive(
+    Debug,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum MonotonicCheck {
+    /// Log a warning and keep going
+    Warn,
+    /// Stop fetching the ticker with an error
+    Error,
+}
+
+/// What to do when a single page from Polygon has the same timestamp more
+/// than once
+#[derive(
+    Debug,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum DuplicateCheck {
+    /// Log a warning and keep every record
+    Warn,
+    /// Log a warning and keep only the first record with each timestamp
+    Drop,
+    /// Stop fetching the ticker with an error
+    Error,
+}
+
+/// A single range of a single ticker to fetch, see
+/// [`Service::plan`](crate::service::Service::plan)
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkItem {
+    pub ticker: String,
+    pub timespan: Timespan,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+/// How many requests fetching a single ticker is expected to take, see
+/// [`Service::estimate`](crate::service::Service::estimate)
+#[derive(Debug, Clone, Serialize)]
+pub struct Estimate {
+    pub ticker: String,
+    /// The pages of aggregates to fetch
+    pub chunks: i64,
+    /// The chunks, along with any lookups needed before fetching them
+    pub requests: i64,
+}
+
+/// A range of time, `from..=to`
+pub type DateRange = (DateTime<Utc>, DateTime<Utc>);
+
+/// What's already downloaded of a ticker, see
+/// [`Service::coverage`](crate::service::Service::coverage)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    pub ticker: String,
+    /// The timestamps of the first and the last record, if there are any
+    pub range: Option<DateRange>,
+    pub records: usize,
+    /// The gaps between the first and the last record
+    pub gaps: Vec<crate::gaps::Gap>,
+}
+
+#[derive(Builder, Clone)]
 #[builder(setter(strip_option))]
 pub struct AggregateRequest<'a> {
     pub(crate) ticker: &'a str,
     #[builder(default)]
     pub(crate) timespan: Timespan,
+    /// How many `timespan`s each bar covers, e.g. 5 for 5-minute bars
+    #[builder(default = "1")]
+    pub(crate) multiplier: u32,
     pub(crate) from: DateTime<Utc>,
     pub(crate) to: DateTime<Utc>,
     #[builder(default)]
     pub(crate) next_url: Option<String>,
     pub(crate) limit: u32,
+    #[builder(default)]
+    pub(crate) sort: SortOrder,
+    /// Whether prices are adjusted for splits
+    #[builder(default = "true")]
+    pub(crate) adjusted: bool,
+    /// How long to wait for each response, instead of the client's timeout
+    #[builder(default)]
+    pub(crate) timeout: Option<Duration>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AggregateRecord {
     /// The Unix Msec timestamp for the start of the aggregate window.
     #[serde(alias = "t", default)]
@@ -85,3 +397,149 @@ pub struct AggregateResponse {
     pub results: Vec<AggregateRecord>,
     pub next_url: Option<String>,
 }
+
+#[derive(Deserialize)]
+pub struct GroupedDailyResponse {
+    pub adjusted: bool,
+    #[serde(alias = "queryCount")]
+    pub query_count: i64,
+    #[serde(alias = "resultsCount")]
+    pub results_count: usize,
+    pub status: String,
+    #[serde(default)]
+    pub results: Vec<GroupedDailyRecord>,
+}
+
+/// The daily bar of a single ticker, as returned by the grouped daily
+/// endpoint. The fields are the same as [`AggregateRecord`], with the ticker
+/// added since a single response covers the whole market
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupedDailyRecord {
+    /// The exchange symbol that this item is traded under.
+    #[serde(alias = "T")]
+    pub ticker: String,
+    #[serde(alias = "t", default)]
+    pub timestamp: i64,
+    #[serde(alias = "o")]
+    pub open: Decimal,
+    #[serde(alias = "h")]
+    pub high: Decimal,
+    #[serde(alias = "l")]
+    pub low: Decimal,
+    #[serde(alias = "c")]
+    pub close: Decimal,
+    #[serde(alias = "v", default)]
+    pub volume: Decimal,
+    #[serde(alias = "n", default, skip_serializing_if = "Option::is_none")]
+    pub transactions: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub otc: Option<bool>,
+    #[serde(alias = "vw", default, skip_serializing_if = "Option::is_none")]
+    pub vwap: Option<Decimal>,
+}
+
+impl GroupedDailyRecord {
+    /// Split the record into its ticker and its bar, in the same shape as
+    /// the bars of a single ticker
+    pub fn into_aggregate(self) -> (String, AggregateRecord) {
+        let bar = AggregateRecord {
+            timestamp: self.timestamp,
+            open: self.open,
+            high: self.high,
+            low: self.low,
+            close: self.close,
+            volume: self.volume,
+            transactions: self.transactions,
+            otc: self.otc,
+            vwap: self.vwap,
+        };
+        (self.ticker, bar)
+    }
+}
+
+/// A daily bar in the schema of Polygon's flat files of daily aggregates,
+/// with the same column names in the same order:
+/// `ticker,volume,open,close,high,low,window_start,transactions`.
+/// `window_start` is the start of the day as a Unix nanosecond timestamp.
+#[derive(Debug, Serialize)]
+pub struct FlatFileRecord {
+    pub ticker: String,
+    pub volume: Decimal,
+    pub open: Decimal,
+    pub close: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub window_start: i64,
+    pub transactions: Option<usize>,
+}
+
+impl From<GroupedDailyRecord> for FlatFileRecord {
+    fn from(record: GroupedDailyRecord) -> Self {
+        Self {
+            ticker: record.ticker,
+            volume: record.volume,
+            open: record.open,
+            close: record.close,
+            high: record.high,
+            low: record.low,
+            window_start: record.timestamp.saturating_mul(1_000_000),
+            transactions: record.transactions,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TickerDetailsResponse {
+    pub status: String,
+    pub request_id: String,
+    pub results: TickerDetails,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TickerDetails {
+    /// The exchange symbol that this item is traded under.
+    pub ticker: String,
+    /// The name of the asset.
+    pub name: String,
+    /// The market type of the asset, e.g. `stocks` or `crypto`.
+    pub market: String,
+    /// The type of the asset, e.g. `CS` for common stock.
+    #[serde(rename = "type", default)]
+    pub kind: Option<String>,
+    /// The date that the symbol was first publicly listed.
+    #[serde(default)]
+    pub list_date: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct SplitsResponse {
+    pub status: String,
+    #[serde(default)]
+    pub results: Vec<Split>,
+    pub next_url: Option<String>,
+}
+
+/// A stock split, e.g. a 4-for-1 split has a `split_from` of 1 and a
+/// `split_to` of 4
+#[derive(Debug, Clone, Deserialize)]
+pub struct Split {
+    /// The exchange symbol that this item is traded under.
+    pub ticker: String,
+    /// The first day the ticker traded at the split price.
+    pub execution_date: NaiveDate,
+    /// The number of shares before the split.
+    pub split_from: Decimal,
+    /// The number of shares after the split.
+    pub split_to: Decimal,
+}
+
+/// The body Polygon sends back along with a non-2xx status
+#[derive(Deserialize)]
+pub struct ErrorResponse {
+    pub status: String,
+    pub request_id: Option<String>,
+    /// Polygon's explanation of the error. Depending on the endpoint and the
+    /// kind of failure this is sent as either `error` or `message`
+    #[serde(alias = "error")]
+    pub message: String,
+}